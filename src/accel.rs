@@ -0,0 +1,100 @@
+use anyhow::{anyhow, Result};
+use std::{fs::OpenOptions, io::ErrorKind, path::Path};
+
+const KVM_DEVICE: &str = "/dev/kvm";
+
+/// How qemu runs guest code: hardware virtualization through KVM, or emulation with TCG. TCG
+/// works anywhere (containers, nested setups without KVM), but is far slower.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accelerator {
+    Kvm,
+    Tcg,
+}
+
+impl std::fmt::Display for Accelerator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Kvm => "kvm",
+            Self::Tcg => "tcg",
+        })
+    }
+}
+
+/// Check that KVM can be used by this user; the error says why not.
+pub fn kvm_usable() -> Result<()> {
+    kvm_usable_at(Path::new(KVM_DEVICE))
+}
+
+fn kvm_usable_at(path: &Path) -> Result<()> {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::NotFound => Err(anyhow!(
+            "{} does not exist; is virtualization enabled and the kvm module loaded?",
+            path.display()
+        )),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Err(anyhow!(
+            "no permission to use {}; is your user in the kvm group?",
+            path.display()
+        )),
+        Err(e) => Err(anyhow!("could not open {}: {}", path.display(), e)),
+    }
+}
+
+/// The accelerator to launch with. Without KVM, this falls back to TCG unless KVM is required.
+pub fn select(require_kvm: bool) -> Result<Accelerator> {
+    match kvm_usable() {
+        Ok(()) => Ok(Accelerator::Kvm),
+        Err(e) if require_kvm => Err(e),
+        Err(_) => Ok(Accelerator::Tcg),
+    }
+}
+
+/// The accelerator a qemu was launched with, judging by its arguments.
+pub fn from_args(args: &[String]) -> Option<Accelerator> {
+    let pos = args.iter().position(|arg| arg == "-machine")?;
+    args.get(pos + 1)?
+        .split(',')
+        .find_map(|opt| match opt.strip_prefix("accel=")? {
+            "kvm" => Some(Accelerator::Kvm),
+            "tcg" => Some(Accelerator::Tcg),
+            _ => None,
+        })
+}
+
+/// The accelerator of a running qemu.
+pub fn of_pid(pid: u32) -> Option<Accelerator> {
+    let cmdline = std::fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+    let args: Vec<String> = cmdline
+        .split(|b| *b == 0)
+        .map(|arg| String::from_utf8_lossy(arg).to_string())
+        .collect();
+    from_args(&args)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_kvm_usable() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("kvm");
+        assert!(kvm_usable_at(&path).is_err());
+        std::fs::write(&path, "")?;
+        assert!(kvm_usable_at(&path).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_args() -> Result<()> {
+        let args: Vec<String> = vec!["-nodefaults".into(), "-machine".into(), "accel=tcg".into()];
+        assert_eq!(from_args(&args), Some(Accelerator::Tcg));
+        let args: Vec<String> = vec!["-machine".into(), "q35,accel=kvm".into()];
+        assert_eq!(from_args(&args), Some(Accelerator::Kvm));
+        assert_eq!(from_args(&["-machine".into()]), None);
+        assert_eq!(from_args(&[]), None);
+        Ok(())
+    }
+}
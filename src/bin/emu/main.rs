@@ -2,5 +2,6 @@
 async fn main() {
     if let Err(e) = emu_cli::evaluate().await {
         println!("error: {}", e.to_string());
+        std::process::exit(1);
     }
 }
@@ -0,0 +1,125 @@
+use crate::config::{BootDevice, MachineConfiguration};
+use std::collections::HashMap;
+
+/// A device the firmware can boot from.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BootTarget {
+    /// A disk image, by its id in `[disks]`.
+    Disk(String),
+    /// The nth CD-ROM of a run: the `--cdrom` image, then the `--extra` one.
+    Cdrom(usize),
+    Network,
+}
+
+impl BootTarget {
+    /// The id of the qemu device, which is how its boot index is changed while it runs.
+    pub fn device_id(&self) -> String {
+        match self {
+            Self::Disk(id) => format!("disk{}-dev", id),
+            Self::Cdrom(x) => format!("cdrom{}-dev", x),
+            Self::Network => "nic0".to_string(),
+        }
+    }
+}
+
+pub type BootIndexes = HashMap<BootTarget, u32>;
+
+/// Boot indexes for the devices of a VM. `disks` are its disk ids in order, with their
+/// configured `bootindex`; `cdroms` is how many CD-ROMs the run has.
+///
+/// Without a boot order, only disks with a `bootindex` get one, as configured. With one, every
+/// device of the listed kinds is numbered in that order, and a disk's `bootindex` only orders it
+/// among the disks.
+pub fn indexes(
+    order: &[BootDevice],
+    disks: &[(String, Option<u32>)],
+    cdroms: usize,
+) -> BootIndexes {
+    if order.is_empty() {
+        return disks
+            .iter()
+            .filter_map(|(id, index)| index.map(|index| (BootTarget::Disk(id.clone()), index)))
+            .collect();
+    }
+
+    let mut disks = disks.to_vec();
+    // stable, so disks without a bootindex stay in order after the ones with one.
+    disks.sort_by_key(|(_, index)| index.unwrap_or(u32::MAX));
+
+    let mut targets = Vec::new();
+    for device in order {
+        match device {
+            BootDevice::Disk => {
+                targets.extend(disks.iter().map(|(id, _)| BootTarget::Disk(id.clone())))
+            }
+            BootDevice::Cdrom => targets.extend((0..cdroms).map(BootTarget::Cdrom)),
+            BootDevice::Network => targets.push(BootTarget::Network),
+        }
+    }
+
+    targets.into_iter().zip(0..).collect()
+}
+
+/// qemu arguments for the firmware's boot menu.
+pub fn menu_args(machine: &MachineConfiguration) -> Vec<String> {
+    if !machine.boot_menu {
+        return Vec::new();
+    }
+
+    let mut opts = "menu=on".to_string();
+    if let Some(timeout) = machine.boot_menu_timeout {
+        opts += &format!(",splash-time={}", timeout);
+    }
+
+    vec!["-boot".to_string(), opts]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_indexes() {
+        let disks = vec![
+            ("0".to_string(), None),
+            ("1".to_string(), Some(3)),
+            ("2".to_string(), None),
+        ];
+
+        assert_eq!(
+            indexes(&[], &disks, 1),
+            [(BootTarget::Disk("1".to_string()), 3)]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(
+            indexes(&[BootDevice::Cdrom, BootDevice::Disk], &disks, 2),
+            [
+                (BootTarget::Cdrom(0), 0),
+                (BootTarget::Cdrom(1), 1),
+                (BootTarget::Disk("1".to_string()), 2),
+                (BootTarget::Disk("0".to_string()), 3),
+                (BootTarget::Disk("2".to_string()), 4),
+            ]
+            .into_iter()
+            .collect()
+        );
+        assert_eq!(
+            indexes(&[BootDevice::Network, BootDevice::Cdrom], &disks, 0),
+            [(BootTarget::Network, 0)].into_iter().collect()
+        );
+    }
+
+    #[test]
+    fn test_menu_args() {
+        let mut machine = MachineConfiguration::default();
+        assert!(menu_args(&machine).is_empty());
+        machine.boot_menu = true;
+        assert_eq!(menu_args(&machine), vec!["-boot", "menu=on"]);
+        machine.boot_menu_timeout = Some(5000);
+        assert_eq!(
+            menu_args(&machine),
+            vec!["-boot", "menu=on,splash-time=5000"]
+        );
+    }
+}
@@ -0,0 +1,310 @@
+use crate::{config::MachineConfiguration, launcher::QEMU_BIN_NAME, util::path_exists};
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+    time::UNIX_EPOCH,
+};
+
+const CACHE_FILENAME: &str = "capabilities.json";
+
+// values `-drive if=` takes, and the device qemu attaches such a drive with, where we can check
+// for one.
+const DRIVE_INTERFACES: &[(&str, Option<&str>)] = &[
+    ("virtio", Some("virtio-blk-pci")),
+    ("ide", Some("ide-hd")),
+    ("scsi", Some("scsi-hd")),
+    ("floppy", Some("floppy")),
+    ("sd", Some("sd-card")),
+    ("pflash", None),
+    ("mtd", None),
+    ("none", None),
+];
+
+/// What the installed qemu supports, as far as the machine settings are concerned. Probing runs
+/// qemu several times, so the results are cached until qemu is upgraded.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    binary: PathBuf,
+    modified: u64,
+    pub version: String,
+    pub cpus: Vec<String>,
+    pub vgas: Vec<String>,
+    pub devices: Vec<String>,
+}
+
+fn locate_qemu() -> Option<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path)
+        .map(|p| p.join(QEMU_BIN_NAME))
+        .find(|p| path_exists(p.clone()))
+}
+
+fn cache_path() -> Option<PathBuf> {
+    dirs::cache_dir().map(|dir| dir.join("emu").join(CACHE_FILENAME))
+}
+
+fn modified(path: &Path) -> Result<u64> {
+    Ok(std::fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)?
+        .as_secs())
+}
+
+fn help(binary: &Path, args: &[&str]) -> Result<String> {
+    let out = Command::new(binary)
+        .args(args)
+        .output()
+        .map_err(|e| anyhow!("could not run {}: {}", binary.display(), e))?;
+
+    if !out.status.success() {
+        return Err(anyhow!(
+            "{} {} failed: {}",
+            binary.display(),
+            args.join(" "),
+            out.status
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&out.stdout).to_string())
+}
+
+fn parse_version(out: &str) -> String {
+    out.split_once("version ")
+        .and_then(|(_, rest)| rest.split_whitespace().next())
+        .unwrap_or_default()
+        .to_string()
+}
+
+// `-cpu help` lists models under "Available CPUs:" as `<name>  <description>`, which some x86
+// builds prefix with `x86 ` and others indent, followed by other sections such as the CPUID
+// flags.
+fn parse_cpus(out: &str) -> Vec<String> {
+    let mut v = Vec::new();
+    for line in out.lines().map(str::trim) {
+        if line.is_empty() || line.ends_with(':') {
+            if v.is_empty() {
+                continue;
+            }
+
+            break;
+        }
+
+        let line = line.strip_prefix("x86 ").unwrap_or(line);
+        if let Some(name) = line.split_whitespace().next() {
+            v.push(name.to_string());
+        }
+    }
+
+    v
+}
+
+// `-vga help` lists `<name>  <description>`.
+fn parse_vgas(out: &str) -> Vec<String> {
+    out.lines()
+        .filter_map(|line| line.split_whitespace().next())
+        .filter(|name| !name.ends_with(':'))
+        .map(ToString::to_string)
+        .collect()
+}
+
+// `-device help` lists devices as `name "virtio-blk-pci", bus PCI, alias "virtio-blk"`.
+fn parse_devices(out: &str) -> Vec<String> {
+    let mut v = Vec::new();
+    for line in out.lines() {
+        for key in ["name \"", "alias \""] {
+            if let Some((_, rest)) = line.split_once(key) {
+                if let Some((name, _)) = rest.split_once('"') {
+                    v.push(name.to_string());
+                }
+            }
+        }
+    }
+
+    v
+}
+
+impl Capabilities {
+    pub fn probe(binary: &Path) -> Result<Self> {
+        Ok(Self {
+            binary: binary.to_path_buf(),
+            modified: modified(binary)?,
+            version: parse_version(&help(binary, &["-version"])?),
+            cpus: parse_cpus(&help(binary, &["-cpu", "help"])?),
+            vgas: parse_vgas(&help(binary, &["-vga", "help"])?),
+            devices: parse_devices(&help(binary, &["-device", "help"])?),
+        })
+    }
+
+    /// The capabilities of the qemu on `$PATH`, from the cache if qemu hasn't changed since it
+    /// was last probed.
+    pub fn load() -> Result<Self> {
+        let binary = locate_qemu().ok_or_else(|| anyhow!("{} is not installed", QEMU_BIN_NAME))?;
+        let cache = cache_path();
+
+        if let Some(cache) = &cache {
+            if let Ok(s) = std::fs::read_to_string(cache) {
+                if let Ok(caps) = serde_json::from_str::<Self>(&s) {
+                    if caps.binary == binary && caps.modified == modified(&binary)? {
+                        return Ok(caps);
+                    }
+                }
+            }
+        }
+
+        let caps = Self::probe(&binary)?;
+        if let Some(cache) = &cache {
+            // a missing cache only costs time.
+            if let Some(parent) = cache.parent() {
+                std::fs::create_dir_all(parent).unwrap_or_default();
+            }
+            std::fs::write(cache, serde_json::to_string(&caps)?).unwrap_or_default();
+        }
+
+        Ok(caps)
+    }
+
+    pub fn valid_cpu_type(&self, cpu_type: &str) -> Result<()> {
+        // older configurations may carry properties here; cpu_flags is the place for them now.
+        let model = cpu_type.split(',').next().unwrap_or_default();
+        if !self.cpus.is_empty() && !self.cpus.iter().any(|cpu| cpu == model) {
+            return Err(anyhow!(
+                "qemu {} has no CPU model '{}'; `{} -cpu help` lists them",
+                self.version,
+                model,
+                QEMU_BIN_NAME
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn valid_vga(&self, vga: &str) -> Result<()> {
+        if !self.vgas.is_empty() && !self.vgas.iter().any(|v| v == vga) {
+            return Err(anyhow!(
+                "qemu {} has no VGA card '{}'; choose one of: {}",
+                self.version,
+                vga,
+                self.vgas.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn valid_image_interface(&self, interface: &str) -> Result<()> {
+        match DRIVE_INTERFACES.iter().find(|(name, _)| *name == interface) {
+            None => Err(anyhow!(
+                "'{}' is not a drive interface; choose one of: {}",
+                interface,
+                DRIVE_INTERFACES
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            )),
+            Some((_, Some(device)))
+                if !self.devices.is_empty() && !self.devices.iter().any(|d| d == device) =>
+            {
+                Err(anyhow!(
+                    "qemu {} lacks the {} device needed for the {} interface",
+                    self.version,
+                    device,
+                    interface
+                ))
+            }
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// Check the machine settings qemu would otherwise only reject at boot. A list that came
+    /// back empty means qemu's output could not be parsed, not that it supports nothing, so its
+    /// check is skipped and qemu gets to complain instead.
+    pub fn valid(&self, machine: &MachineConfiguration) -> Result<()> {
+        self.valid_cpu_type(&machine.cpu_type)?;
+        self.valid_vga(&machine.vga)?;
+        self.valid_image_interface(&machine.image_interface)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    const CPU_HELP: &str = "Available CPUs:
+x86 486                   (alias configured by machine type)
+x86 host                  processor with all supported host features
+x86 max                   Enables all features supported by the accelerator in the current host
+x86 qemu64                QEMU Virtual CPU version 2.5+
+
+Recognized CPUID flags:
+  3dnow 3dnowext 3dnowprefetch abm
+";
+
+    const INDENTED_CPU_HELP: &str = "Available CPUs:
+  486                   (alias configured by machine type)
+  host                  processor with all supported host features
+  qemu64                QEMU Virtual CPU version 2.5+
+
+Recognized CPUID flags:
+  3dnow 3dnowext 3dnowprefetch abm
+";
+
+    const VGA_HELP: &str = "none                 no graphic card
+std                  standard VGA (default)
+virtio               Virtio VGA
+";
+
+    const DEVICE_HELP: &str = "Storage devices:
+name \"ide-hd\", bus IDE, desc \"virtual IDE disk\"
+name \"virtio-blk-pci\", bus PCI, alias \"virtio-blk\"
+";
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!(
+            parse_version("QEMU emulator version 8.2.2 (Debian 1:8.2.2+ds-0ubuntu1)\n"),
+            "8.2.2"
+        );
+        assert_eq!(parse_cpus(CPU_HELP), vec!["486", "host", "max", "qemu64"]);
+        assert_eq!(parse_cpus(INDENTED_CPU_HELP), vec!["486", "host", "qemu64"]);
+        assert!(parse_cpus("").is_empty());
+        assert_eq!(parse_vgas(VGA_HELP), vec!["none", "std", "virtio"]);
+        assert_eq!(
+            parse_devices(DEVICE_HELP),
+            vec!["ide-hd", "virtio-blk-pci", "virtio-blk"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_valid() -> Result<()> {
+        let caps = Capabilities {
+            version: "8.2.2".to_string(),
+            cpus: parse_cpus(CPU_HELP),
+            vgas: parse_vgas(VGA_HELP),
+            devices: parse_devices(DEVICE_HELP),
+            ..Default::default()
+        };
+
+        let mut machine = MachineConfiguration::default();
+        machine.cpu_type = "host".to_string();
+        caps.valid(&machine)?;
+        assert!(caps.valid_cpu_type("host,migratable=no").is_ok());
+        assert!(caps.valid_cpu_type("banana").is_err());
+        assert!(caps.valid_vga("cirrus").is_err());
+        assert!(caps.valid_image_interface("ide").is_ok());
+        assert!(caps.valid_image_interface("scsi").is_err());
+        assert!(caps.valid_image_interface("usb").is_err());
+
+        // nothing parsed is nothing to check against.
+        let caps = Capabilities::default();
+        assert!(caps.valid_cpu_type("banana").is_ok());
+        assert!(caps.valid_vga("cirrus").is_ok());
+        assert!(caps.valid_image_interface("scsi").is_ok());
+        assert!(caps.valid_image_interface("usb").is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,619 @@
+use crate::{
+    accel,
+    cloud_init::CloudInit,
+    config::{CacheMode, Firmware},
+    config_storage::{disk_id, XDGConfigStorage},
+    hooks::{HookEvent, Hooks},
+    launcher::daemonize,
+    logs::{OutputLogger, SerialLogger},
+    rest::RestClient,
+    shares::{self, Share},
+    snapshot,
+    template::{self, ExtraArgsData},
+    traits::{ConfigStorageHandler, Launcher},
+    util::{path_exists, pid_running},
+    vm::VM,
+};
+use anyhow::{anyhow, Result};
+use serde_json::json;
+use std::{
+    fs::{read_to_string, remove_file},
+    path::PathBuf,
+    process::{Command, ExitStatus, Stdio},
+    sync::Arc,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+pub const CLOUD_HYPERVISOR_BIN_NAME: &str = "cloud-hypervisor";
+const API_SOCKET: &str = "api.sock";
+const SNAPSHOTS_DIR: &str = "snapshots";
+const API_TIMEOUT: Duration = Duration::from_secs(10);
+
+// rust-hypervisor-firmware boots disks the way a BIOS would; CLOUDHV.fd is OVMF built for
+// cloud-hypervisor.
+const BIOS_SEARCH_PATHS: &[&str] = &[
+    "/usr/share/cloud-hypervisor/hypervisor-fw",
+    "/usr/lib/cloud-hypervisor/hypervisor-fw",
+];
+const UEFI_SEARCH_PATHS: &[&str] = &[
+    "/usr/share/cloud-hypervisor/CLOUDHV.fd",
+    "/usr/share/edk2/cloudhv/CLOUDHV.fd",
+    "/usr/share/ovmf/CLOUDHV.fd",
+];
+
+/// Runs VMs under cloud-hypervisor, which boots much faster than qemu at the cost of most of
+/// its devices: there is no display, no user-mode networking and no CD-ROM.
+#[derive(Debug, Clone)]
+pub struct CloudHypervisorLauncher {
+    config: Arc<Box<dyn ConfigStorageHandler>>,
+}
+
+impl Default for CloudHypervisorLauncher {
+    fn default() -> Self {
+        Self {
+            config: Arc::new(Box::new(XDGConfigStorage::default())),
+        }
+    }
+}
+
+impl CloudHypervisorLauncher {
+    pub fn new(config: Arc<Box<dyn ConfigStorageHandler>>) -> Self {
+        Self { config }
+    }
+
+    fn api_path(&self, vm: &VM) -> PathBuf {
+        self.config.vm_path(vm, API_SOCKET)
+    }
+
+    fn api(&self, vm: &VM) -> RestClient {
+        RestClient::new(self.api_path(vm))
+    }
+
+    fn snapshot_path(&self, vm: &VM, name: &str) -> PathBuf {
+        self.config.vm_path(vm, SNAPSHOTS_DIR).join(name)
+    }
+
+    fn running(&self, vm: &VM) -> bool {
+        read_to_string(self.config.pidfile(vm))
+            .ok()
+            .and_then(|s| s.trim().parse::<u32>().ok())
+            .is_some_and(pid_running)
+    }
+
+    fn wait_api(&self, vm: &VM) -> Result<()> {
+        let start = Instant::now();
+        while self.api(vm).get("/api/v1/vmm.ping").is_err() {
+            if start.elapsed() > API_TIMEOUT {
+                return Err(anyhow!(
+                    "timed out waiting for the {} API",
+                    CLOUD_HYPERVISOR_BIN_NAME
+                ));
+            }
+
+            sleep(Duration::from_millis(50));
+        }
+
+        Ok(())
+    }
+
+    // settings only qemu can honor are refused rather than ignored.
+    fn check(&self, vm: &VM) -> Result<()> {
+        let config = vm.config();
+        let unsupported = |what: &str| {
+            Err(anyhow!(
+                "{} is not supported with {}",
+                what,
+                CLOUD_HYPERVISOR_BIN_NAME
+            ))
+        };
+
+        if !config.ports.is_empty() {
+            return unsupported("port forwarding (there is no user-mode networking)");
+        }
+
+        if !config.machine.numa.is_empty() {
+            return unsupported("numa");
+        }
+
+        if !config.machine.cpu_flags.is_empty() {
+            return unsupported("cpu_flags");
+        }
+
+        if config.machine.tpm {
+            return unsupported("tpm");
+        }
+
+        if !config.devices.is_empty() {
+            return unsupported("[[devices]]");
+        }
+
+        if vm.cdrom().is_some() || vm.extra_disk().is_some() {
+            return unsupported("a CD-ROM");
+        }
+
+        if !config.machine.boot_order.is_empty()
+            || config.machine.boot_menu
+            || vm.boot_once().is_some()
+        {
+            return unsupported("boot_order, boot_menu and --boot-once");
+        }
+
+        for (id, disk) in &config.disks {
+            if disk.interface.is_some()
+                || disk.aio.is_some()
+                || disk.bootindex.is_some()
+                || disk.discard
+                || disk.detect_zeroes
+            {
+                return unsupported(&format!(
+                    "disk {}'s settings other than cache and read_only",
+                    id
+                ));
+            }
+        }
+
+        if !config.shares.is_empty() && shares::locate_virtiofsd().is_none() {
+            return unsupported(&format!(
+                "sharing directories without {}",
+                shares::VIRTIOFSD_BIN_NAME
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn firmware(&self, vm: &VM) -> Result<PathBuf> {
+        let config = vm.config();
+        let paths = match config.machine.firmware {
+            Firmware::Uefi => {
+                if let Some(code) = config.machine.ovmf_code {
+                    return Ok(code);
+                }
+                UEFI_SEARCH_PATHS
+            }
+            Firmware::Bios => BIOS_SEARCH_PATHS,
+        };
+
+        paths
+            .iter()
+            .map(PathBuf::from)
+            .find(|p| path_exists(p.clone()))
+            .ok_or_else(|| {
+                anyhow!(
+                    "no {} firmware for {} found; looked in: {}",
+                    config.machine.firmware,
+                    CLOUD_HYPERVISOR_BIN_NAME,
+                    paths.join(", ")
+                )
+            })
+    }
+
+    fn args(&self, vm: &VM) -> Result<Vec<String>> {
+        let config = vm.config();
+        let machine = &config.machine;
+        let mut v = vec![
+            "--api-socket".to_string(),
+            format!("path={}", self.api_path(vm).display()),
+        ];
+
+        let mut cpus = format!("boot={}", machine.cpus);
+        if machine.maxcpus() != machine.cpus {
+            cpus += &format!(",max={}", machine.maxcpus());
+        }
+        if machine.sockets.is_some() || machine.cores.is_some() || machine.threads.is_some() {
+            let (sockets, cores, threads) = machine.topology();
+            cpus += &format!(",topology={}:{}:1:{}", threads, cores, sockets);
+        }
+        if !machine.cpu_pinning.is_empty() {
+            let affinity: Vec<String> = machine
+                .cpu_pinning
+                .iter()
+                .enumerate()
+                .map(|(vcpu, cpu)| format!("{}@[{}]", vcpu, cpu))
+                .collect();
+            cpus += &format!(",affinity=[{}]", affinity.join(","));
+        }
+        v.push("--cpus".to_string());
+        v.push(cpus);
+
+        // virtiofsd needs to see guest memory.
+        let mut memory = format!("size={}M", machine.memory);
+        if machine.memory_shared || !config.shares.is_empty() {
+            memory += ",shared=on";
+        }
+        if machine.memory_backend == crate::config::MemoryBackend::Hugepages {
+            memory += ",hugepages=on";
+        }
+        if machine.memory_prealloc {
+            memory += ",prefault=on";
+        }
+        v.push("--memory".to_string());
+        v.push(memory);
+
+        if let Some(kernel) = &machine.kernel {
+            v.push("--kernel".to_string());
+            v.push(kernel.display().to_string());
+            if let Some(initrd) = &machine.initrd {
+                v.push("--initramfs".to_string());
+                v.push(initrd.display().to_string());
+            }
+            if let Some(append) = &machine.append {
+                v.push("--cmdline".to_string());
+                v.push(append.clone());
+            }
+        } else {
+            v.push("--firmware".to_string());
+            v.push(self.firmware(vm)?.display().to_string());
+        }
+
+        let mut disks = Vec::new();
+        for disk in self.config.disk_list(vm)? {
+            let settings = config
+                .disks
+                .get(&disk_id(&disk))
+                .cloned()
+                .unwrap_or_default();
+            let mut opts = format!("path={}", disk.display());
+            if settings.read_only {
+                opts += ",readonly=on";
+            }
+            if matches!(settings.cache, CacheMode::None | CacheMode::Directsync) {
+                opts += ",direct=on";
+            }
+            // the overlays of an ephemeral run are nothing without the disks they cover, and
+            // cloud-hypervisor refuses backing files unless told otherwise.
+            if vm.overlays().is_some() {
+                opts += ",backing_files=on";
+            }
+            disks.push(opts);
+        }
+        if path_exists(self.config.cloud_init_path(vm)) {
+            disks.push(format!(
+                "path={},readonly=on",
+                self.config.seed_path(vm).display()
+            ));
+        }
+        if !disks.is_empty() {
+            v.push("--disk".to_string());
+            v.append(&mut disks);
+        }
+
+        let shares = Share::list(&config.shares);
+        if !shares.is_empty() {
+            v.push("--fs".to_string());
+            for share in shares {
+                v.push(format!(
+                    "tag={},socket={}",
+                    share.tag,
+                    share.socket_path(&self.config.shares_path(vm)).display()
+                ));
+            }
+        }
+
+        if machine.balloon {
+            v.push("--balloon".to_string());
+            v.push(if machine.free_page_reporting {
+                "size=0,free_page_reporting=on".to_string()
+            } else {
+                "size=0".to_string()
+            });
+        }
+
+        // the serial port goes straight into the logger's fifo; there is no console to attach to.
+        v.push("--serial".to_string());
+        v.push(format!(
+            "file={}",
+            self.config.serial_fifo_path(vm).display()
+        ));
+        v.push("--console".to_string());
+        v.push("off".to_string());
+
+        if !config.extra_args.is_empty() {
+            let data = ExtraArgsData::new(
+                vm,
+                &self.config.vm_root(vm),
+                &self.api_path(vm),
+                &self.config.serial_path(vm),
+            );
+            v.append(&mut template::extra_args(&config.extra_args, &data)?);
+        }
+
+        Ok(v)
+    }
+
+    fn prepare(&self, vm: &VM) -> Result<()> {
+        accel::kvm_usable()?;
+        self.check(vm)?;
+
+        let path = self.config.cloud_init_path(vm);
+        if path_exists(path.clone()) {
+            CloudInit::from_file(&path)?.write_seed(&vm.name(), &self.config.seed_path(vm))?;
+        }
+
+        if !vm.config().shares.is_empty() {
+            std::fs::create_dir_all(self.config.shares_path(vm))?;
+        }
+
+        // cloud-hypervisor refuses to start on a stale socket.
+        let api = self.api_path(vm);
+        if path_exists(api.clone()) {
+            remove_file(api)?;
+        }
+
+        Ok(())
+    }
+
+    // runs cloud-hypervisor until it exits, along with the helpers it needs. `detached` writes the
+    // pidfile; `resume` resumes a VM restored from a snapshot, which starts out paused.
+    fn run(&self, vm: &VM, args: Vec<String>, detached: bool, resume: bool) -> Result<ExitStatus> {
+        let logger = SerialLogger::start(
+            self.config.serial_fifo_path(vm),
+            self.config.serial_log_path(vm),
+        )?;
+        let mut output =
+            OutputLogger::start(self.config.qemu_log_path(vm), CLOUD_HYPERVISOR_BIN_NAME)?;
+        let virtiofsd = match shares::start(&vm.config().shares, &self.config.shares_path(vm)) {
+            Ok(v) => v,
+            Err(e) => {
+                output.finish(&format!("could not start: {}", e));
+                return Err(e);
+            }
+        };
+        let hooks = Hooks::new(self.config.clone());
+
+        let status = Command::new(CLOUD_HYPERVISOR_BIN_NAME)
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                output.capture(&mut child);
+                if detached {
+                    std::fs::write(self.config.pidfile(vm), format!("{}", child.id()))?;
+                }
+                if resume {
+                    if let Err(e) = self
+                        .wait_api(vm)
+                        .and_then(|_| self.api(vm).put("/api/v1/vm.resume", None))
+                    {
+                        println!("could not resume {}: {}", vm, e);
+                    }
+                }
+                hooks.notify(vm, HookEvent::PostStart, &[]);
+                child.wait()
+            });
+
+        match &status {
+            Ok(status) => output.finish(&format!("exited with {}", status)),
+            Err(e) => output.finish(&format!("could not start: {}", e)),
+        }
+        logger.finish();
+        let stopped = shares::stop(virtiofsd);
+        if status.is_ok() {
+            hooks.notify(vm, HookEvent::PostStop, &[]);
+        }
+        let status = status?;
+        stopped?;
+        Ok(status)
+    }
+}
+
+impl Launcher for CloudHypervisorLauncher {
+    fn command_line(&self, vm: &VM) -> Result<Vec<String>> {
+        let mut v = vec![CLOUD_HYPERVISOR_BIN_NAME.to_string()];
+        v.append(&mut self.args(vm)?);
+        Ok(v)
+    }
+
+    fn launch_attached(&self, vm: &VM) -> Result<ExitStatus> {
+        self.prepare(vm)?;
+        let args = self.args(vm)?;
+        self.run(vm, args, false, false)
+    }
+
+    fn launch_detached(&self, vm: &VM) -> Result<()> {
+        self.prepare(vm)?;
+        let args = self.args(vm)?;
+        daemonize(&self.config, vm, CLOUD_HYPERVISOR_BIN_NAME, || {
+            self.run(vm, args, true, false).map(|_| ())
+        })
+    }
+
+    fn shutdown_wait(&self, vm: &VM) -> Result<ExitStatus> {
+        self.shutdown_immediately(vm)?;
+
+        let pidfile = self.config.pidfile(vm);
+        let pid = read_to_string(&pidfile)?.trim().parse::<u32>()?;
+        let mut start = Instant::now();
+        while pid_running(pid) {
+            sleep(Duration::from_millis(50));
+            if start.elapsed() > Duration::from_secs(10) {
+                println!("Waiting for {} to quit...", CLOUD_HYPERVISOR_BIN_NAME);
+                start = Instant::now();
+            }
+        }
+        remove_file(pidfile)?;
+
+        Ok(ExitStatus::default())
+    }
+
+    fn shutdown_immediately(&self, vm: &VM) -> Result<()> {
+        self.api(vm).put("/api/v1/vm.power-button", None)?;
+        Ok(())
+    }
+
+    fn reset(&self, vm: &VM) -> Result<()> {
+        self.api(vm).put("/api/v1/vm.reboot", None)?;
+        Ok(())
+    }
+
+    fn snapshot(&self, vm: &VM, name: String) -> Result<()> {
+        let path = self.snapshot_path(vm, &name);
+        if path_exists(path.clone()) {
+            std::fs::remove_dir_all(&path)?;
+        }
+        std::fs::create_dir_all(&path)?;
+
+        // the VM has to be paused while it is saved, and resumed whether that worked or not.
+        let api = self.api(vm);
+        api.put("/api/v1/vm.pause", None)?;
+        let res = api
+            .put(
+                "/api/v1/vm.snapshot",
+                Some(json!({"destination_url": format!("file://{}", path.display())})),
+            )
+            .and_then(|_| snapshot::save_disks(&**self.config, vm, &path));
+        api.put("/api/v1/vm.resume", None)?;
+        if res.is_err() {
+            std::fs::remove_dir_all(&path).unwrap_or_default();
+        }
+        res?;
+
+        println!("Saved current state to snapshot '{}'", name);
+        Ok(())
+    }
+
+    fn restore(&self, vm: &VM, name: String) -> Result<()> {
+        let path = self.snapshot_path(vm, &name);
+        if !path_exists(path.clone()) {
+            return Err(anyhow!("{} has no snapshot '{}'", vm, name));
+        }
+
+        if self.running(vm) {
+            return Err(anyhow!(
+                "{} can only restore a VM that is not running; shut {} down first",
+                CLOUD_HYPERVISOR_BIN_NAME,
+                vm
+            ));
+        }
+
+        snapshot::restore_disks(&**self.config, vm, &name, &path)?;
+
+        self.prepare(vm)?;
+        let args = vec![
+            "--api-socket".to_string(),
+            format!("path={}", self.api_path(vm).display()),
+            "--restore".to_string(),
+            format!("source_url=file://{}", path.display()),
+        ];
+        daemonize(&self.config, vm, CLOUD_HYPERVISOR_BIN_NAME, || {
+            self.run(vm, args, true, true).map(|_| ())
+        })?;
+
+        println!("Restored from snapshot '{}'", name);
+        Ok(())
+    }
+
+    fn delete_snapshot(&self, vm: &VM, name: String) -> Result<()> {
+        let path = self.snapshot_path(vm, &name);
+        if !path_exists(path.clone()) {
+            return Err(anyhow!("{} has no snapshot '{}'", vm, name));
+        }
+
+        std::fs::remove_dir_all(path)?;
+        println!("Deleted snapshot '{}'", name);
+        Ok(())
+    }
+
+    // the balloon is sized by how much memory it takes away, not how much is left.
+    fn balloon(&self, vm: &VM, megabytes: u32) -> Result<()> {
+        let inflate = vm.config().machine.memory.saturating_sub(megabytes) as u64;
+        self.api(vm).put(
+            "/api/v1/vm.resize",
+            Some(json!({"desired_balloon": inflate * 1024 * 1024})),
+        )?;
+        Ok(())
+    }
+
+    fn balloon_size(&self, vm: &VM) -> Result<u32> {
+        let info = self
+            .api(vm)
+            .get("/api/v1/vm.info")?
+            .ok_or_else(|| anyhow!("{} returned no VM info", CLOUD_HYPERVISOR_BIN_NAME))?;
+
+        match info["memory_actual_size"].as_u64() {
+            Some(size) => Ok((size / 1024 / 1024) as u32),
+            None => Err(anyhow!(
+                "{} did not report the VM's memory size",
+                CLOUD_HYPERVISOR_BIN_NAME
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Configuration;
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_command_line() -> Result<()> {
+        let dir = tempdir()?;
+        let storage: Arc<Box<dyn ConfigStorageHandler>> =
+            Arc::new(Box::new(XDGConfigStorage::new(dir.path().to_path_buf())));
+        let launcher = CloudHypervisorLauncher::new(storage.clone());
+
+        let mut vm = VM::new("vm1".to_string(), storage.clone());
+        storage.create(&vm)?;
+        let root = storage.vm_root(&vm);
+        std::fs::write(root.join("qemu-0.qcow2"), "")?;
+
+        let mut config = Configuration::default();
+        config.machine.memory = 1024;
+        config.machine.cpus = 2;
+        config.machine.cpu_pinning = vec![4, 5];
+        config.machine.balloon = true;
+        config.machine.kernel = Some(PathBuf::from("/boot/vmlinuz"));
+        config.machine.append = Some("console=ttyS0".to_string());
+        config.set_disk_value(0, "read-only", "true")?;
+        vm.set_config(config.clone());
+
+        let root = root.display();
+        assert_eq!(
+            launcher.command_line(&vm)?,
+            vec![
+                "cloud-hypervisor".to_string(),
+                "--api-socket".to_string(),
+                format!("path={}/api.sock", root),
+                "--cpus".to_string(),
+                "boot=2,affinity=[0@[4],1@[5]]".to_string(),
+                "--memory".to_string(),
+                "size=1024M".to_string(),
+                "--kernel".to_string(),
+                "/boot/vmlinuz".to_string(),
+                "--cmdline".to_string(),
+                "console=ttyS0".to_string(),
+                "--disk".to_string(),
+                format!("path={}/qemu-0.qcow2,readonly=on,direct=on", root),
+                "--balloon".to_string(),
+                "size=0".to_string(),
+                "--serial".to_string(),
+                format!("file={}/serial.fifo", root),
+                "--console".to_string(),
+                "off".to_string(),
+            ]
+        );
+
+        // ephemeral runs boot from overlays, which need their backing files.
+        let overlays = dir.path().join("overlays");
+        std::fs::create_dir(&overlays)?;
+        vm.set_overlays(overlays.clone());
+        let argv = launcher.command_line(&vm)?;
+        let disk = argv.iter().position(|arg| arg == "--disk").unwrap() + 1;
+        assert_eq!(
+            argv[disk],
+            format!(
+                "path={}/qemu-0.qcow2,readonly=on,direct=on,backing_files=on",
+                overlays.display()
+            )
+        );
+
+        config.map_port(2222, 22);
+        vm.set_config(config);
+        assert!(launcher.check(&vm).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,152 @@
+use crate::iso9660::Iso9660;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{io::Write, path::Path};
+
+const VOLUME_ID: &str = "cidata";
+const DEFAULT_USER_DATA: &str = "#cloud-config\n";
+
+/// NoCloud seed data for a VM. Files given by the user are stored verbatim; anything not given is
+/// generated from the hostname and ssh keys at launch time.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CloudInit {
+    #[serde(default)]
+    pub hostname: Option<String>,
+    #[serde(default)]
+    pub ssh_authorized_keys: Vec<String>,
+    #[serde(default)]
+    pub user_data: Option<String>,
+    #[serde(default)]
+    pub meta_data: Option<String>,
+    #[serde(default)]
+    pub network_config: Option<String>,
+}
+
+impl std::fmt::Display for CloudInit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&toml::to_string_pretty(self).map_err(|_| std::fmt::Error)?)
+    }
+}
+
+// single-quoted YAML scalars only need their quotes doubled
+fn yaml_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+impl CloudInit {
+    pub fn from_file(filename: &Path) -> Result<Self> {
+        Ok(toml::from_str(&std::fs::read_to_string(filename)?)?)
+    }
+
+    pub fn to_file(&self, filename: &Path) -> Result<()> {
+        let mut f = std::fs::File::create(filename)?;
+        f.write_all(self.to_string().as_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn valid(&self) -> Result<()> {
+        if self.meta_data.is_some()
+            && (self.hostname.is_some() || !self.ssh_authorized_keys.is_empty())
+        {
+            return Err(anyhow!(
+                "hostname and ssh keys cannot be combined with custom meta-data; put them in the meta-data instead"
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn meta_data(&self, vm_name: &str) -> String {
+        if let Some(meta_data) = &self.meta_data {
+            return meta_data.clone();
+        }
+
+        let mut s = format!(
+            "instance-id: {}\nlocal-hostname: {}\n",
+            yaml_quote(vm_name),
+            yaml_quote(self.hostname.as_deref().unwrap_or(vm_name))
+        );
+
+        if !self.ssh_authorized_keys.is_empty() {
+            s += "public-keys:\n";
+            for key in &self.ssh_authorized_keys {
+                s += &format!("  - {}\n", yaml_quote(key));
+            }
+        }
+
+        s
+    }
+
+    pub fn user_data(&self) -> String {
+        self.user_data
+            .clone()
+            .unwrap_or(DEFAULT_USER_DATA.to_string())
+    }
+
+    pub fn seed(&self, vm_name: &str) -> Result<Iso9660> {
+        self.valid()?;
+
+        let mut iso = Iso9660::new(VOLUME_ID);
+        iso.add_file("meta-data", self.meta_data(vm_name).into_bytes())?;
+        iso.add_file("user-data", self.user_data().into_bytes())?;
+        if let Some(network_config) = &self.network_config {
+            iso.add_file("network-config", network_config.clone().into_bytes())?;
+        }
+
+        Ok(iso)
+    }
+
+    pub fn write_seed(&self, vm_name: &str, path: &Path) -> Result<()> {
+        self.seed(vm_name)?.write(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_meta_data() -> Result<()> {
+        let mut ci = CloudInit::default();
+        assert_eq!(
+            ci.meta_data("vm1"),
+            "instance-id: 'vm1'\nlocal-hostname: 'vm1'\n"
+        );
+        assert_eq!(ci.user_data(), DEFAULT_USER_DATA);
+
+        ci.hostname = Some("box".to_string());
+        ci.ssh_authorized_keys
+            .push("ssh-ed25519 AAAA erik's key".to_string());
+        assert_eq!(
+            ci.meta_data("vm1"),
+            "instance-id: 'vm1'\nlocal-hostname: 'box'\npublic-keys:\n  - 'ssh-ed25519 AAAA erik''s key'\n"
+        );
+        assert!(ci.valid().is_ok());
+
+        ci.meta_data = Some("instance-id: custom\n".to_string());
+        assert!(ci.valid().is_err());
+        ci.hostname = None;
+        ci.ssh_authorized_keys.clear();
+        assert!(ci.valid().is_ok());
+        assert_eq!(ci.meta_data("vm1"), "instance-id: custom\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_io() -> Result<()> {
+        let tmp = NamedTempFile::new()?;
+        let ci = CloudInit {
+            hostname: Some("box".to_string()),
+            network_config: Some("version: 2\n".to_string()),
+            ..Default::default()
+        };
+        ci.to_file(tmp.path())?;
+        assert_eq!(CloudInit::from_file(tmp.path())?, ci);
+        assert!(ci.seed("vm1")?.build()?.len() > 0);
+        Ok(())
+    }
+}
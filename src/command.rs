@@ -1,3 +1,4 @@
+use crate::{config::BootDevice, devices::Device};
 use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
@@ -46,6 +47,43 @@ pub enum CommandType {
         /// Port of VM
         port: u16,
     },
+    /// Attach the terminal to the VM's serial console (Ctrl-] detaches)
+    Console {
+        /// Name of VM
+        name: String,
+    },
+    /// Show the serial console log of a VM
+    Logs {
+        /// Keep printing new output as it arrives
+        #[arg(short, long, default_value = "false")]
+        follow: bool,
+        /// Only show output since this time; a duration like 10m, 2h, 1d or a UTC timestamp
+        #[arg(short, long)]
+        since: Option<String>,
+        /// Name of VM
+        name: String,
+    },
+    /// Print the qemu command line used to launch a VM, without launching it
+    Args {
+        /// Print the arguments as a JSON array instead of a shell command
+        #[arg(short, long, default_value = "false")]
+        json: bool,
+        /// Name of VM
+        name: String,
+    },
+    /// Open a viewer on the VNC or SPICE display of a VM
+    Display {
+        /// Name of VM
+        name: String,
+    },
+    /// Serve a browser-based viewer for the VNC display of a VM on localhost
+    WebConsole {
+        /// Port on localhost to serve the web console on
+        #[arg(short, long, default_value = "6080")]
+        port: u16,
+        /// Name of VM
+        name: String,
+    },
     /// Uses ssh_port configuration variable to SSH into the host
     SSH {
         /// Name of VM
@@ -80,6 +118,24 @@ pub enum CommandType {
         /// Supply an extra ISO image (useful for windows installations)
         #[arg(long = "extra")]
         extra_disk: Option<PathBuf>,
+        /// Boot this kernel image directly instead of going through the firmware
+        #[arg(long)]
+        kernel: Option<PathBuf>,
+        /// Initial ramdisk to use with --kernel
+        #[arg(long)]
+        initrd: Option<PathBuf>,
+        /// Kernel command line to use with --kernel
+        #[arg(long)]
+        append: Option<String>,
+        /// Print the qemu command line instead of launching the VM
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+        /// Boot from throwaway overlays of the disks, leaving the VM untouched
+        #[arg(long, default_value = "false")]
+        ephemeral: bool,
+        /// Boot from this device (disk, cdrom or network) first; reboots follow the boot order
+        #[arg(long)]
+        boot_once: Option<BootDevice>,
         /// Name of VM
         name: String,
     },
@@ -91,6 +147,27 @@ pub enum CommandType {
         /// Name of VM
         name: String,
     },
+    /// Wait until a running VM's guest is ready to use; by default, until SSH answers
+    #[command(group = clap::ArgGroup::new("readiness").args(["ssh", "port", "agent", "serial_regex"]))]
+    Wait {
+        /// Wait for the SSH server on the VM's ssh-port to answer (the default)
+        #[arg(long, default_value = "false")]
+        ssh: bool,
+        /// Wait for something in the guest to accept connections on this forwarded host port
+        #[arg(long)]
+        port: Option<u16>,
+        /// Wait for the qemu guest agent to answer
+        #[arg(long, default_value = "false")]
+        agent: bool,
+        /// Wait for a line of serial output matching this regular expression
+        #[arg(long)]
+        serial_regex: Option<String>,
+        /// Give up after this long; a duration like 90s or 5m
+        #[arg(short, long, default_value = "5m")]
+        timeout: String,
+        /// Name of VM
+        name: String,
+    },
     /// Issue QMP commands to the guest
     QMP {
         /// Name of VM
@@ -128,6 +205,29 @@ pub enum CommandType {
         /// VM image to import from
         from_file: PathBuf,
     },
+    /// Configure cloud-init NoCloud seed data for a VM; shows the current settings without flags
+    CloudInit {
+        /// Hostname for the guest
+        #[arg(long)]
+        hostname: Option<String>,
+        /// SSH public key, or a file containing one, to authorize; may be repeated
+        #[arg(short = 'k', long = "ssh-key")]
+        ssh_keys: Vec<String>,
+        /// File to use as user-data instead of the generated one
+        #[arg(long)]
+        user_data: Option<PathBuf>,
+        /// File to use as meta-data instead of the generated one
+        #[arg(long)]
+        meta_data: Option<PathBuf>,
+        /// File to use as network-config
+        #[arg(long)]
+        network_config: Option<PathBuf>,
+        /// Remove all cloud-init settings from the VM
+        #[arg(long, default_value = "false")]
+        clear: bool,
+        /// Name of VM
+        name: String,
+    },
     /// Show and manipulate VM configuration
     #[command(subcommand)]
     Config(ConfigSubcommand),
@@ -154,6 +254,13 @@ pub enum CommandType {
         /// Name of VM
         name: String,
     },
+    /// Show or change how much memory a running VM may use, through its balloon device
+    Balloon {
+        /// Name of VM
+        name: String,
+        /// Memory the VM may use, in megabytes; the balloon takes the rest
+        size: Option<u32>,
+    },
     /// Hard Reset a VM
     Reset {
         /// Name of VM
@@ -192,6 +299,18 @@ pub enum ConfigSubcommand {
     /// Adjust port mappings
     #[command(subcommand)]
     Port(ConfigPortSubcommand),
+    /// Add or remove extra devices
+    #[command(subcommand)]
+    Device(ConfigDeviceSubcommand),
+    /// Adjust settings of individual disks
+    #[command(subcommand)]
+    Disk(ConfigDiskSubcommand),
+    /// Adjust the NUMA nodes of the VM
+    #[command(subcommand)]
+    Numa(ConfigNumaSubcommand),
+    /// Adjust directories shared with the VM
+    #[command(subcommand)]
+    Share(ConfigShareSubcommand),
 }
 
 #[derive(Debug, Subcommand, Clone)]
@@ -238,3 +357,86 @@ pub enum ConfigPortSubcommand {
         hostport: u16,
     },
 }
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum ConfigShareSubcommand {
+    /// Share a host directory with the VM; mount it in the guest with `mount -t virtiofs <TAG>`
+    /// (or `-t 9p -o trans=virtio` where virtiofsd is not installed)
+    Add {
+        /// Name of VM
+        name: String,
+        /// Directory on the host to share
+        host_path: PathBuf,
+        /// Tag the guest mounts the share by
+        tag: String,
+    },
+    /// Stop sharing a host directory
+    Remove {
+        /// Name of VM
+        name: String,
+        /// Directory on the host that is shared
+        host_path: PathBuf,
+    },
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum ConfigDiskSubcommand {
+    /// Set a value for a disk; type-safe
+    Set {
+        /// Name of VM
+        name: String,
+        /// Id of disk, as shown by list-disks
+        disk: u32,
+        /// Name of key to set
+        key: String,
+        /// Value of key to set
+        value: String,
+    },
+    /// Return a disk to the default settings
+    Reset {
+        /// Name of VM
+        name: String,
+        /// Id of disk, as shown by list-disks
+        disk: u32,
+    },
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum ConfigNumaSubcommand {
+    /// Add a NUMA node; the memory of all nodes must add up to the VM's memory
+    Add {
+        /// Bind the node's memory to this host NUMA node
+        #[arg(long)]
+        host_node: Option<u32>,
+        /// Name of VM
+        name: String,
+        /// vCPUs in the node, e.g. 0-3,8
+        cpus: String,
+        /// Memory of the node, in megabytes
+        memory: u32,
+    },
+    /// Remove all NUMA nodes
+    Clear {
+        /// Name of VM
+        name: String,
+    },
+}
+
+#[derive(Debug, Subcommand, Clone)]
+pub enum ConfigDeviceSubcommand {
+    /// Add a device: virtio-rng, watchdog[,action=<action>], usb-tablet, usb-keyboard,
+    /// audio[,backend=<backend>] or virtio-serial,name=<port name>
+    Add {
+        /// Name of VM
+        name: String,
+        /// Device to add
+        device: Device,
+    },
+    /// Remove a device, written the same way as it was added
+    Remove {
+        /// Name of VM
+        name: String,
+        /// Device to remove
+        device: Device,
+    },
+}
@@ -1,22 +1,42 @@
 use super::{
-    config_storage::XDGConfigStorage,
-    image::{QEmuImageHandler, QEMU_IMG_DEFAULT_FORMAT},
+    accel,
+    capabilities::Capabilities,
+    cloud_hypervisor::CloudHypervisorLauncher,
+    cloud_init::CloudInit,
+    config::{parse_cpu_list, Backend, DisplayBackend},
+    config_storage::{disk_id, XDGConfigStorage},
+    console::{self, RawTerminal},
+    devices::Device,
+    firecracker::FirecrackerLauncher,
+    hooks::{HookEvent, Hooks},
+    image::QEmuImageHandler,
     launcher::QEmuLauncher,
+    logs,
     supervisor::SystemdSupervisor,
-    traits::{ConfigStorageHandler, ImageHandler, Launcher, SupervisorHandler},
+    traits::{
+        ConfigStorageHandler, ImageHandler, Launcher, SupervisorHandler, DEFAULT_SNAPSHOT_TAG,
+    },
     vm::VM,
+    wait::{self, Readiness},
+    web_console::{VncTarget, WebConsole},
+};
+use crate::{
+    qmp::client::Client,
+    util::{parse_since, path_exists, pid_running, shell_quote, valid_filename},
 };
-use crate::{qmp::client::Client, util::valid_filename};
 use anyhow::{anyhow, Result};
-use std::{path::PathBuf, process::Command, sync::Arc};
+use std::{
+    os::unix::fs::DirBuilderExt, path::PathBuf, process::Command, sync::Arc, time::Duration,
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, Interest},
     sync::Mutex,
 };
 
+const DISPLAY_VIEWER: &str = "remote-viewer";
+
 #[derive(Debug, Clone)]
 pub struct CommandHandler {
-    launcher: Arc<Box<dyn Launcher>>,
     config: Arc<Box<dyn ConfigStorageHandler>>,
     image: Arc<Box<dyn ImageHandler>>,
 }
@@ -24,7 +44,6 @@ pub struct CommandHandler {
 impl Default for CommandHandler {
     fn default() -> Self {
         Self {
-            launcher: Arc::new(Box::new(QEmuLauncher::default())),
             config: Arc::new(Box::new(XDGConfigStorage::default())),
             image: Arc::new(Box::new(QEmuImageHandler::default())),
         }
@@ -32,36 +51,108 @@ impl Default for CommandHandler {
 }
 
 impl CommandHandler {
+    fn launcher(&self, vm: &VM) -> Box<dyn Launcher> {
+        match vm.config().machine.backend {
+            Backend::Qemu => Box::new(QEmuLauncher::default()),
+            Backend::CloudHypervisor => Box::new(CloudHypervisorLauncher::default()),
+            Backend::Firecracker => Box::new(FirecrackerLauncher::default()),
+        }
+    }
+
     pub fn reset(&self, vm: &VM) -> Result<()> {
-        self.launcher.reset(vm)
+        self.launcher(vm).reset(vm)
     }
 
+    // the stop and start hooks run as they would for shutdown and run; the post- hooks are run
+    // by the launcher.
     pub fn restart(&self, vm: &VM) -> Result<()> {
-        self.launcher.restart(vm)
+        Hooks::new(self.config.clone()).run(vm, HookEvent::PreStop, &[])?;
+        self.launcher(vm).shutdown_wait(vm)?;
+        self.run(vm, true, false)
+    }
+
+    pub fn balloon(&self, vm: &VM, size: Option<u32>) -> Result<()> {
+        let config = vm.config();
+        if !config.machine.balloon {
+            return Err(anyhow!(
+                "{} has no balloon device; set balloon to true and restart it",
+                vm
+            ));
+        }
+
+        if let Some(size) = size {
+            if size == 0 || size > config.machine.memory {
+                return Err(anyhow!(
+                    "size must be between 1 and {} megabytes",
+                    config.machine.memory
+                ));
+            }
+
+            // the guest gives memory back gradually; the new size shows up over time.
+            self.launcher(vm).balloon(vm, size)?;
+            println!("Asked {} to shrink to {}M", vm, size);
+        } else {
+            println!(
+                "{}: {}M of {}M",
+                vm,
+                self.launcher(vm).balloon_size(vm)?,
+                config.machine.memory
+            );
+        }
+
+        Ok(())
+    }
+
+    // runs the snapshot hooks around a snapshot operation; `action` is save, load or delete.
+    fn with_snapshot_hooks(
+        &self,
+        vm: &VM,
+        snapshot: &str,
+        action: &str,
+        f: impl FnOnce() -> Result<()>,
+    ) -> Result<()> {
+        let hooks = Hooks::new(self.config.clone());
+        let env = [("EMU_SNAPSHOT", snapshot), ("EMU_SNAPSHOT_ACTION", action)];
+        hooks.run(vm, HookEvent::PreSnapshot, &env)?;
+        f()?;
+        hooks.notify(vm, HookEvent::PostSnapshot, &env);
+        Ok(())
     }
 
     pub fn snapshot_save(&self, vm: &VM, snapshot: String) -> Result<()> {
-        self.launcher.snapshot(vm, snapshot)
+        self.with_snapshot_hooks(vm, &snapshot, "save", || {
+            self.launcher(vm).snapshot(vm, snapshot.clone())
+        })
     }
 
     pub fn snapshot_load(&self, vm: &VM, snapshot: String) -> Result<()> {
-        self.launcher.restore(vm, snapshot)
+        self.with_snapshot_hooks(vm, &snapshot, "load", || {
+            self.launcher(vm).restore(vm, snapshot.clone())
+        })
     }
 
     pub fn snapshot_delete(&self, vm: &VM, snapshot: String) -> Result<()> {
-        self.launcher.delete_snapshot(vm, snapshot)
+        self.with_snapshot_hooks(vm, &snapshot, "delete", || {
+            self.launcher(vm).delete_snapshot(vm, snapshot.clone())
+        })
     }
 
     pub fn save_state(&self, vm: &VM) -> Result<()> {
-        self.launcher.save_state(vm)
+        self.with_snapshot_hooks(vm, DEFAULT_SNAPSHOT_TAG, "save", || {
+            self.launcher(vm).save_state(vm)
+        })
     }
 
     pub fn load_state(&self, vm: &VM) -> Result<()> {
-        self.launcher.load_state(vm)
+        self.with_snapshot_hooks(vm, DEFAULT_SNAPSHOT_TAG, "load", || {
+            self.launcher(vm).load_state(vm)
+        })
     }
 
     pub fn clear_state(&self, vm: &VM) -> Result<()> {
-        self.launcher.clear_state(vm)
+        self.with_snapshot_hooks(vm, DEFAULT_SNAPSHOT_TAG, "delete", || {
+            self.launcher(vm).clear_state(vm)
+        })
     }
 
     pub fn list(&self, running: bool) -> Result<()> {
@@ -82,11 +173,17 @@ impl CommandHandler {
         .for_each(|vm| {
             let supervisor = vm.supervisor();
 
+            let accel = supervisor
+                .pidof(vm)
+                .ok()
+                .and_then(accel::of_pid)
+                .map_or_else(String::new, |accel| format!(", accel: {}", accel));
+
             let (status, is_running) = if supervisor.supervised() {
                 match supervisor.is_active(vm) {
                     Ok(res) => {
                         if res {
-                            ("supervised: running".to_string(), true)
+                            (format!("supervised: running{}", accel), true)
                         } else {
                             ("supervised: not running".to_string(), false)
                         }
@@ -97,7 +194,10 @@ impl CommandHandler {
                     ),
                 }
             } else if supervisor.is_active(vm).unwrap_or_default() {
-                (format!("pid: {}", supervisor.pidof(vm).unwrap()), true)
+                (
+                    format!("pid: {}{}", supervisor.pidof(vm).unwrap(), accel),
+                    true,
+                )
             } else {
                 ("stopped".to_string(), false)
             };
@@ -218,6 +318,117 @@ impl CommandHandler {
         Ok(())
     }
 
+    pub async fn console(&self, vm: &VM) -> Result<()> {
+        let path = self.config.serial_path(vm);
+        if !path_exists(path.clone()) {
+            return Err(anyhow!("{} is not running or has no serial console", vm));
+        }
+
+        println!(
+            "Connected to {}; press {} to detach",
+            vm,
+            console::ESCAPE_DESCRIPTION
+        );
+
+        let term = RawTerminal::enable()?;
+        let res = console::attach(&path, console::ESCAPE_CHAR).await;
+        term.restore()?;
+        println!();
+        res
+    }
+
+    pub fn logs(&self, vm: &VM, follow: bool, since: Option<String>) -> Result<()> {
+        if !self.config.vm_exists(vm) {
+            return Err(anyhow!("vm doesn't exist"));
+        }
+
+        let since = match since {
+            Some(since) => Some(parse_since(&since)?),
+            None => None,
+        };
+
+        // qemu's own output is interleaved with the serial console's.
+        let path = self.config.serial_log_path(vm);
+        let qemu_path = self.config.qemu_log_path(vm);
+        for line in logs::merge_lines(
+            logs::read_lines(&path, since)?,
+            logs::read_lines(&qemu_path, since)?,
+        ) {
+            println!("{}", line);
+        }
+
+        if follow {
+            if !path_exists(path.clone()) {
+                return Err(anyhow!("{} has no serial log yet", vm));
+            }
+
+            let mut paths = vec![path];
+            if path_exists(qemu_path.clone()) {
+                paths.push(qemu_path);
+            }
+
+            logs::follow(&paths)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn display(&self, vm: &VM) -> Result<()> {
+        let config = vm.config();
+        let path = self.config.display_path(vm);
+        let uri = match (config.machine.display, config.machine.display_port) {
+            (DisplayBackend::Vnc, Some(port)) => format!("vnc://127.0.0.1:{}", port),
+            (DisplayBackend::Vnc, None) => format!("vnc+unix://{}", path.display()),
+            (DisplayBackend::Spice, Some(port)) => format!("spice://127.0.0.1:{}", port),
+            (DisplayBackend::Spice, None) => format!("spice+unix://{}", path.display()),
+            (display, _) => {
+                return Err(anyhow!(
+                    "{} uses the {} display; set display to vnc or spice to view it remotely",
+                    vm,
+                    display
+                ))
+            }
+        };
+
+        if config.machine.display_port.is_none() && !path_exists(path) {
+            return Err(anyhow!("{} is not running", vm));
+        }
+
+        match Command::new(DISPLAY_VIEWER).arg(uri).spawn() {
+            Ok(mut child) => {
+                child.wait()?;
+                Ok(())
+            }
+            Err(e) => Err(anyhow!("could not run {}: {}", DISPLAY_VIEWER, e)),
+        }
+    }
+
+    pub async fn web_console(&self, vm: &VM, port: u16) -> Result<()> {
+        let config = vm.config();
+        if config.machine.display != DisplayBackend::Vnc {
+            return Err(anyhow!(
+                "{} uses the {} display; set display to vnc to use the web console",
+                vm,
+                config.machine.display
+            ));
+        }
+
+        let target = match config.machine.display_port {
+            Some(port) => VncTarget::Tcp(format!("127.0.0.1:{}", port).parse()?),
+            None => VncTarget::Unix(self.config.display_path(vm)),
+        };
+
+        let listener = tokio::net::TcpListener::bind(("127.0.0.1", port)).await?;
+        println!(
+            "Serving the console of {} on http://127.0.0.1:{}/; press Ctrl-C to stop",
+            vm, port
+        );
+
+        WebConsole::new(vm.name(), target, config.machine.display_password)
+            .serve(listener)
+            .await
+    }
+
     pub fn ssh(&self, vm: &VM, args: Option<Vec<String>>) -> Result<()> {
         let mut cmd = Command::new("ssh");
         let port = vm.config().machine.ssh_port.to_string();
@@ -256,22 +467,23 @@ impl CommandHandler {
         }
 
         for disk in self.config.disk_list(vm)? {
-            let disk = disk
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .trim_start_matches("qemu-")
-                .trim_end_matches(QEMU_IMG_DEFAULT_FORMAT)
-                .trim_end_matches(".");
-            println!("{}", disk);
+            println!("{}", disk_id(&disk));
         }
 
         Ok(())
     }
 
     pub fn delete(&self, vm: &VM, disk: Option<String>) -> Result<()> {
-        self.config.delete(vm, disk)?;
+        self.config.delete(vm, disk.clone())?;
+
+        // settings of a deleted disk must not carry over to the next disk created with its id.
+        if let Some(id) = disk.and_then(|disk| disk.parse::<u32>().ok()) {
+            let mut vm = vm.clone();
+            let mut config = vm.config();
+            config.remove_disk(id);
+            vm.set_config(config);
+            self.config.write_config(vm)?;
+        }
 
         if vm.supervisor().supervised() {
             if let Err(_) = self.unsupervise(vm) {
@@ -310,12 +522,16 @@ impl CommandHandler {
     }
 
     pub fn shutdown(&self, vm: &VM, nowait: bool) -> Result<()> {
+        // post-stop hooks are run by whoever launched qemu, once it exits.
+        Hooks::new(self.config.clone()).run(vm, HookEvent::PreStop, &[])?;
+
         if nowait {
-            self.launcher.shutdown_immediately(vm)
+            self.launcher(vm).shutdown_immediately(vm)
         } else {
-            if let Ok(status) = self.launcher.shutdown_wait(vm) {
+            if let Ok(status) = self.launcher(vm).shutdown_wait(vm) {
                 println!(
-                    "qemu exited with {} status",
+                    "{} exited with {} status",
+                    vm.config().machine.backend,
                     status.code().unwrap_or_default()
                 );
             }
@@ -324,27 +540,159 @@ impl CommandHandler {
         }
     }
 
-    pub fn run(&self, vm: &VM, detach: bool) -> Result<()> {
+    /// Where the overlays of an ephemeral run of `vm` by this process go.
+    pub fn overlays_path(&self, vm: &VM) -> PathBuf {
+        std::env::temp_dir().join(format!("emu-{}-{}", vm.name(), std::process::id()))
+    }
+
+    // an attached run killed along with its VM (by ctrl-c, say) never removes its overlays. The
+    // pid in their name says whether that run is over; a detached run outlives the pid it named
+    // them after, so nothing is removed while the VM is up.
+    fn remove_stale_overlays(&self, vm: &VM) {
+        if vm.supervisor().is_active(vm).unwrap_or(true) {
+            return;
+        }
+
+        let prefix = format!("emu-{}-", vm.name());
+        if let Ok(dir) = std::fs::read_dir(std::env::temp_dir()) {
+            for item in dir.flatten() {
+                let name = item.file_name().to_string_lossy().to_string();
+                if let Some(pid) = name.strip_prefix(&prefix) {
+                    if !pid.is_empty()
+                        && pid.chars().all(|c| c.is_ascii_digit())
+                        && pid.parse::<u32>().is_ok_and(|pid| !pid_running(pid))
+                    {
+                        std::fs::remove_dir_all(item.path()).unwrap_or_default();
+                    }
+                }
+            }
+        }
+    }
+
+    // overlays every disk, and copies the firmware variables and TPM state, which the guest
+    // would otherwise write to.
+    fn create_overlays(&self, vm: &VM) -> Result<PathBuf> {
+        if vm.config().machine.backend == Backend::Firecracker {
+            return Err(anyhow!(
+                "ephemeral runs need qcow2 overlays, which firecracker cannot boot"
+            ));
+        }
+
+        let dir = self.overlays_path(vm);
+        std::fs::DirBuilder::new().mode(0o700).create(&dir)?;
+
+        let res = (|| -> Result<()> {
+            for disk in self.config.disk_list(vm)? {
+                if let Some(name) = disk.file_name() {
+                    self.image.create_overlay(disk.clone(), dir.join(name))?;
+                }
+            }
+
+            let nvram = self.config.nvram_path(vm);
+            if path_exists(nvram.clone()) {
+                std::fs::copy(nvram, dir.join("nvram.fd"))?;
+            }
+
+            let tpm = self.config.tpm_path(vm);
+            if path_exists(tpm.clone()) {
+                std::fs::create_dir(dir.join("tpm"))?;
+                for item in std::fs::read_dir(tpm)? {
+                    let item = item?;
+                    std::fs::copy(item.path(), dir.join("tpm").join(item.file_name()))?;
+                }
+            }
+
+            Ok(())
+        })();
+
+        match res {
+            Ok(_) => Ok(dir),
+            Err(e) => {
+                std::fs::remove_dir_all(dir).unwrap_or_default();
+                Err(e)
+            }
+        }
+    }
+
+    pub fn run(&self, vm: &VM, detach: bool, ephemeral: bool) -> Result<()> {
+        vm.config().valid()?;
+
         for running in self.config.running_vms()? {
             if running.config().is_port_conflict(&vm.config()) {
                 return Err(anyhow!("{} will fail to launch because {} already occupies a network port it would use", vm, running));
             }
         }
 
-        if detach {
-            self.launcher.launch_detached(vm)
+        Hooks::new(self.config.clone()).run(vm, HookEvent::PreStart, &[])?;
+
+        self.remove_stale_overlays(vm);
+
+        let mut vm = vm.clone();
+        if ephemeral {
+            vm.set_overlays(self.create_overlays(&vm)?);
+        }
+
+        let res = if detach {
+            self.launcher(&vm).launch_detached(&vm)
         } else {
-            match self.launcher.launch_attached(vm) {
+            match self.launcher(&vm).launch_attached(&vm) {
                 Ok(status) => {
                     if status.success() {
                         Ok(())
                     } else {
-                        Err(anyhow!("qemu exited uncleanly: {}", status))
+                        Err(anyhow!(
+                            "{} exited uncleanly: {}{}",
+                            vm.config().machine.backend,
+                            status,
+                            logs::exit_report(&self.config.qemu_log_path(&vm))
+                        ))
                     }
                 }
                 Err(e) => Err(e),
             }
+        };
+
+        // a detached VM that is still up removes its overlays when it exits.
+        if let Some(overlays) = vm.overlays() {
+            if !detach || res.is_err() {
+                std::fs::remove_dir_all(overlays).unwrap_or_default();
+            }
         }
+
+        res
+    }
+
+    pub fn wait(&self, vm: &VM, readiness: Readiness, timeout: Duration) -> Result<()> {
+        if !self.config.vm_exists(vm) {
+            return Err(anyhow!("vm doesn't exist"));
+        }
+
+        wait::wait(&self.config, vm, &readiness, timeout)?;
+        println!("{} is ready ({})", vm, readiness);
+        Ok(())
+    }
+
+    pub fn args(&self, vm: &VM, json: bool) -> Result<()> {
+        if !self.config.vm_exists(vm) {
+            return Err(anyhow!("vm doesn't exist"));
+        }
+
+        vm.config().valid()?;
+        let argv = self.launcher(vm).command_line(vm)?;
+
+        if json {
+            println!("{}", serde_json::to_string_pretty(&argv)?);
+        } else {
+            println!(
+                "{}",
+                argv.iter()
+                    .map(|arg| shell_quote(arg))
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            );
+        }
+
+        Ok(())
     }
 
     pub fn import(&self, vm: &VM, from_file: PathBuf, format: String) -> Result<()> {
@@ -401,11 +749,35 @@ impl CommandHandler {
             }
         }
 
+        // the EFI variable store and TPM state belong to the VM's installation, not its
+        // configuration, so they always follow the disks.
+        if self.config.nvram_path(from).exists() {
+            std::fs::copy(self.config.nvram_path(from), self.config.nvram_path(to))?;
+        }
+
+        if self.config.tpm_path(from).exists() {
+            std::fs::create_dir_all(self.config.tpm_path(to))?;
+            for item in std::fs::read_dir(self.config.tpm_path(from))? {
+                let item = item?;
+                // skip the swtpm socket; only the state files matter.
+                if item.metadata()?.is_file() {
+                    std::fs::copy(item.path(), self.config.tpm_path(to).join(item.file_name()))?;
+                }
+            }
+        }
+
         if config && self.config.config_path(from).exists() {
             println!("Configuration found in {}; copying to {}", from, to);
             std::fs::copy(self.config.config_path(from), self.config.config_path(to))?;
         }
 
+        if config && self.config.cloud_init_path(from).exists() {
+            std::fs::copy(
+                self.config.cloud_init_path(from),
+                self.config.cloud_init_path(to),
+            )?;
+        }
+
         Ok(())
     }
 
@@ -427,13 +799,30 @@ impl CommandHandler {
             return Ok(());
         }
         println!("{}", vm.config().to_string());
-        Ok(())
+        vm.config().valid()
     }
 
     pub fn config_set(&self, vm: &VM, key: String, value: String) -> Result<()> {
         let mut vm = vm.clone();
         let mut config = vm.config();
         config.set_machine_value(&key, &value)?;
+
+        // catch what the installed qemu would reject at boot, if there is one to ask.
+        let caps = match config.machine.backend {
+            Backend::Qemu => Capabilities::load().ok(),
+            _ => None,
+        };
+        if let Some(caps) = caps {
+            match key.as_str() {
+                "cpu-type" | "cpu_type" => caps.valid_cpu_type(&config.machine.cpu_type)?,
+                "vga" => caps.valid_vga(&config.machine.vga)?,
+                "image-interface" | "image_interface" => {
+                    caps.valid_image_interface(&config.machine.image_interface)?
+                }
+                _ => {}
+            }
+        }
+
         vm.set_config(config);
         match self.config.write_config(vm.clone()) {
             Ok(_) => {}
@@ -445,6 +834,141 @@ impl CommandHandler {
         Ok(())
     }
 
+    pub fn device_add(&self, vm: &VM, device: Device) -> Result<()> {
+        if !self.config.vm_exists(vm) {
+            return Err(anyhow!("vm doesn't exist"));
+        }
+
+        let mut vm = vm.clone();
+        let mut config = vm.config();
+        config.add_device(device);
+        config.valid()?;
+        vm.set_config(config);
+        self.config.write_config(vm)
+    }
+
+    pub fn device_remove(&self, vm: &VM, device: Device) -> Result<()> {
+        let mut vm = vm.clone();
+        let mut config = vm.config();
+        if !config.remove_device(&device) {
+            return Err(anyhow!("{} has no device {}", vm, device));
+        }
+        vm.set_config(config);
+        self.config.write_config(vm)
+    }
+
+    // nodes are added one at a time, so the memory of all nodes is only checked at launch.
+    pub fn numa_add(
+        &self,
+        vm: &VM,
+        cpus: String,
+        memory: u32,
+        host_node: Option<u32>,
+    ) -> Result<()> {
+        if !self.config.vm_exists(vm) {
+            return Err(anyhow!("vm doesn't exist"));
+        }
+
+        parse_cpu_list(&cpus)?;
+        let mut vm = vm.clone();
+        let mut config = vm.config();
+        config.add_numa_node(cpus, memory, host_node);
+        vm.set_config(config);
+        self.config.write_config(vm)
+    }
+
+    pub fn numa_clear(&self, vm: &VM) -> Result<()> {
+        let mut vm = vm.clone();
+        let mut config = vm.config();
+        config.clear_numa_nodes();
+        vm.set_config(config);
+        self.config.write_config(vm)
+    }
+
+    pub fn disk_set(&self, vm: &VM, disk: u32, key: String, value: String) -> Result<()> {
+        if !self.config.vm_exists(vm) {
+            return Err(anyhow!("vm doesn't exist"));
+        }
+
+        let mut vm = vm.clone();
+        let mut config = vm.config();
+        config.set_disk_value(disk, &key, &value)?;
+        vm.set_config(config);
+        self.config.write_config(vm)
+    }
+
+    pub fn disk_reset(&self, vm: &VM, disk: u32) -> Result<()> {
+        let mut vm = vm.clone();
+        let mut config = vm.config();
+        config.remove_disk(disk);
+        vm.set_config(config);
+        self.config.write_config(vm)
+    }
+
+    pub fn cloud_init_show(&self, vm: &VM) -> Result<()> {
+        if !self.config.vm_exists(vm) {
+            return Err(anyhow!("vm doesn't exist"));
+        }
+
+        let path = self.config.cloud_init_path(vm);
+        if path_exists(path.clone()) {
+            println!("{}", CloudInit::from_file(&path)?);
+        } else {
+            println!("VM {} has no cloud-init configuration", vm);
+        }
+
+        Ok(())
+    }
+
+    // settings given are merged into the existing ones; ssh keys accumulate.
+    pub fn cloud_init_set(&self, vm: &VM, settings: CloudInit) -> Result<()> {
+        if !self.config.vm_exists(vm) {
+            return Err(anyhow!("vm doesn't exist"));
+        }
+
+        let path = self.config.cloud_init_path(vm);
+        let mut ci = if path_exists(path.clone()) {
+            CloudInit::from_file(&path)?
+        } else {
+            CloudInit::default()
+        };
+
+        if settings.hostname.is_some() {
+            ci.hostname = settings.hostname;
+        }
+
+        for key in settings.ssh_authorized_keys {
+            if !ci.ssh_authorized_keys.contains(&key) {
+                ci.ssh_authorized_keys.push(key);
+            }
+        }
+
+        if settings.user_data.is_some() {
+            ci.user_data = settings.user_data;
+        }
+
+        if settings.meta_data.is_some() {
+            ci.meta_data = settings.meta_data;
+        }
+
+        if settings.network_config.is_some() {
+            ci.network_config = settings.network_config;
+        }
+
+        ci.valid()?;
+        ci.to_file(&path)
+    }
+
+    pub fn cloud_init_clear(&self, vm: &VM) -> Result<()> {
+        for path in [self.config.cloud_init_path(vm), self.config.seed_path(vm)] {
+            if path_exists(path.clone()) {
+                std::fs::remove_file(path)?;
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn port_map(&self, vm: &VM, hostport: u16, guestport: u16) -> Result<()> {
         let mut vm = vm.clone();
         let mut config = vm.config();
@@ -461,6 +985,36 @@ impl CommandHandler {
         self.config.write_config(vm)
     }
 
+    pub fn share_add(&self, vm: &VM, host_path: PathBuf, tag: String) -> Result<()> {
+        if !self.config.vm_exists(vm) {
+            return Err(anyhow!("vm doesn't exist"));
+        }
+
+        let host_path = host_path
+            .canonicalize()
+            .map_err(|e| anyhow!("could not locate {}: {}", host_path.display(), e))?;
+        if !host_path.is_dir() {
+            return Err(anyhow!("{} is not a directory", host_path.display()));
+        }
+
+        let mut vm = vm.clone();
+        let mut config = vm.config();
+        config.add_share(host_path, tag);
+        config.valid()?;
+        vm.set_config(config);
+        self.config.write_config(vm)
+    }
+
+    pub fn share_remove(&self, vm: &VM, host_path: PathBuf) -> Result<()> {
+        // the directory may already be gone from the host.
+        let host_path = host_path.canonicalize().unwrap_or(host_path);
+        let mut vm = vm.clone();
+        let mut config = vm.config();
+        config.remove_share(host_path);
+        vm.set_config(config);
+        self.config.write_config(vm)
+    }
+
     pub fn qmp(&self, vm: &VM, command: &str, args: Option<&str>) -> Result<()> {
         let mut us = Client::new(self.config.monitor_path(vm))?;
         us.handshake()?;
@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, io::Write, path::PathBuf};
+use std::{collections::HashMap, io::Write, path::PathBuf, str::FromStr};
 
+use crate::{devices::Device, shares::valid_tag, template::valid_extra_arg, util::shell_split};
 use anyhow::{anyhow, Result};
 
 const DEFAULT_CPU_TYPE: &str = "host";
@@ -9,13 +10,27 @@ const DEFAULT_MEMORY: u32 = 16384;
 const DEFAULT_VGA: &str = "virtio";
 const DEFAULT_SSH_PORT: u16 = 2222;
 const DEFAULT_IMAGE_INTERFACE: &str = "virtio";
+pub const VNC_BASE_PORT: u16 = 5900;
+// qemu's limit for splash-time.
+const MAX_BOOT_MENU_TIMEOUT: u32 = 0xffff;
 
 pub type PortMap = HashMap<String, u16>;
+pub type ShareMap = HashMap<String, String>;
+pub type DiskMap = HashMap<String, DiskConfiguration>;
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Configuration {
     pub machine: MachineConfiguration,
     pub ports: PortMap,
+    #[serde(default)]
+    pub shares: ShareMap,
+    #[serde(default)]
+    pub disks: DiskMap,
+    #[serde(default)]
+    pub devices: Vec<Device>,
+    /// Appended to the qemu command line as-is, after rendering; see `template::extra_args`.
+    #[serde(default)]
+    pub extra_args: Vec<String>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -24,8 +39,466 @@ pub struct MachineConfiguration {
     pub memory: u32, // megabytes
     pub cpus: u32,
     pub cpu_type: String,
+    #[serde(default)]
+    pub sockets: Option<u32>,
+    #[serde(default)]
+    pub cores: Option<u32>,
+    #[serde(default)]
+    pub threads: Option<u32>,
+    #[serde(default)]
+    pub maxcpus: Option<u32>,
+    #[serde(default)]
+    pub cpu_flags: Vec<String>,
     pub vga: String,
     pub image_interface: String,
+    #[serde(default)]
+    pub require_kvm: bool,
+    #[serde(default)]
+    pub firmware: Firmware,
+    #[serde(default)]
+    pub ovmf_code: Option<PathBuf>,
+    #[serde(default)]
+    pub ovmf_vars: Option<PathBuf>,
+    #[serde(default)]
+    pub tpm: bool,
+    #[serde(default)]
+    pub kernel: Option<PathBuf>,
+    #[serde(default)]
+    pub initrd: Option<PathBuf>,
+    #[serde(default)]
+    pub append: Option<String>,
+    #[serde(default)]
+    pub display: DisplayBackend,
+    #[serde(default)]
+    pub display_port: Option<u16>,
+    #[serde(default)]
+    pub display_password: Option<String>,
+    #[serde(default)]
+    pub memory_backend: MemoryBackend,
+    #[serde(default)]
+    pub memory_shared: bool,
+    #[serde(default)]
+    pub memory_prealloc: bool,
+    #[serde(default)]
+    pub cpu_pinning: Vec<u32>,
+    #[serde(default)]
+    pub balloon: bool,
+    #[serde(default)]
+    pub free_page_reporting: bool,
+    #[serde(default)]
+    pub backend: Backend,
+    #[serde(default)]
+    pub boot_order: Vec<BootDevice>,
+    #[serde(default)]
+    pub boot_menu: bool,
+    #[serde(default)]
+    pub boot_menu_timeout: Option<u32>, // milliseconds
+    #[serde(default)]
+    pub numa: Vec<NumaNode>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Firmware {
+    #[default]
+    Bios,
+    Uefi,
+}
+
+impl std::fmt::Display for Firmware {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Bios => "bios",
+            Self::Uefi => "uefi",
+        })
+    }
+}
+
+impl FromStr for Firmware {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bios" => Ok(Self::Bios),
+            "uefi" => Ok(Self::Uefi),
+            _ => Err(anyhow!("firmware must be one of: bios, uefi")),
+        }
+    }
+}
+
+/// The hypervisor a VM runs under.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    #[default]
+    Qemu,
+    CloudHypervisor,
+    Firecracker,
+}
+
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Qemu => "qemu",
+            Self::CloudHypervisor => "cloud-hypervisor",
+            Self::Firecracker => "firecracker",
+        })
+    }
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "qemu" => Ok(Self::Qemu),
+            "cloud-hypervisor" => Ok(Self::CloudHypervisor),
+            "firecracker" => Ok(Self::Firecracker),
+            _ => Err(anyhow!(
+                "backend must be one of: qemu, cloud-hypervisor, firecracker"
+            )),
+        }
+    }
+}
+
+/// A kind of device to boot from, as listed in `boot_order`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BootDevice {
+    Disk,
+    Cdrom,
+    Network,
+}
+
+impl std::fmt::Display for BootDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Disk => "disk",
+            Self::Cdrom => "cdrom",
+            Self::Network => "network",
+        })
+    }
+}
+
+impl FromStr for BootDevice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "disk" => Ok(Self::Disk),
+            "cdrom" => Ok(Self::Cdrom),
+            "network" => Ok(Self::Network),
+            _ => Err(anyhow!("boot device must be one of: disk, cdrom, network")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DisplayBackend {
+    #[default]
+    Gtk,
+    Vnc,
+    Spice,
+    None,
+}
+
+impl DisplayBackend {
+    /// Remote displays are served by qemu and are reachable whether the VM is headless or not.
+    pub fn is_remote(&self) -> bool {
+        matches!(self, Self::Vnc | Self::Spice)
+    }
+}
+
+impl std::fmt::Display for DisplayBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Gtk => "gtk",
+            Self::Vnc => "vnc",
+            Self::Spice => "spice",
+            Self::None => "none",
+        })
+    }
+}
+
+impl FromStr for DisplayBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "gtk" => Ok(Self::Gtk),
+            "vnc" => Ok(Self::Vnc),
+            "spice" => Ok(Self::Spice),
+            "none" => Ok(Self::None),
+            _ => Err(anyhow!("display must be one of: gtk, vnc, spice, none")),
+        }
+    }
+}
+
+/// Settings for a single disk, keyed by its id in `[disks]`. Disks without an interface or boot
+/// index are attached the old way, with the machine's `image_interface`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiskConfiguration {
+    #[serde(default)]
+    pub interface: Option<DiskInterface>,
+    #[serde(default)]
+    pub cache: CacheMode,
+    #[serde(default)]
+    pub aio: Option<Aio>,
+    #[serde(default)]
+    pub discard: bool,
+    #[serde(default)]
+    pub detect_zeroes: bool,
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub bootindex: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiskInterface {
+    VirtioBlk,
+    VirtioScsi,
+    Nvme,
+    Ide,
+}
+
+impl DiskInterface {
+    /// The interface closest to an `image_interface` value, for disks that need a device of
+    /// their own (to carry a boot index) but don't name an interface.
+    pub fn from_image_interface(s: &str) -> Option<Self> {
+        match s {
+            "virtio" => Some(Self::VirtioBlk),
+            "ide" => Some(Self::Ide),
+            _ => s.parse().ok(),
+        }
+    }
+}
+
+impl std::fmt::Display for DiskInterface {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::VirtioBlk => "virtio-blk",
+            Self::VirtioScsi => "virtio-scsi",
+            Self::Nvme => "nvme",
+            Self::Ide => "ide",
+        })
+    }
+}
+
+impl FromStr for DiskInterface {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "virtio-blk" => Ok(Self::VirtioBlk),
+            "virtio-scsi" => Ok(Self::VirtioScsi),
+            "nvme" => Ok(Self::Nvme),
+            "ide" => Ok(Self::Ide),
+            _ => Err(anyhow!(
+                "interface must be one of: virtio-blk, virtio-scsi, nvme, ide"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    #[default]
+    None,
+    Writeback,
+    Writethrough,
+    Directsync,
+    Unsafe,
+}
+
+impl CacheMode {
+    /// Modes that bypass the host page cache, which native aio requires.
+    pub fn is_direct(&self) -> bool {
+        matches!(self, Self::None | Self::Directsync)
+    }
+}
+
+impl std::fmt::Display for CacheMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Writeback => "writeback",
+            Self::Writethrough => "writethrough",
+            Self::Directsync => "directsync",
+            Self::Unsafe => "unsafe",
+        })
+    }
+}
+
+impl FromStr for CacheMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "writeback" => Ok(Self::Writeback),
+            "writethrough" => Ok(Self::Writethrough),
+            "directsync" => Ok(Self::Directsync),
+            "unsafe" => Ok(Self::Unsafe),
+            _ => Err(anyhow!(
+                "cache must be one of: none, writeback, writethrough, directsync, unsafe"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Aio {
+    Threads,
+    Native,
+    IoUring,
+}
+
+impl std::fmt::Display for Aio {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Threads => "threads",
+            Self::Native => "native",
+            Self::IoUring => "io_uring",
+        })
+    }
+}
+
+impl FromStr for Aio {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "threads" => Ok(Self::Threads),
+            "native" => Ok(Self::Native),
+            "io_uring" => Ok(Self::IoUring),
+            _ => Err(anyhow!("aio must be one of: threads, native, io_uring")),
+        }
+    }
+}
+
+impl DiskConfiguration {
+    pub fn valid(&self) -> Result<()> {
+        if self.aio == Some(Aio::Native) && !self.cache.is_direct() {
+            return Err(anyhow!(
+                "native aio requires a cache mode of none or directsync"
+            ));
+        }
+
+        Ok(())
+    }
+
+    pub fn set_value(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "interface" => {
+                self.interface = match optional(value) {
+                    Some(interface) => Some(interface.parse::<DiskInterface>()?),
+                    None => None,
+                };
+                Ok(())
+            }
+            "cache" => {
+                self.cache = value.parse::<CacheMode>()?;
+                Ok(())
+            }
+            "aio" => {
+                self.aio = match optional(value) {
+                    Some(aio) => Some(aio.parse::<Aio>()?),
+                    None => None,
+                };
+                Ok(())
+            }
+            "discard" => {
+                self.discard = value.parse::<bool>()?;
+                Ok(())
+            }
+            "detect-zeroes" | "detect_zeroes" => {
+                self.detect_zeroes = value.parse::<bool>()?;
+                Ok(())
+            }
+            "read-only" | "read_only" => {
+                self.read_only = value.parse::<bool>()?;
+                Ok(())
+            }
+            "bootindex" => {
+                self.bootindex = match optional(value) {
+                    Some(index) => Some(index.parse::<u32>()?),
+                    None => None,
+                };
+                Ok(())
+            }
+            _ => Err(anyhow!("key does not exist")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MemoryBackend {
+    #[default]
+    Ram,
+    Memfd,
+    Hugepages,
+}
+
+impl std::fmt::Display for MemoryBackend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Ram => "ram",
+            Self::Memfd => "memfd",
+            Self::Hugepages => "hugepages",
+        })
+    }
+}
+
+impl FromStr for MemoryBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "ram" => Ok(Self::Ram),
+            "memfd" => Ok(Self::Memfd),
+            "hugepages" => Ok(Self::Hugepages),
+            _ => Err(anyhow!(
+                "memory backend must be one of: ram, memfd, hugepages"
+            )),
+        }
+    }
+}
+
+/// A guest NUMA node: the vCPUs in it (e.g. `0-3,8`), its share of the VM's memory, and
+/// optionally the host node its memory is bound to.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NumaNode {
+    pub cpus: String,
+    pub memory: u32, // megabytes
+    #[serde(default)]
+    pub host_node: Option<u32>,
+}
+
+/// Parses a list of CPU ranges like `0-3,8` into inclusive ranges.
+pub fn parse_cpu_list(s: &str) -> Result<Vec<(u32, u32)>> {
+    let mut v = Vec::new();
+    for part in s.split(',') {
+        let range = match part.split_once('-') {
+            Some((start, end)) => (start.trim().parse::<u32>()?, end.trim().parse::<u32>()?),
+            None => {
+                let cpu = part.trim().parse::<u32>()?;
+                (cpu, cpu)
+            }
+        };
+
+        if range.0 > range.1 {
+            return Err(anyhow!("invalid cpu range '{}'", part));
+        }
+        v.push(range);
+    }
+
+    Ok(v)
 }
 
 impl std::fmt::Display for Configuration {
@@ -34,22 +507,116 @@ impl std::fmt::Display for Configuration {
     }
 }
 
-impl Default for Configuration {
+impl Default for MachineConfiguration {
     fn default() -> Self {
-        Configuration {
-            machine: MachineConfiguration {
-                ssh_port: DEFAULT_SSH_PORT,
-                memory: DEFAULT_MEMORY,
-                cpus: DEFAULT_CPUS,
-                cpu_type: DEFAULT_CPU_TYPE.to_string(),
-                vga: DEFAULT_VGA.to_string(),
-                image_interface: DEFAULT_IMAGE_INTERFACE.to_string(),
-            },
-            ports: HashMap::new(),
+        MachineConfiguration {
+            ssh_port: DEFAULT_SSH_PORT,
+            memory: DEFAULT_MEMORY,
+            cpus: DEFAULT_CPUS,
+            cpu_type: DEFAULT_CPU_TYPE.to_string(),
+            sockets: None,
+            cores: None,
+            threads: None,
+            maxcpus: None,
+            cpu_flags: Vec::new(),
+            vga: DEFAULT_VGA.to_string(),
+            image_interface: DEFAULT_IMAGE_INTERFACE.to_string(),
+            require_kvm: false,
+            firmware: Firmware::default(),
+            ovmf_code: None,
+            ovmf_vars: None,
+            tpm: false,
+            kernel: None,
+            initrd: None,
+            append: None,
+            display: DisplayBackend::default(),
+            display_port: None,
+            display_password: None,
+            memory_backend: MemoryBackend::default(),
+            memory_shared: false,
+            memory_prealloc: false,
+            cpu_pinning: Vec::new(),
+            balloon: false,
+            free_page_reporting: false,
+            backend: Backend::default(),
+            boot_order: Vec::new(),
+            boot_menu: false,
+            boot_menu_timeout: None,
+            numa: Vec::new(),
         }
     }
 }
 
+impl MachineConfiguration {
+    /// The most CPUs the VM can have; the ones above `cpus` can be hotplugged.
+    pub fn maxcpus(&self) -> u32 {
+        self.maxcpus.unwrap_or(self.cpus)
+    }
+
+    /// Sockets, cores per socket and threads per core. Unless configured, there is one socket
+    /// and one thread per core, and cores make up the rest.
+    pub fn topology(&self) -> (u32, u32, u32) {
+        let sockets = self.sockets.unwrap_or(1);
+        let threads = self.threads.unwrap_or(1);
+        let cores = self
+            .cores
+            .unwrap_or_else(|| self.maxcpus() / (sockets * threads).max(1));
+        (sockets, cores, threads)
+    }
+
+    /// The boot order for a boot that has to start from `first`, as with `emu run --boot-once`.
+    /// Without a configured order, disks and then CD-ROMs follow it.
+    pub fn boot_order_from(&self, first: BootDevice) -> Vec<BootDevice> {
+        let rest = if self.boot_order.is_empty() {
+            vec![BootDevice::Disk, BootDevice::Cdrom]
+        } else {
+            self.boot_order.clone()
+        };
+
+        std::iter::once(first)
+            .chain(rest.into_iter().filter(|device| *device != first))
+            .collect()
+    }
+
+    /// CPU flags as qemu `-cpu` properties: `+vmx` is `vmx=on`, `-hypervisor` is
+    /// `hypervisor=off`.
+    pub fn cpu_properties(&self) -> Vec<String> {
+        self.cpu_flags
+            .iter()
+            .map(|flag| match flag.split_at(1) {
+                ("+", name) => format!("{}=on", name),
+                (_, name) => format!("{}=off", name),
+            })
+            .collect()
+    }
+}
+
+fn valid_cpu_flag(flag: &str) -> Result<()> {
+    let name = flag
+        .strip_prefix('+')
+        .or_else(|| flag.strip_prefix('-'))
+        .ok_or_else(|| anyhow!("cpu flag '{}' must start with + or -", flag))?;
+
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(anyhow!("invalid cpu flag '{}'", flag));
+    }
+
+    Ok(())
+}
+
+// an empty value clears optional settings
+fn optional(value: &str) -> Option<&str> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
 impl Configuration {
     pub fn is_port_conflict(&self, other: &Self) -> bool {
         for key in self.ports.keys() {
@@ -86,6 +653,170 @@ impl Configuration {
             return Err(anyhow!("No cpus value set"));
         }
 
+        let maxcpus = self.machine.maxcpus();
+        if maxcpus < self.machine.cpus {
+            return Err(anyhow!(
+                "maxcpus ({}) must be at least cpus ({})",
+                maxcpus,
+                self.machine.cpus
+            ));
+        }
+
+        if [
+            self.machine.sockets,
+            self.machine.cores,
+            self.machine.threads,
+        ]
+        .contains(&Some(0))
+        {
+            return Err(anyhow!("sockets, cores and threads must be at least 1"));
+        }
+
+        let (sockets, cores, threads) = self.machine.topology();
+        if sockets * cores * threads != maxcpus {
+            return Err(anyhow!(
+                "{} sockets * {} cores * {} threads does not add up to {} cpus",
+                sockets,
+                cores,
+                threads,
+                maxcpus
+            ));
+        }
+
+        for flag in &self.machine.cpu_flags {
+            valid_cpu_flag(flag)?;
+        }
+
+        if self.machine.cpu_pinning.len() > maxcpus as usize {
+            return Err(anyhow!(
+                "{} vCPUs are pinned, but the VM has at most {}",
+                self.machine.cpu_pinning.len(),
+                maxcpus
+            ));
+        }
+
+        if self.machine.free_page_reporting && !self.machine.balloon {
+            return Err(anyhow!("free page reporting requires the balloon device"));
+        }
+
+        if !self.machine.numa.is_empty() {
+            let mut assigned = Vec::new();
+            for node in &self.machine.numa {
+                for (start, end) in parse_cpu_list(&node.cpus)
+                    .map_err(|e| anyhow!("numa node cpus '{}': {}", node.cpus, e))?
+                {
+                    for cpu in start..=end {
+                        if cpu >= maxcpus {
+                            return Err(anyhow!(
+                                "numa node cpu {} is beyond the VM's {} cpus",
+                                cpu,
+                                maxcpus
+                            ));
+                        }
+                        if assigned.contains(&cpu) {
+                            return Err(anyhow!("cpu {} is in more than one numa node", cpu));
+                        }
+                        assigned.push(cpu);
+                    }
+                }
+            }
+
+            let memory: u32 = self.machine.numa.iter().map(|node| node.memory).sum();
+            if memory != self.machine.memory {
+                return Err(anyhow!(
+                    "numa nodes have {}M of memory, but the VM has {}M",
+                    memory,
+                    self.machine.memory
+                ));
+            }
+        }
+
+        if self.machine.kernel.is_none()
+            && (self.machine.initrd.is_some() || self.machine.append.is_some())
+        {
+            return Err(anyhow!("initrd and append require a kernel to be set"));
+        }
+
+        for (x, device) in self.machine.boot_order.iter().enumerate() {
+            if self.machine.boot_order[..x].contains(device) {
+                return Err(anyhow!("boot device {} is listed more than once", device));
+            }
+        }
+
+        if let Some(timeout) = self.machine.boot_menu_timeout {
+            if !self.machine.boot_menu {
+                return Err(anyhow!("boot_menu_timeout requires boot_menu"));
+            }
+
+            if timeout > MAX_BOOT_MENU_TIMEOUT {
+                return Err(anyhow!(
+                    "boot_menu_timeout is at most {} milliseconds",
+                    MAX_BOOT_MENU_TIMEOUT
+                ));
+            }
+        }
+
+        if self.machine.display == DisplayBackend::Vnc
+            && matches!(self.machine.display_port, Some(port) if port < VNC_BASE_PORT)
+        {
+            return Err(anyhow!("VNC display ports start at {}", VNC_BASE_PORT));
+        }
+
+        if self.machine.display_password.is_some() && !self.machine.display.is_remote() {
+            return Err(anyhow!(
+                "display passwords are only supported for vnc and spice displays"
+            ));
+        }
+
+        for (id, disk) in &self.disks {
+            if id.parse::<u32>().is_err() {
+                return Err(anyhow!("disk id '{}' is not a number", id));
+            }
+
+            disk.valid().map_err(|e| anyhow!("disk {}: {}", id, e))?;
+        }
+
+        for arg in &self.extra_args {
+            valid_extra_arg(arg)?;
+        }
+
+        let mut watchdogs = 0;
+        let mut ports = Vec::new();
+        for device in &self.devices {
+            device.valid()?;
+            match device {
+                Device::Watchdog { .. } => watchdogs += 1,
+                Device::VirtioSerial { name } => {
+                    if ports.contains(&name) {
+                        return Err(anyhow!(
+                            "virtio-serial port '{}' is used more than once",
+                            name
+                        ));
+                    }
+                    ports.push(name);
+                }
+                _ => {}
+            }
+        }
+
+        if watchdogs > 1 {
+            return Err(anyhow!("only one watchdog is supported"));
+        }
+
+        let mut tags = Vec::new();
+        for (host, tag) in &self.shares {
+            if !PathBuf::from(host).is_absolute() {
+                return Err(anyhow!("shared path {} must be absolute", host));
+            }
+
+            valid_tag(tag)?;
+
+            if tags.contains(&tag) {
+                return Err(anyhow!("share tag '{}' is used more than once", tag));
+            }
+            tags.push(tag);
+        }
+
         Ok(())
     }
 
@@ -97,6 +828,49 @@ impl Configuration {
         self.ports.remove(&hostport.to_string());
     }
 
+    pub fn add_share(&mut self, host: PathBuf, tag: String) {
+        self.shares.insert(host.display().to_string(), tag);
+    }
+
+    pub fn remove_share(&mut self, host: PathBuf) {
+        self.shares.remove(&host.display().to_string());
+    }
+
+    pub fn add_device(&mut self, device: Device) {
+        self.devices.push(device);
+    }
+
+    /// Returns false when there was no such device.
+    pub fn remove_device(&mut self, device: &Device) -> bool {
+        let len = self.devices.len();
+        self.devices.retain(|d| d != device);
+        len != self.devices.len()
+    }
+
+    pub fn add_numa_node(&mut self, cpus: String, memory: u32, host_node: Option<u32>) {
+        self.machine.numa.push(NumaNode {
+            cpus,
+            memory,
+            host_node,
+        });
+    }
+
+    pub fn clear_numa_nodes(&mut self) {
+        self.machine.numa.clear();
+    }
+
+    pub fn set_disk_value(&mut self, id: u32, key: &str, value: &str) -> Result<()> {
+        let mut disk = self.disks.get(&id.to_string()).cloned().unwrap_or_default();
+        disk.set_value(key, value)?;
+        disk.valid()?;
+        self.disks.insert(id.to_string(), disk);
+        Ok(())
+    }
+
+    pub fn remove_disk(&mut self, id: u32) {
+        self.disks.remove(&id.to_string());
+    }
+
     pub fn set_machine_value(&mut self, key: &str, value: &str) -> Result<()> {
         match key {
             "memory" => {
@@ -127,6 +901,67 @@ impl Configuration {
                 self.machine.cpu_type = value.to_string();
                 Ok(())
             }
+            "sockets" | "cores" | "threads" | "maxcpus" => {
+                let count = match optional(value) {
+                    Some(count) => Some(count.parse::<u32>()?),
+                    None => None,
+                };
+                match key {
+                    "sockets" => self.machine.sockets = count,
+                    "cores" => self.machine.cores = count,
+                    "threads" => self.machine.threads = count,
+                    _ => self.machine.maxcpus = count,
+                }
+                Ok(())
+            }
+            "extra-args" | "extra_args" => {
+                let args = shell_split(value)?;
+                for arg in &args {
+                    valid_extra_arg(arg)?;
+                }
+                self.extra_args = args;
+                Ok(())
+            }
+            "cpu-flags" | "cpu_flags" => {
+                let flags: Vec<String> = value
+                    .split([',', ' '])
+                    .filter(|flag| !flag.is_empty())
+                    .map(ToString::to_string)
+                    .collect();
+                for flag in &flags {
+                    valid_cpu_flag(flag)?;
+                }
+                self.machine.cpu_flags = flags;
+                Ok(())
+            }
+            "memory-backend" | "memory_backend" => {
+                self.machine.memory_backend = value.parse::<MemoryBackend>()?;
+                Ok(())
+            }
+            "memory-shared" | "memory_shared" => {
+                self.machine.memory_shared = value.parse::<bool>()?;
+                Ok(())
+            }
+            "memory-prealloc" | "memory_prealloc" => {
+                self.machine.memory_prealloc = value.parse::<bool>()?;
+                Ok(())
+            }
+            "balloon" => {
+                self.machine.balloon = value.parse::<bool>()?;
+                Ok(())
+            }
+            "free-page-reporting" | "free_page_reporting" => {
+                self.machine.free_page_reporting = value.parse::<bool>()?;
+                Ok(())
+            }
+            "cpu-pinning" | "cpu_pinning" => {
+                let mut pinning = Vec::new();
+                for cpu in value.split([',', ' ']).filter(|cpu| !cpu.is_empty()) {
+                    pinning.push(cpu.parse::<u32>()?);
+                }
+                self.machine.cpu_pinning = pinning;
+                Ok(())
+            }
             "ssh-port" => {
                 self.machine.ssh_port = value.parse::<u16>()?;
                 Ok(())
@@ -135,6 +970,76 @@ impl Configuration {
                 self.machine.ssh_port = value.parse::<u16>()?;
                 Ok(())
             }
+            "require-kvm" | "require_kvm" => {
+                self.machine.require_kvm = value.parse::<bool>()?;
+                Ok(())
+            }
+            "firmware" => {
+                self.machine.firmware = value.parse::<Firmware>()?;
+                Ok(())
+            }
+            "backend" => {
+                self.machine.backend = value.parse::<Backend>()?;
+                Ok(())
+            }
+            "boot-order" | "boot_order" => {
+                let mut order = Vec::new();
+                for device in value.split([',', ' ']).filter(|device| !device.is_empty()) {
+                    order.push(device.parse::<BootDevice>()?);
+                }
+                self.machine.boot_order = order;
+                Ok(())
+            }
+            "boot-menu" | "boot_menu" => {
+                self.machine.boot_menu = value.parse::<bool>()?;
+                Ok(())
+            }
+            "boot-menu-timeout" | "boot_menu_timeout" => {
+                self.machine.boot_menu_timeout = match optional(value) {
+                    Some(timeout) => Some(timeout.parse::<u32>()?),
+                    None => None,
+                };
+                Ok(())
+            }
+            "ovmf-code" | "ovmf_code" => {
+                self.machine.ovmf_code = optional(value).map(PathBuf::from);
+                Ok(())
+            }
+            "ovmf-vars" | "ovmf_vars" => {
+                self.machine.ovmf_vars = optional(value).map(PathBuf::from);
+                Ok(())
+            }
+            "tpm" => {
+                self.machine.tpm = value.parse::<bool>()?;
+                Ok(())
+            }
+            "kernel" => {
+                self.machine.kernel = optional(value).map(PathBuf::from);
+                Ok(())
+            }
+            "initrd" => {
+                self.machine.initrd = optional(value).map(PathBuf::from);
+                Ok(())
+            }
+            "display" => {
+                self.machine.display = value.parse::<DisplayBackend>()?;
+                Ok(())
+            }
+            "display-port" | "display_port" => {
+                self.machine.display_port = match optional(value) {
+                    Some(port) => Some(port.parse::<u16>()?),
+                    None => None,
+                };
+                Ok(())
+            }
+            "display-password" | "display_password" => {
+                self.machine.display_password = optional(value).map(ToString::to_string);
+                Ok(())
+            }
+            "append" => {
+                self.machine.append = optional(value).map(ToString::to_string);
+                Ok(())
+            }
             _ => Err(anyhow!("key does not exist")),
         }
     }
@@ -162,6 +1067,262 @@ mod tests {
         assert_eq!(config.machine.cpu_type, "host");
         config.set_machine_value("ssh-port", "2222")?;
         assert_eq!(config.machine.ssh_port, 2222);
+        config.set_machine_value("sockets", "2")?;
+        assert_eq!(config.machine.sockets, Some(2));
+        config.set_machine_value("threads", "2")?;
+        assert_eq!(config.machine.threads, Some(2));
+        config.set_machine_value("cores", "")?;
+        assert_eq!(config.machine.cores, None);
+        config.set_machine_value("maxcpus", "8")?;
+        assert_eq!(config.machine.maxcpus, Some(8));
+        config.set_machine_value("cpu-flags", "+vmx,-hypervisor")?;
+        assert_eq!(config.machine.cpu_flags, vec!["+vmx", "-hypervisor"]);
+        assert!(config.set_machine_value("cpu-flags", "vmx").is_err());
+        config.set_machine_value("cpu-flags", "")?;
+        assert!(config.machine.cpu_flags.is_empty());
+        config.set_machine_value("require-kvm", "true")?;
+        assert!(config.machine.require_kvm);
+        config.set_machine_value("memory-backend", "hugepages")?;
+        assert_eq!(config.machine.memory_backend, MemoryBackend::Hugepages);
+        assert!(config.set_machine_value("memory-backend", "swap").is_err());
+        config.set_machine_value("memory-shared", "true")?;
+        assert!(config.machine.memory_shared);
+        config.set_machine_value("memory-prealloc", "true")?;
+        assert!(config.machine.memory_prealloc);
+        config.set_machine_value("cpu-pinning", "4,5,6,7")?;
+        assert_eq!(config.machine.cpu_pinning, vec![4, 5, 6, 7]);
+        assert!(config.set_machine_value("cpu-pinning", "4,x").is_err());
+        config.set_machine_value("cpu-pinning", "")?;
+        assert!(config.machine.cpu_pinning.is_empty());
+        config.set_machine_value("extra-args", "-device  pvpanic -name {vm_name}")?;
+        assert_eq!(
+            config.extra_args,
+            vec!["-device", "pvpanic", "-name", "{vm_name}"]
+        );
+        assert!(config
+            .set_machine_value("extra-args", "-name {vm_name")
+            .is_err());
+        config.set_machine_value("extra-args", "-fw_cfg 'name=opt/x,string=a b'")?;
+        assert_eq!(config.extra_args, vec!["-fw_cfg", "name=opt/x,string=a b"]);
+        assert!(config
+            .set_machine_value("extra-args", "-name 'vm1")
+            .is_err());
+        config.set_machine_value("extra-args", "")?;
+        assert!(config.extra_args.is_empty());
+        config.set_machine_value("balloon", "true")?;
+        assert!(config.machine.balloon);
+        config.set_machine_value("free-page-reporting", "true")?;
+        assert!(config.machine.free_page_reporting);
+        config.set_machine_value("firmware", "uefi")?;
+        assert_eq!(config.machine.firmware, Firmware::Uefi);
+        config.set_machine_value("backend", "cloud-hypervisor")?;
+        assert_eq!(config.machine.backend, Backend::CloudHypervisor);
+        config.set_machine_value("backend", "firecracker")?;
+        assert_eq!(config.machine.backend, Backend::Firecracker);
+        assert!(config.set_machine_value("backend", "bochs").is_err());
+        assert!(config.set_machine_value("firmware", "coreboot").is_err());
+        config.set_machine_value("ovmf-code", "/tmp/OVMF_CODE.fd")?;
+        assert_eq!(
+            config.machine.ovmf_code,
+            Some(PathBuf::from("/tmp/OVMF_CODE.fd"))
+        );
+        config.set_machine_value("ovmf-vars", "/tmp/OVMF_VARS.fd")?;
+        assert_eq!(
+            config.machine.ovmf_vars,
+            Some(PathBuf::from("/tmp/OVMF_VARS.fd"))
+        );
+        config.set_machine_value("tpm", "true")?;
+        assert!(config.machine.tpm);
+        assert!(config.set_machine_value("tpm", "yes").is_err());
+        config.set_machine_value("kernel", "/boot/vmlinuz")?;
+        assert_eq!(config.machine.kernel, Some(PathBuf::from("/boot/vmlinuz")));
+        config.set_machine_value("initrd", "/boot/initrd.img")?;
+        assert_eq!(
+            config.machine.initrd,
+            Some(PathBuf::from("/boot/initrd.img"))
+        );
+        config.set_machine_value("append", "console=ttyS0 root=/dev/vda1")?;
+        assert_eq!(
+            config.machine.append,
+            Some("console=ttyS0 root=/dev/vda1".to_string())
+        );
+        config.set_machine_value("append", "")?;
+        assert_eq!(config.machine.append, None);
+        config.set_machine_value("display", "spice")?;
+        assert_eq!(config.machine.display, DisplayBackend::Spice);
+        assert!(config.set_machine_value("display", "sdl").is_err());
+        config.set_machine_value("display-port", "5930")?;
+        assert_eq!(config.machine.display_port, Some(5930));
+        config.set_machine_value("display-port", "")?;
+        assert_eq!(config.machine.display_port, None);
+        config.set_machine_value("display-password", "hunter2")?;
+        assert_eq!(config.machine.display_password, Some("hunter2".to_string()));
+        config.set_machine_value("boot-order", "cdrom,disk")?;
+        assert_eq!(
+            config.machine.boot_order,
+            vec![BootDevice::Cdrom, BootDevice::Disk]
+        );
+        assert!(config.set_machine_value("boot-order", "floppy").is_err());
+        config.set_machine_value("boot-menu", "true")?;
+        assert!(config.machine.boot_menu);
+        config.set_machine_value("boot-menu-timeout", "5000")?;
+        assert_eq!(config.machine.boot_menu_timeout, Some(5000));
+        config.set_machine_value("boot-menu-timeout", "")?;
+        assert_eq!(config.machine.boot_menu_timeout, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_disk_value() -> Result<()> {
+        let mut config = Configuration::default();
+        config.set_disk_value(0, "interface", "nvme")?;
+        assert_eq!(config.disks["0"].interface, Some(DiskInterface::Nvme));
+        assert!(config.set_disk_value(0, "interface", "floppy").is_err());
+        config.set_disk_value(0, "cache", "writeback")?;
+        assert_eq!(config.disks["0"].cache, CacheMode::Writeback);
+        // native aio can't go through the page cache
+        assert!(config.set_disk_value(0, "aio", "native").is_err());
+        config.set_disk_value(0, "aio", "io_uring")?;
+        assert_eq!(config.disks["0"].aio, Some(Aio::IoUring));
+        config.set_disk_value(0, "discard", "true")?;
+        config.set_disk_value(0, "detect-zeroes", "true")?;
+        config.set_disk_value(0, "read-only", "true")?;
+        config.set_disk_value(0, "bootindex", "1")?;
+        assert_eq!(
+            config.disks["0"],
+            DiskConfiguration {
+                interface: Some(DiskInterface::Nvme),
+                cache: CacheMode::Writeback,
+                aio: Some(Aio::IoUring),
+                discard: true,
+                detect_zeroes: true,
+                read_only: true,
+                bootindex: Some(1),
+            }
+        );
+        config.set_disk_value(0, "interface", "")?;
+        assert_eq!(config.disks["0"].interface, None);
+        assert!(config.set_disk_value(0, "size", "10").is_err());
+        config.remove_disk(0);
+        assert!(config.disks.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_valid() -> Result<()> {
+        let mut config = Configuration::default();
+        assert!(config.valid().is_ok());
+        config.machine.append = Some("console=ttyS0".to_string());
+        assert!(config.valid().is_err());
+        config.machine.kernel = Some(PathBuf::from("/boot/vmlinuz"));
+        assert!(config.valid().is_ok());
+        config.machine.display_password = Some("hunter2".to_string());
+        assert!(config.valid().is_err());
+        config.machine.display = DisplayBackend::Vnc;
+        assert!(config.valid().is_ok());
+        config.machine.display_port = Some(5000);
+        assert!(config.valid().is_err());
+        config.machine.display_port = Some(5901);
+        assert!(config.valid().is_ok());
+        config.add_share(PathBuf::from("/home/user/src"), "src".to_string());
+        assert!(config.valid().is_ok());
+        config.add_share(PathBuf::from("/srv/src"), "src".to_string());
+        assert!(config.valid().is_err());
+        config.remove_share(PathBuf::from("/srv/src"));
+        config.add_share(PathBuf::from("src"), "relative".to_string());
+        assert!(config.valid().is_err());
+        config.remove_share(PathBuf::from("src"));
+        assert!(config.valid().is_ok());
+        config.machine.cpus = 4;
+        config.machine.maxcpus = Some(2);
+        assert!(config.valid().is_err());
+        config.machine.maxcpus = Some(8);
+        assert!(config.valid().is_ok());
+        assert_eq!(config.machine.topology(), (1, 8, 1));
+        config.machine.sockets = Some(2);
+        config.machine.threads = Some(2);
+        assert!(config.valid().is_ok());
+        assert_eq!(config.machine.topology(), (2, 2, 2));
+        config.machine.cores = Some(4);
+        assert!(config.valid().is_err());
+        config.machine.cores = None;
+        config.machine.threads = Some(3);
+        assert!(config.valid().is_err());
+        config.machine.threads = Some(0);
+        assert!(config.valid().is_err());
+        config.machine.threads = None;
+        config.machine.cpu_flags = vec!["+vmx".to_string(), "-hypervisor".to_string()];
+        assert!(config.valid().is_ok());
+        assert_eq!(
+            config.machine.cpu_properties(),
+            vec!["vmx=on", "hypervisor=off"]
+        );
+        config.machine.free_page_reporting = true;
+        assert!(config.valid().is_err());
+        config.machine.balloon = true;
+        assert!(config.valid().is_ok());
+        config.machine.cpu_pinning = (0..9).collect();
+        assert!(config.valid().is_err());
+        config.machine.cpu_pinning = (0..8).collect();
+        assert!(config.valid().is_ok());
+        config.machine.memory = 4096;
+        config.add_numa_node("0-3".to_string(), 2048, Some(0));
+        assert!(config.valid().is_err());
+        config.add_numa_node("4-5,7".to_string(), 2048, Some(1));
+        assert!(config.valid().is_ok());
+        config.add_numa_node("5".to_string(), 0, None);
+        assert!(config.valid().is_err());
+        config.clear_numa_nodes();
+        config.add_numa_node("0-8".to_string(), 4096, None);
+        assert!(config.valid().is_err());
+        config.clear_numa_nodes();
+        config.add_device("watchdog".parse()?);
+        config.add_device("virtio-serial,name=org.qemu.guest_agent.0".parse()?);
+        assert!(config.valid().is_ok());
+        config.add_device("watchdog,action=pause".parse()?);
+        assert!(config.valid().is_err());
+        assert!(config.remove_device(&"watchdog,action=pause".parse()?));
+        assert!(!config.remove_device(&"watchdog,action=pause".parse()?));
+        config.add_device("virtio-serial,name=org.qemu.guest_agent.0".parse()?);
+        assert!(config.valid().is_err());
+        config.devices.clear();
+        config.machine.memory = 0;
+        assert!(config.valid().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_cpu_list() -> Result<()> {
+        assert_eq!(parse_cpu_list("0-3,8")?, vec![(0, 3), (8, 8)]);
+        assert_eq!(parse_cpu_list("2")?, vec![(2, 2)]);
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("a-b").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_boot_order() -> Result<()> {
+        let mut config = Configuration::default();
+        config.machine.boot_order = vec![BootDevice::Disk, BootDevice::Network, BootDevice::Disk];
+        assert!(config.valid().is_err());
+        config.machine.boot_order.pop();
+        assert!(config.valid().is_ok());
+        assert_eq!(
+            config.machine.boot_order_from(BootDevice::Network),
+            vec![BootDevice::Network, BootDevice::Disk]
+        );
+        config.machine.boot_order.clear();
+        assert_eq!(
+            config.machine.boot_order_from(BootDevice::Cdrom),
+            vec![BootDevice::Cdrom, BootDevice::Disk]
+        );
+        config.machine.boot_menu_timeout = Some(5000);
+        assert!(config.valid().is_err());
+        config.machine.boot_menu = true;
+        assert!(config.valid().is_ok());
+        config.machine.boot_menu_timeout = Some(70000);
+        assert!(config.valid().is_err());
         Ok(())
     }
 
@@ -208,8 +1369,38 @@ mod tests {
                 image_interface: Default::default(),
                 memory: 2048,
                 vga: Default::default(),
+                firmware: Firmware::Uefi,
+                backend: Backend::CloudHypervisor,
+                tpm: true,
+                boot_order: vec![BootDevice::Cdrom, BootDevice::Disk],
+                numa: vec![NumaNode {
+                    cpus: "0-3".to_string(),
+                    memory: 2048,
+                    host_node: Some(0),
+                }],
+                ..Default::default()
             },
             ports: Default::default(),
+            shares: [("/home/user/src".to_string(), "src".to_string())]
+                .into_iter()
+                .collect(),
+            disks: [(
+                "0".to_string(),
+                DiskConfiguration {
+                    interface: Some(DiskInterface::VirtioScsi),
+                    aio: Some(Aio::Native),
+                    bootindex: Some(1),
+                    ..Default::default()
+                },
+            )]
+            .into_iter()
+            .collect(),
+            devices: vec![
+                Device::UsbTablet,
+                "watchdog,action=poweroff".parse()?,
+                "audio,backend=pipewire".parse()?,
+            ],
+            extra_args: vec!["-device".to_string(), "pvpanic".to_string()],
         };
 
         orig.to_file(path.clone())?;
@@ -1,7 +1,21 @@
 use super::{image::QEMU_IMG_DEFAULT_FORMAT, traits::ConfigStorageHandler, vm::VM};
 use crate::util::path_exists;
 use anyhow::{anyhow, Result};
-use std::{path::PathBuf, sync::Arc};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// The id of a disk image, as shown by `emu list-disks` and used in `[disks]`.
+pub fn disk_id(path: &Path) -> String {
+    path.file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .trim_start_matches("qemu-")
+        .trim_end_matches(QEMU_IMG_DEFAULT_FORMAT)
+        .trim_end_matches('.')
+        .to_string()
+}
 
 #[derive(Debug, Clone)]
 pub struct XDGConfigStorage {
@@ -132,6 +146,14 @@ impl ConfigStorageHandler for XDGConfigStorage {
 
         v.sort();
 
+        // ephemeral runs boot from overlays named after the disks they cover.
+        if let Some(overlays) = vm.overlays() {
+            v = v
+                .iter()
+                .filter_map(|disk| disk.file_name().map(|name| overlays.join(name)))
+                .collect();
+        }
+
         Ok(v)
     }
 
@@ -143,6 +165,60 @@ impl ConfigStorageHandler for XDGConfigStorage {
         self.vm_path(vm, "mon")
     }
 
+    fn serial_path(&self, vm: &VM) -> PathBuf {
+        self.vm_path(vm, "serial")
+    }
+
+    fn display_path(&self, vm: &VM) -> PathBuf {
+        self.vm_path(vm, "display")
+    }
+
+    fn serial_fifo_path(&self, vm: &VM) -> PathBuf {
+        self.vm_path(vm, "serial.fifo")
+    }
+
+    fn serial_log_path(&self, vm: &VM) -> PathBuf {
+        self.vm_path(vm, "serial.log")
+    }
+
+    fn qemu_log_path(&self, vm: &VM) -> PathBuf {
+        self.vm_path(vm, "qemu.log")
+    }
+
+    fn nvram_path(&self, vm: &VM) -> PathBuf {
+        match vm.overlays() {
+            Some(overlays) => overlays.join("nvram.fd"),
+            None => self.vm_path(vm, "nvram.fd"),
+        }
+    }
+
+    fn tpm_path(&self, vm: &VM) -> PathBuf {
+        match vm.overlays() {
+            Some(overlays) => overlays.join("tpm"),
+            None => self.vm_path(vm, "tpm"),
+        }
+    }
+
+    fn cloud_init_path(&self, vm: &VM) -> PathBuf {
+        self.vm_path(vm, "cloud-init")
+    }
+
+    fn seed_path(&self, vm: &VM) -> PathBuf {
+        self.vm_path(vm, "cidata.iso")
+    }
+
+    fn shares_path(&self, vm: &VM) -> PathBuf {
+        self.vm_path(vm, "shares")
+    }
+
+    fn tap_path(&self, vm: &VM) -> PathBuf {
+        self.vm_path(vm, "tap")
+    }
+
+    fn hooks_path(&self, vm: &VM) -> PathBuf {
+        self.vm_path(vm, "hooks")
+    }
+
     fn write_config(&self, vm: VM) -> Result<()> {
         vm.config().to_file(self.config_path(&vm))
     }
@@ -182,6 +258,12 @@ mod tests {
     use anyhow::Result;
     use tempfile::tempdir;
 
+    #[test]
+    fn test_disk_id() {
+        assert_eq!(disk_id(Path::new("/vm/qemu-0.qcow2")), "0");
+        assert_eq!(disk_id(Path::new("/vm/qemu-12.qcow2")), "12");
+    }
+
     #[test]
     fn test_xdg_storage() -> Result<()> {
         let base = tempdir()?;
@@ -221,6 +303,26 @@ mod tests {
         assert_eq!(storage.config_path(&vm1), base_path.join("vm1/config"));
         assert_eq!(storage.pidfile(&vm1), base_path.join("vm1/pid"));
         assert_eq!(storage.monitor_path(&vm1), base_path.join("vm1/mon"));
+        assert_eq!(storage.serial_path(&vm1), base_path.join("vm1/serial"));
+        assert_eq!(storage.display_path(&vm1), base_path.join("vm1/display"));
+        assert_eq!(
+            storage.serial_fifo_path(&vm1),
+            base_path.join("vm1/serial.fifo")
+        );
+        assert_eq!(
+            storage.serial_log_path(&vm1),
+            base_path.join("vm1/serial.log")
+        );
+        assert_eq!(storage.qemu_log_path(&vm1), base_path.join("vm1/qemu.log"));
+        assert_eq!(storage.nvram_path(&vm1), base_path.join("vm1/nvram.fd"));
+        assert_eq!(storage.tpm_path(&vm1), base_path.join("vm1/tpm"));
+        assert_eq!(
+            storage.cloud_init_path(&vm1),
+            base_path.join("vm1/cloud-init")
+        );
+        assert_eq!(storage.seed_path(&vm1), base_path.join("vm1/cidata.iso"));
+        assert_eq!(storage.shares_path(&vm1), base_path.join("vm1/shares"));
+        assert_eq!(storage.hooks_path(&vm1), base_path.join("vm1/hooks"));
         assert_eq!(storage.disk_list(&vm1)?.len(), 0);
         assert_eq!(storage.running_vms()?.len(), 0);
         storage.write_config(vm1.clone())?;
@@ -228,4 +330,26 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_overlays() -> Result<()> {
+        let base = tempdir()?;
+        let storage = XDGConfigStorage::new(base.path().to_path_buf());
+
+        let mut vm: VM = "vm1".to_string().into();
+        storage.create(&vm)?;
+        std::fs::write(storage.vm_path(&vm, "qemu-0.qcow2"), "")?;
+        std::fs::write(storage.vm_path(&vm, "qemu-1.qcow2"), "")?;
+
+        let overlays = base.path().join("overlays");
+        vm.set_overlays(overlays.clone());
+        assert_eq!(
+            storage.disk_list(&vm)?,
+            vec![overlays.join("qemu-0.qcow2"), overlays.join("qemu-1.qcow2")]
+        );
+        assert_eq!(storage.nvram_path(&vm), overlays.join("nvram.fd"));
+        assert_eq!(storage.tpm_path(&vm), overlays.join("tpm"));
+        assert_eq!(storage.pidfile(&vm), storage.vm_path(&vm, "pid"));
+        Ok(())
+    }
 }
@@ -0,0 +1,103 @@
+use anyhow::{anyhow, Result};
+use std::{
+    io::Read,
+    path::Path,
+    process::{Command, Stdio},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+};
+
+/// Ctrl-], the same detach key telnet and virsh use.
+pub const ESCAPE_CHAR: u8 = 0x1d;
+pub const ESCAPE_DESCRIPTION: &str = "Ctrl-]";
+
+/// Puts the controlling terminal in raw mode for as long as it lives. `stty` is used so that we
+/// don't have to carry termios bindings around.
+#[derive(Debug)]
+pub struct RawTerminal {
+    saved: String,
+}
+
+fn stty(args: &[&str]) -> Result<String> {
+    let out = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()?;
+
+    if out.status.success() {
+        Ok(String::from_utf8_lossy(&out.stdout).trim().to_string())
+    } else {
+        Err(anyhow!("stty exited uncleanly: {}", out.status))
+    }
+}
+
+impl RawTerminal {
+    pub fn enable() -> Result<Self> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        Ok(Self { saved })
+    }
+
+    pub fn restore(self) -> Result<()> {
+        stty(&[&self.saved])?;
+        Ok(())
+    }
+}
+
+// standard input is read on a plain thread: tokio's stdin keeps the runtime alive until the next
+// keypress after we detach, which is confusing at an interactive terminal.
+fn stdin_channel() -> UnboundedReceiver<Vec<u8>> {
+    let (s, r) = unbounded_channel();
+    std::thread::spawn(move || {
+        let mut buf = [0_u8; 4096];
+        let mut stdin = std::io::stdin();
+        while let Ok(size) = stdin.read(&mut buf) {
+            if size == 0 || s.send(buf[..size].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+
+    r
+}
+
+/// Connect standard input and output to the unix socket at `path` until either side closes, or
+/// the escape character is typed.
+pub async fn attach(path: &Path, escape: u8) -> Result<()> {
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| anyhow!("could not connect to {}: {}", path.display(), e))?;
+    let (mut reader, mut writer) = stream.into_split();
+    let mut input = stdin_channel();
+    let mut stdout = tokio::io::stdout();
+    let mut buf = [0_u8; 4096];
+
+    loop {
+        tokio::select! {
+            res = reader.read(&mut buf) => {
+                let size = res?;
+                if size == 0 {
+                    break;
+                }
+                stdout.write_all(&buf[..size]).await?;
+                stdout.flush().await?;
+            }
+            data = input.recv() => match data {
+                Some(data) => {
+                    if let Some(pos) = data.iter().position(|b| *b == escape) {
+                        writer.write_all(&data[..pos]).await?;
+                        break;
+                    }
+                    writer.write_all(&data).await?;
+                }
+                None => break,
+            }
+        }
+    }
+
+    Ok(())
+}
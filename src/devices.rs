@@ -0,0 +1,319 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+const DEFAULT_AUDIO_BACKEND: &str = "pa";
+
+fn default_audio_backend() -> String {
+    DEFAULT_AUDIO_BACKEND.to_string()
+}
+
+/// What qemu does when the guest stops feeding the watchdog.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatchdogAction {
+    #[default]
+    Reset,
+    Shutdown,
+    Poweroff,
+    Pause,
+    InjectNmi,
+    None,
+}
+
+impl std::fmt::Display for WatchdogAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Reset => "reset",
+            Self::Shutdown => "shutdown",
+            Self::Poweroff => "poweroff",
+            Self::Pause => "pause",
+            Self::InjectNmi => "inject-nmi",
+            Self::None => "none",
+        })
+    }
+}
+
+impl FromStr for WatchdogAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "reset" => Ok(Self::Reset),
+            "shutdown" => Ok(Self::Shutdown),
+            "poweroff" => Ok(Self::Poweroff),
+            "pause" => Ok(Self::Pause),
+            "inject-nmi" => Ok(Self::InjectNmi),
+            "none" => Ok(Self::None),
+            _ => Err(anyhow!(
+                "watchdog action must be one of: reset, shutdown, poweroff, pause, inject-nmi, none"
+            )),
+        }
+    }
+}
+
+/// Extra devices a VM can have, in `[[devices]]`. On the command line they're written like
+/// qemu options: the type, then comma-separated settings, e.g. `watchdog,action=poweroff`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Device {
+    /// virtio-rng, fed from the host's /dev/urandom
+    VirtioRng,
+    /// i6300esb watchdog
+    Watchdog {
+        #[serde(default)]
+        action: WatchdogAction,
+    },
+    /// absolute pointer; the mouse follows the host cursor in graphical consoles
+    UsbTablet,
+    UsbKeyboard,
+    /// intel-hda sound card, played through the host's audio backend (pa, pipewire, alsa, ...)
+    Audio {
+        #[serde(default = "default_audio_backend")]
+        backend: String,
+    },
+    /// a virtio-serial port with this name (e.g. org.qemu.guest_agent.0), exposed as a socket in
+    /// the VM's directory
+    VirtioSerial {
+        name: String,
+    },
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+impl Device {
+    pub fn valid(&self) -> Result<()> {
+        match self {
+            Self::Audio { backend } if !valid_name(backend) => {
+                Err(anyhow!("invalid audio backend '{}'", backend))
+            }
+            Self::VirtioSerial { name } if !valid_name(name) => Err(anyhow!(
+                "virtio-serial port name '{}' may only contain letters, numbers, '-', '_' and '.'",
+                name
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn is_usb(&self) -> bool {
+        matches!(self, Self::UsbTablet | Self::UsbKeyboard)
+    }
+
+    /// The socket a virtio-serial port is served on.
+    pub fn socket_path(dir: &Path, name: &str) -> PathBuf {
+        dir.join(format!("{}.sock", name))
+    }
+}
+
+impl std::fmt::Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::VirtioRng => f.write_str("virtio-rng"),
+            Self::Watchdog { action } => write!(f, "watchdog,action={}", action),
+            Self::UsbTablet => f.write_str("usb-tablet"),
+            Self::UsbKeyboard => f.write_str("usb-keyboard"),
+            Self::Audio { backend } => write!(f, "audio,backend={}", backend),
+            Self::VirtioSerial { name } => write!(f, "virtio-serial,name={}", name),
+        }
+    }
+}
+
+impl FromStr for Device {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(',');
+        let typ = parts.next().unwrap_or_default();
+
+        let mut settings = Vec::new();
+        for part in parts {
+            match part.split_once('=') {
+                Some((key, value)) => settings.push((key, value)),
+                None => return Err(anyhow!("device setting '{}' must be key=value", part)),
+            }
+        }
+
+        let mut device = match typ {
+            "virtio-rng" => Self::VirtioRng,
+            "watchdog" => Self::Watchdog {
+                action: WatchdogAction::default(),
+            },
+            "usb-tablet" => Self::UsbTablet,
+            "usb-keyboard" => Self::UsbKeyboard,
+            "audio" => Self::Audio {
+                backend: default_audio_backend(),
+            },
+            "virtio-serial" => Self::VirtioSerial {
+                name: String::new(),
+            },
+            _ => {
+                return Err(anyhow!(
+                "device must be one of: virtio-rng, watchdog, usb-tablet, usb-keyboard, audio, virtio-serial"
+            ))
+            }
+        };
+
+        for (key, value) in settings {
+            match (&mut device, key) {
+                (Self::Watchdog { action }, "action") => *action = value.parse()?,
+                (Self::Audio { backend }, "backend") => *backend = value.to_string(),
+                (Self::VirtioSerial { name }, "name") => *name = value.to_string(),
+                _ => return Err(anyhow!("{} has no setting '{}'", typ, key)),
+            }
+        }
+
+        device.valid()?;
+        Ok(device)
+    }
+}
+
+/// qemu arguments for the devices. Buses the devices need (USB, virtio-serial) are added once,
+/// and virtio-serial sockets go in `dir`.
+pub fn qemu_args(devices: &[Device], dir: &Path) -> Vec<String> {
+    let mut v = Vec::new();
+
+    if devices.iter().any(Device::is_usb) {
+        v.push("-device".to_string());
+        v.push("qemu-xhci,id=usb".to_string());
+    }
+
+    if devices
+        .iter()
+        .any(|d| matches!(d, Device::VirtioSerial { .. }))
+    {
+        v.push("-device".to_string());
+        v.push("virtio-serial-pci,id=vserial0".to_string());
+    }
+
+    for (x, device) in devices.iter().enumerate() {
+        match device {
+            Device::VirtioRng => {
+                v.push("-object".to_string());
+                v.push(format!("rng-random,id=rng{},filename=/dev/urandom", x));
+                v.push("-device".to_string());
+                v.push(format!("virtio-rng-pci,rng=rng{}", x));
+            }
+            Device::Watchdog { action } => {
+                v.push("-device".to_string());
+                v.push("i6300esb".to_string());
+                v.push("-action".to_string());
+                v.push(format!("watchdog={}", action));
+            }
+            Device::UsbTablet => {
+                v.push("-device".to_string());
+                v.push("usb-tablet,bus=usb.0".to_string());
+            }
+            Device::UsbKeyboard => {
+                v.push("-device".to_string());
+                v.push("usb-kbd,bus=usb.0".to_string());
+            }
+            Device::Audio { backend } => {
+                v.push("-audiodev".to_string());
+                v.push(format!("{},id=audio{}", backend, x));
+                v.push("-device".to_string());
+                v.push(format!("intel-hda,id=hda{}", x));
+                v.push("-device".to_string());
+                v.push(format!("hda-duplex,bus=hda{}.0,audiodev=audio{}", x, x));
+            }
+            Device::VirtioSerial { name } => {
+                v.push("-chardev".to_string());
+                v.push(format!(
+                    "socket,server=on,wait=off,id=vport{},path={}",
+                    x,
+                    Device::socket_path(dir, name).display()
+                ));
+                v.push("-device".to_string());
+                v.push(format!(
+                    "virtserialport,bus=vserial0.0,chardev=vport{},name={}",
+                    x, name
+                ));
+            }
+        }
+    }
+
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!("virtio-rng".parse::<Device>()?, Device::VirtioRng);
+        assert_eq!(
+            "watchdog".parse::<Device>()?,
+            Device::Watchdog {
+                action: WatchdogAction::Reset
+            }
+        );
+        assert_eq!(
+            "watchdog,action=poweroff".parse::<Device>()?,
+            Device::Watchdog {
+                action: WatchdogAction::Poweroff
+            }
+        );
+        assert_eq!(
+            "audio".parse::<Device>()?,
+            Device::Audio {
+                backend: "pa".to_string()
+            }
+        );
+        let serial = "virtio-serial,name=org.qemu.guest_agent.0".parse::<Device>()?;
+        assert_eq!(
+            serial.to_string(),
+            "virtio-serial,name=org.qemu.guest_agent.0"
+        );
+
+        assert!("virtio-serial".parse::<Device>().is_err());
+        assert!("virtio-serial,name=a/b".parse::<Device>().is_err());
+        assert!("watchdog,action=explode".parse::<Device>().is_err());
+        assert!("usb-tablet,speed=high".parse::<Device>().is_err());
+        assert!("floppy".parse::<Device>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_qemu_args() -> Result<()> {
+        let devices = vec![
+            Device::UsbTablet,
+            Device::Watchdog {
+                action: WatchdogAction::Poweroff,
+            },
+            Device::VirtioSerial {
+                name: "org.qemu.guest_agent.0".to_string(),
+            },
+        ];
+
+        assert_eq!(
+            qemu_args(&devices, Path::new("/vm")),
+            vec![
+                "-device",
+                "qemu-xhci,id=usb",
+                "-device",
+                "virtio-serial-pci,id=vserial0",
+                "-device",
+                "usb-tablet,bus=usb.0",
+                "-device",
+                "i6300esb",
+                "-action",
+                "watchdog=poweroff",
+                "-chardev",
+                "socket,server=on,wait=off,id=vport2,path=/vm/org.qemu.guest_agent.0.sock",
+                "-device",
+                "virtserialport,bus=vserial0.0,chardev=vport2,name=org.qemu.guest_agent.0",
+            ]
+        );
+        assert!(qemu_args(&[], Path::new("/vm")).is_empty());
+        Ok(())
+    }
+}
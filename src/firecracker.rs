@@ -0,0 +1,605 @@
+use crate::{
+    accel,
+    cloud_init::CloudInit,
+    config::{Aio, CacheMode, MemoryBackend},
+    config_storage::{disk_id, XDGConfigStorage},
+    hooks::{HookEvent, Hooks},
+    image::is_qcow2,
+    launcher::daemonize,
+    logs::{OutputLogger, SerialLogger},
+    rest::RestClient,
+    snapshot,
+    tap::Tap,
+    traits::{ConfigStorageHandler, Launcher},
+    util::{path_exists, pid_running},
+    vm::VM,
+};
+use anyhow::{anyhow, Result};
+use serde_json::{json, Value};
+use std::{
+    fs::{read_to_string, remove_file, OpenOptions},
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::Arc,
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+pub const FIRECRACKER_BIN_NAME: &str = "firecracker";
+const API_SOCKET: &str = "firecracker.sock";
+const MACHINE_CONFIG: &str = "firecracker.json";
+const SNAPSHOTS_DIR: &str = "snapshots";
+const SNAPSHOT_STATE: &str = "vmstate";
+const SNAPSHOT_MEMORY: &str = "memory";
+const API_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_BOOT_ARGS: &str = "console=ttyS0 reboot=k panic=1 pci=off";
+
+/// Runs VMs as Firecracker microVMs: a kernel booted straight onto raw disk images, with the
+/// network on a TAP device. Meant for short-lived VMs that boot in well under a second.
+#[derive(Debug, Clone)]
+pub struct FirecrackerLauncher {
+    config: Arc<Box<dyn ConfigStorageHandler>>,
+}
+
+impl Default for FirecrackerLauncher {
+    fn default() -> Self {
+        Self {
+            config: Arc::new(Box::new(XDGConfigStorage::default())),
+        }
+    }
+}
+
+impl FirecrackerLauncher {
+    pub fn new(config: Arc<Box<dyn ConfigStorageHandler>>) -> Self {
+        Self { config }
+    }
+
+    fn api_path(&self, vm: &VM) -> PathBuf {
+        self.config.vm_path(vm, API_SOCKET)
+    }
+
+    fn api(&self, vm: &VM) -> RestClient {
+        RestClient::new(self.api_path(vm))
+    }
+
+    fn snapshot_path(&self, vm: &VM, name: &str) -> PathBuf {
+        self.config.vm_path(vm, SNAPSHOTS_DIR).join(name)
+    }
+
+    fn running(&self, vm: &VM) -> bool {
+        read_to_string(self.config.pidfile(vm))
+            .ok()
+            .and_then(|s| s.trim().parse::<u32>().ok())
+            .is_some_and(pid_running)
+    }
+
+    fn wait_api(&self, vm: &VM) -> Result<()> {
+        let start = Instant::now();
+        while self.api(vm).get("/").is_err() {
+            if start.elapsed() > API_TIMEOUT {
+                return Err(anyhow!(
+                    "timed out waiting for the {} API",
+                    FIRECRACKER_BIN_NAME
+                ));
+            }
+
+            sleep(Duration::from_millis(50));
+        }
+
+        Ok(())
+    }
+
+    // settings only qemu can honor are refused rather than ignored.
+    fn check(&self, vm: &VM) -> Result<()> {
+        let config = vm.config();
+        let machine = &config.machine;
+        let unsupported = |what: &str| {
+            Err(anyhow!(
+                "{} is not supported with {}",
+                what,
+                FIRECRACKER_BIN_NAME
+            ))
+        };
+
+        if machine.kernel.is_none() {
+            return Err(anyhow!(
+                "{} has no firmware and needs a `kernel` to boot",
+                FIRECRACKER_BIN_NAME
+            ));
+        }
+
+        if machine.sockets.is_some()
+            || machine.cores.is_some()
+            || machine.maxcpus.is_some()
+            || machine.threads.is_some_and(|t| t > 2)
+        {
+            return unsupported("a CPU topology other than `threads` of 1 or 2");
+        }
+
+        if !machine.numa.is_empty() {
+            return unsupported("numa");
+        }
+
+        if !machine.cpu_flags.is_empty() {
+            return unsupported("cpu_flags");
+        }
+
+        if !machine.cpu_pinning.is_empty() {
+            return unsupported("cpu_pinning");
+        }
+
+        if machine.free_page_reporting {
+            return unsupported("free_page_reporting");
+        }
+
+        if machine.tpm {
+            return unsupported("tpm");
+        }
+
+        if !config.shares.is_empty() {
+            return unsupported("[shares]");
+        }
+
+        if !config.devices.is_empty() {
+            return unsupported("[[devices]]");
+        }
+
+        if vm.cdrom().is_some() || vm.extra_disk().is_some() {
+            return unsupported("a CD-ROM");
+        }
+
+        if !machine.boot_order.is_empty() || machine.boot_menu || vm.boot_once().is_some() {
+            return unsupported("boot_order, boot_menu and --boot-once");
+        }
+
+        for (id, disk) in &config.disks {
+            if disk.interface.is_some()
+                || disk.aio.is_some_and(|aio| aio != Aio::IoUring)
+                || disk.bootindex.is_some()
+                || disk.discard
+                || disk.detect_zeroes
+            {
+                return unsupported(&format!(
+                    "disk {}'s settings other than cache, aio of io_uring and read_only",
+                    id
+                ));
+            }
+        }
+
+        for disk in self.config.disk_list(vm)? {
+            if is_qcow2(&disk)? {
+                return Err(anyhow!(
+                    "{} only boots raw images, and {} is qcow2; convert it with `qemu-img convert -O raw`",
+                    FIRECRACKER_BIN_NAME,
+                    disk.display()
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The VM in the form of Firecracker's `--config-file`; each section is also the body of the
+    /// API call that configures it.
+    pub fn machine_config(&self, vm: &VM) -> Result<Value> {
+        let config = vm.config();
+        let machine = &config.machine;
+        let tap = Tap::new(&**self.config, vm)?;
+
+        let mut boot_source = json!({
+            "kernel_image_path": machine.kernel.clone().unwrap_or_default(),
+            "boot_args": format!(
+                "{} {}",
+                machine.append.as_deref().unwrap_or(DEFAULT_BOOT_ARGS),
+                tap.kernel_arg()
+            ),
+        });
+        if let Some(initrd) = &machine.initrd {
+            boot_source["initrd_path"] = json!(initrd);
+        }
+
+        // the first disk is the root filesystem.
+        let mut drives = Vec::new();
+        for (i, disk) in self.config.disk_list(vm)?.iter().enumerate() {
+            let id = disk_id(disk);
+            let settings = config.disks.get(&id).cloned().unwrap_or_default();
+            let mut drive = json!({
+                "drive_id": format!("disk{}", id),
+                "path_on_host": disk,
+                "is_root_device": i == 0,
+                "is_read_only": settings.read_only,
+                "cache_type": if settings.cache == CacheMode::Unsafe { "Unsafe" } else { "Writeback" },
+            });
+            if settings.aio == Some(Aio::IoUring) {
+                drive["io_engine"] = json!("Async");
+            }
+            drives.push(drive);
+        }
+        if path_exists(self.config.cloud_init_path(vm)) {
+            drives.push(json!({
+                "drive_id": "seed",
+                "path_on_host": self.config.seed_path(vm),
+                "is_root_device": false,
+                "is_read_only": true,
+            }));
+        }
+
+        let mut machine_config = json!({
+            "vcpu_count": machine.cpus,
+            "mem_size_mib": machine.memory,
+            "smt": machine.threads == Some(2),
+        });
+        if machine.memory_backend == MemoryBackend::Hugepages {
+            machine_config["huge_pages"] = json!("2M");
+        }
+
+        let mut v = json!({
+            "boot-source": boot_source,
+            "drives": drives,
+            "machine-config": machine_config,
+            "network-interfaces": [{
+                "iface_id": "eth0",
+                "guest_mac": tap.guest_mac(),
+                "host_dev_name": tap.name(),
+            }],
+        });
+        if machine.balloon {
+            v["balloon"] = json!({
+                "amount_mib": 0,
+                "deflate_on_oom": true,
+                "stats_polling_interval_s": 1,
+            });
+        }
+
+        Ok(v)
+    }
+
+    fn boot(&self, vm: &VM, machine_config: &Value) -> Result<()> {
+        let api = self.api(vm);
+        api.put(
+            "/machine-config",
+            Some(machine_config["machine-config"].clone()),
+        )?;
+        api.put("/boot-source", Some(machine_config["boot-source"].clone()))?;
+        for drive in machine_config["drives"].as_array().into_iter().flatten() {
+            api.put(
+                &format!("/drives/{}", drive["drive_id"].as_str().unwrap_or_default()),
+                Some(drive.clone()),
+            )?;
+        }
+        for iface in machine_config["network-interfaces"]
+            .as_array()
+            .into_iter()
+            .flatten()
+        {
+            api.put(
+                &format!(
+                    "/network-interfaces/{}",
+                    iface["iface_id"].as_str().unwrap_or_default()
+                ),
+                Some(iface.clone()),
+            )?;
+        }
+        if !machine_config["balloon"].is_null() {
+            api.put("/balloon", Some(machine_config["balloon"].clone()))?;
+        }
+        api.put("/actions", Some(json!({"action_type": "InstanceStart"})))?;
+        Ok(())
+    }
+
+    fn load_snapshot(&self, vm: &VM, path: &Path) -> Result<()> {
+        self.api(vm).put(
+            "/snapshot/load",
+            Some(json!({
+                "snapshot_path": path.join(SNAPSHOT_STATE),
+                "mem_backend": {
+                    "backend_type": "File",
+                    "backend_path": path.join(SNAPSHOT_MEMORY),
+                },
+                "resume_vm": true,
+            })),
+        )?;
+        Ok(())
+    }
+
+    fn prepare(&self, vm: &VM) -> Result<()> {
+        accel::kvm_usable()?;
+        self.check(vm)?;
+        // its socket and TAP device are about to be replaced.
+        if self.running(vm) {
+            return Err(anyhow!("{} is already running", vm));
+        }
+
+        let path = self.config.cloud_init_path(vm);
+        if path_exists(path.clone()) {
+            CloudInit::from_file(&path)?.write_seed(&vm.name(), &self.config.seed_path(vm))?;
+        }
+
+        // firecracker refuses to start on a stale socket.
+        let api = self.api_path(vm);
+        if path_exists(api.clone()) {
+            remove_file(api)?;
+        }
+
+        Tap::new(&**self.config, vm)?.create()
+    }
+
+    // runs firecracker until it exits, booting the VM or restoring `snapshot` once the API is up.
+    // `detached` writes the pidfile. The guest's serial console is firecracker's stdout, which
+    // goes to the serial logger.
+    fn run(&self, vm: &VM, detached: bool, snapshot: Option<PathBuf>) -> Result<ExitStatus> {
+        let tap = Tap::new(&**self.config, vm)?;
+        let logger = SerialLogger::start(
+            self.config.serial_fifo_path(vm),
+            self.config.serial_log_path(vm),
+        )?;
+        let mut output = OutputLogger::start(self.config.qemu_log_path(vm), FIRECRACKER_BIN_NAME)?;
+        let hooks = Hooks::new(self.config.clone());
+        let serial = OpenOptions::new()
+            .write(true)
+            .open(self.config.serial_fifo_path(vm))?;
+
+        let status = Command::new(FIRECRACKER_BIN_NAME)
+            .args(["--api-sock", &self.api_path(vm).display().to_string()])
+            .stdin(Stdio::null())
+            .stdout(serial)
+            .stderr(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                output.capture(&mut child);
+                if detached {
+                    std::fs::write(self.config.pidfile(vm), format!("{}", child.id()))?;
+                }
+
+                let started = self.wait_api(vm).and_then(|_| match &snapshot {
+                    Some(path) => self.load_snapshot(vm, path),
+                    None => {
+                        let machine_config = self.machine_config(vm)?;
+                        std::fs::write(
+                            self.config.vm_path(vm, MACHINE_CONFIG),
+                            serde_json::to_string_pretty(&machine_config)?,
+                        )?;
+                        self.boot(vm, &machine_config)
+                    }
+                });
+
+                // a firecracker that never booted would otherwise sit there forever.
+                match started.and_then(|_| tap.forward_ports(&vm.config().ports)) {
+                    Ok(_) => hooks.notify(vm, HookEvent::PostStart, &[]),
+                    Err(e) => {
+                        println!("could not start {}: {}", vm, e);
+                        child.kill()?;
+                    }
+                }
+
+                child.wait()
+            });
+
+        match &status {
+            Ok(status) => output.finish(&format!("exited with {}", status)),
+            Err(e) => output.finish(&format!("could not start: {}", e)),
+        }
+        logger.finish();
+        if let Err(e) = tap.destroy() {
+            println!("could not remove {}: {}", tap.name(), e);
+        }
+        if status.is_ok() {
+            hooks.notify(vm, HookEvent::PostStop, &[]);
+        }
+        Ok(status?)
+    }
+}
+
+impl Launcher for FirecrackerLauncher {
+    fn command_line(&self, vm: &VM) -> Result<Vec<String>> {
+        Ok(vec![
+            FIRECRACKER_BIN_NAME.to_string(),
+            "--api-sock".to_string(),
+            self.api_path(vm).display().to_string(),
+        ])
+    }
+
+    fn launch_attached(&self, vm: &VM) -> Result<ExitStatus> {
+        self.prepare(vm)?;
+        self.run(vm, false, None)
+    }
+
+    fn launch_detached(&self, vm: &VM) -> Result<()> {
+        self.prepare(vm)?;
+        daemonize(&self.config, vm, FIRECRACKER_BIN_NAME, || {
+            self.run(vm, true, None).map(|_| ())
+        })
+    }
+
+    fn shutdown_wait(&self, vm: &VM) -> Result<ExitStatus> {
+        self.shutdown_immediately(vm)?;
+
+        let pidfile = self.config.pidfile(vm);
+        let pid = read_to_string(&pidfile)?.trim().parse::<u32>()?;
+        let mut start = Instant::now();
+        while pid_running(pid) {
+            sleep(Duration::from_millis(50));
+            if start.elapsed() > Duration::from_secs(10) {
+                println!("Waiting for {} to quit...", FIRECRACKER_BIN_NAME);
+                start = Instant::now();
+            }
+        }
+        remove_file(pidfile)?;
+
+        Ok(ExitStatus::default())
+    }
+
+    // the guest reboots on ctrl-alt-del, which with `reboot=k` ends firecracker.
+    fn shutdown_immediately(&self, vm: &VM) -> Result<()> {
+        self.api(vm)
+            .put("/actions", Some(json!({"action_type": "SendCtrlAltDel"})))?;
+        Ok(())
+    }
+
+    fn reset(&self, _vm: &VM) -> Result<()> {
+        Err(anyhow!(
+            "{} cannot reset a VM; shut it down and start it again",
+            FIRECRACKER_BIN_NAME
+        ))
+    }
+
+    fn snapshot(&self, vm: &VM, name: String) -> Result<()> {
+        let path = self.snapshot_path(vm, &name);
+        if path_exists(path.clone()) {
+            std::fs::remove_dir_all(&path)?;
+        }
+        std::fs::create_dir_all(&path)?;
+
+        // the VM has to be paused while it is saved, and resumed whether that worked or not.
+        let api = self.api(vm);
+        api.patch("/vm", Some(json!({"state": "Paused"})))?;
+        let res = api
+            .put(
+                "/snapshot/create",
+                Some(json!({
+                    "snapshot_type": "Full",
+                    "snapshot_path": path.join(SNAPSHOT_STATE),
+                    "mem_file_path": path.join(SNAPSHOT_MEMORY),
+                })),
+            )
+            .and_then(|_| snapshot::save_disks(&**self.config, vm, &path));
+        api.patch("/vm", Some(json!({"state": "Resumed"})))?;
+        if res.is_err() {
+            std::fs::remove_dir_all(&path).unwrap_or_default();
+        }
+        res?;
+
+        println!("Saved current state to snapshot '{}'", name);
+        Ok(())
+    }
+
+    fn restore(&self, vm: &VM, name: String) -> Result<()> {
+        let path = self.snapshot_path(vm, &name);
+        if !path_exists(path.clone()) {
+            return Err(anyhow!("{} has no snapshot '{}'", vm, name));
+        }
+
+        if self.running(vm) {
+            return Err(anyhow!(
+                "{} can only restore a VM that is not running; shut {} down first",
+                FIRECRACKER_BIN_NAME,
+                vm
+            ));
+        }
+
+        snapshot::restore_disks(&**self.config, vm, &name, &path)?;
+
+        self.prepare(vm)?;
+        daemonize(&self.config, vm, FIRECRACKER_BIN_NAME, || {
+            self.run(vm, true, Some(path)).map(|_| ())
+        })?;
+
+        println!("Restored from snapshot '{}'", name);
+        Ok(())
+    }
+
+    fn delete_snapshot(&self, vm: &VM, name: String) -> Result<()> {
+        let path = self.snapshot_path(vm, &name);
+        if !path_exists(path.clone()) {
+            return Err(anyhow!("{} has no snapshot '{}'", vm, name));
+        }
+
+        std::fs::remove_dir_all(path)?;
+        println!("Deleted snapshot '{}'", name);
+        Ok(())
+    }
+
+    // the balloon is sized by how much memory it takes away, not how much is left.
+    fn balloon(&self, vm: &VM, megabytes: u32) -> Result<()> {
+        let inflate = vm.config().machine.memory.saturating_sub(megabytes);
+        self.api(vm)
+            .patch("/balloon", Some(json!({"amount_mib": inflate})))?;
+        Ok(())
+    }
+
+    fn balloon_size(&self, vm: &VM) -> Result<u32> {
+        let stats = self
+            .api(vm)
+            .get("/balloon/statistics")?
+            .ok_or_else(|| anyhow!("{} returned no balloon statistics", FIRECRACKER_BIN_NAME))?;
+
+        match stats["actual_mib"].as_u64() {
+            Some(size) => Ok(vm.config().machine.memory.saturating_sub(size as u32)),
+            None => Err(anyhow!(
+                "{} did not report the balloon's size",
+                FIRECRACKER_BIN_NAME
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Configuration;
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_machine_config() -> Result<()> {
+        let dir = tempdir()?;
+        let storage: Arc<Box<dyn ConfigStorageHandler>> =
+            Arc::new(Box::new(XDGConfigStorage::new(dir.path().to_path_buf())));
+        let launcher = FirecrackerLauncher::new(storage.clone());
+
+        let mut vm = VM::new("vm1".to_string(), storage.clone());
+        storage.create(&vm)?;
+        let root = storage.vm_root(&vm);
+        let disk = root.join("qemu-0.qcow2");
+        std::fs::write(&disk, vec![0; 512])?;
+
+        let mut config = Configuration::default();
+        config.machine.memory = 256;
+        config.machine.cpus = 2;
+        config.machine.balloon = true;
+        config.machine.kernel = Some(PathBuf::from("/boot/vmlinux"));
+        config.set_disk_value(0, "read-only", "true")?;
+        vm.set_config(config.clone());
+        launcher.check(&vm)?;
+
+        let tap = Tap::new(&**storage, &vm)?;
+        assert_eq!(
+            launcher.machine_config(&vm)?,
+            json!({
+                "boot-source": {
+                    "kernel_image_path": "/boot/vmlinux",
+                    "boot_args": format!("{} {}", DEFAULT_BOOT_ARGS, tap.kernel_arg()),
+                },
+                "drives": [{
+                    "drive_id": "disk0",
+                    "path_on_host": disk,
+                    "is_root_device": true,
+                    "is_read_only": true,
+                    "cache_type": "Writeback",
+                }],
+                "machine-config": {
+                    "vcpu_count": 2,
+                    "mem_size_mib": 256,
+                    "smt": false,
+                },
+                "network-interfaces": [{
+                    "iface_id": "eth0",
+                    "guest_mac": tap.guest_mac(),
+                    "host_dev_name": tap.name(),
+                }],
+                "balloon": {
+                    "amount_mib": 0,
+                    "deflate_on_oom": true,
+                    "stats_polling_interval_s": 1,
+                },
+            })
+        );
+
+        std::fs::write(&disk, b"QFI\xfb\0\0\0\x03")?;
+        assert!(launcher.check(&vm).is_err());
+        config.machine.kernel = None;
+        vm.set_config(config);
+        assert!(launcher.check(&vm).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+use crate::{config::MachineConfiguration, util::path_exists};
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+
+// pairs of (code, vars) images as installed by the common distribution packages of OVMF. The
+// order matters; the first pair where both files exist wins.
+const OVMF_SEARCH_PATHS: &[(&str, &str)] = &[
+    // debian, ubuntu
+    (
+        "/usr/share/OVMF/OVMF_CODE_4M.fd",
+        "/usr/share/OVMF/OVMF_VARS_4M.fd",
+    ),
+    (
+        "/usr/share/OVMF/OVMF_CODE.fd",
+        "/usr/share/OVMF/OVMF_VARS.fd",
+    ),
+    // fedora, rhel
+    (
+        "/usr/share/edk2/ovmf/OVMF_CODE.fd",
+        "/usr/share/edk2/ovmf/OVMF_VARS.fd",
+    ),
+    // arch
+    (
+        "/usr/share/edk2/x64/OVMF_CODE.4m.fd",
+        "/usr/share/edk2/x64/OVMF_VARS.4m.fd",
+    ),
+    (
+        "/usr/share/edk2-ovmf/x64/OVMF_CODE.fd",
+        "/usr/share/edk2-ovmf/x64/OVMF_VARS.fd",
+    ),
+    // opensuse
+    (
+        "/usr/share/qemu/ovmf-x86_64-code.bin",
+        "/usr/share/qemu/ovmf-x86_64-vars.bin",
+    ),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OVMF {
+    pub code: PathBuf,
+    pub vars: PathBuf,
+}
+
+impl OVMF {
+    /// Locate the OVMF code and vars templates. Paths set in the machine configuration take
+    /// precedence over the standard installation paths.
+    pub fn locate(machine: &MachineConfiguration) -> Result<Self> {
+        Self::locate_in(machine, OVMF_SEARCH_PATHS)
+    }
+
+    fn locate_in(machine: &MachineConfiguration, search: &[(&str, &str)]) -> Result<Self> {
+        let found = search
+            .iter()
+            .map(|(code, vars)| (PathBuf::from(code), PathBuf::from(vars)))
+            .find(|(code, vars)| path_exists(code.clone()) && path_exists(vars.clone()));
+
+        let code = match (&machine.ovmf_code, &found) {
+            (Some(code), _) => code.clone(),
+            (None, Some((code, _))) => code.clone(),
+            (None, None) => {
+                return Err(anyhow!(
+                    "could not locate OVMF code image; install OVMF or set ovmf-code"
+                ))
+            }
+        };
+
+        let vars = match (&machine.ovmf_vars, &found) {
+            (Some(vars), _) => vars.clone(),
+            (None, Some((_, vars))) => vars.clone(),
+            (None, None) => {
+                return Err(anyhow!(
+                    "could not locate OVMF vars image; install OVMF or set ovmf-vars"
+                ))
+            }
+        };
+
+        for path in [&code, &vars] {
+            if !path_exists(path.clone()) {
+                return Err(anyhow!("OVMF image {} does not exist", path.display()));
+            }
+        }
+
+        Ok(Self { code, vars })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_locate() -> Result<()> {
+        let dir = tempdir()?;
+        let code = dir.path().join("OVMF_CODE.fd");
+        let vars = dir.path().join("OVMF_VARS.fd");
+        let mut machine = MachineConfiguration::default();
+
+        assert!(OVMF::locate_in(&machine, &[]).is_err());
+
+        std::fs::write(&code, b"code")?;
+        std::fs::write(&vars, b"vars")?;
+
+        let search = [(code.to_str().unwrap(), vars.to_str().unwrap())];
+        let ovmf = OVMF::locate_in(&machine, &search)?;
+        assert_eq!(ovmf.code, code);
+        assert_eq!(ovmf.vars, vars);
+
+        let other_vars = dir.path().join("OTHER_VARS.fd");
+        machine.ovmf_vars = Some(other_vars.clone());
+        assert!(OVMF::locate_in(&machine, &search).is_err());
+        std::fs::write(&other_vars, b"vars")?;
+        let ovmf = OVMF::locate_in(&machine, &search)?;
+        assert_eq!(ovmf.code, code);
+        assert_eq!(ovmf.vars, other_vars);
+
+        Ok(())
+    }
+}
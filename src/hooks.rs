@@ -0,0 +1,155 @@
+use crate::{traits::ConfigStorageHandler, util::path_exists, vm::VM};
+use anyhow::{anyhow, Result};
+use std::{path::PathBuf, process::Command, sync::Arc};
+
+/// The points in a VM's life hooks can run at. A hook is an executable named after its event,
+/// e.g. `hooks/pre-start`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    PreStart,
+    PostStart,
+    PreStop,
+    PostStop,
+    PreSnapshot,
+    PostSnapshot,
+}
+
+impl std::fmt::Display for HookEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::PreStart => "pre-start",
+            Self::PostStart => "post-start",
+            Self::PreStop => "pre-stop",
+            Self::PostStop => "post-stop",
+            Self::PreSnapshot => "pre-snapshot",
+            Self::PostSnapshot => "post-snapshot",
+        })
+    }
+}
+
+/// Where hooks shared by all VMs live.
+pub fn global_hooks_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("emu").join("hooks"))
+}
+
+/// Runs the global hook for an event, then the VM's own.
+#[derive(Debug, Clone)]
+pub struct Hooks {
+    config: Arc<Box<dyn ConfigStorageHandler>>,
+    global: Option<PathBuf>,
+}
+
+impl Hooks {
+    pub fn new(config: Arc<Box<dyn ConfigStorageHandler>>) -> Self {
+        Self {
+            config,
+            global: global_hooks_path(),
+        }
+    }
+
+    pub fn with_global(
+        config: Arc<Box<dyn ConfigStorageHandler>>,
+        global: Option<PathBuf>,
+    ) -> Self {
+        Self { config, global }
+    }
+
+    fn hooks(&self, vm: &VM, event: HookEvent) -> Vec<PathBuf> {
+        self.global
+            .iter()
+            .cloned()
+            .chain(std::iter::once(self.config.hooks_path(vm)))
+            .map(|dir| dir.join(event.to_string()))
+            .filter(|hook| path_exists(hook.clone()))
+            .collect()
+    }
+
+    /// Run the hooks for `event`, with `env` added to what every hook gets. The first hook that
+    /// fails stops the others and returns the error.
+    pub fn run(&self, vm: &VM, event: HookEvent, env: &[(&str, &str)]) -> Result<()> {
+        for hook in self.hooks(vm, event) {
+            let status = Command::new(&hook)
+                .env("EMU_EVENT", event.to_string())
+                .env("EMU_VM_NAME", vm.name())
+                .env("EMU_VM_ROOT", self.config.vm_root(vm))
+                .env("EMU_CONFIG_PATH", self.config.config_path(vm))
+                .env("EMU_MONITOR_PATH", self.config.monitor_path(vm))
+                .env("EMU_SERIAL_PATH", self.config.serial_path(vm))
+                .env("EMU_PIDFILE", self.config.pidfile(vm))
+                .envs(env.iter().copied())
+                .status()
+                .map_err(|e| anyhow!("could not run {} hook {}: {}", event, hook.display(), e))?;
+
+            if !status.success() {
+                return Err(anyhow!(
+                    "{} hook {} failed: {}",
+                    event,
+                    hook.display(),
+                    status
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run the hooks for an event that has already happened, where failing is no reason to stop.
+    pub fn notify(&self, vm: &VM, event: HookEvent, env: &[(&str, &str)]) {
+        if let Err(e) = self.run(vm, event, env) {
+            println!("{}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_storage::XDGConfigStorage;
+    use anyhow::Result;
+    use std::os::unix::fs::PermissionsExt;
+    use tempfile::tempdir;
+
+    fn write_hook(dir: &std::path::Path, event: HookEvent, script: &str) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(event.to_string());
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_hooks() -> Result<()> {
+        let dir = tempdir()?;
+        let storage: Arc<Box<dyn ConfigStorageHandler>> =
+            Arc::new(Box::new(XDGConfigStorage::new(dir.path().join("vms"))));
+        let global = dir.path().join("hooks");
+        let hooks = Hooks::with_global(storage.clone(), Some(global.clone()));
+
+        let vm = VM::new("vm1".to_string(), storage.clone());
+        storage.create(&vm)?;
+        let out = dir.path().join("out");
+
+        // nothing to run is fine
+        hooks.run(&vm, HookEvent::PreStart, &[])?;
+
+        write_hook(
+            &global,
+            HookEvent::PreStart,
+            &format!("echo global $EMU_VM_NAME >> {}", out.display()),
+        )?;
+        write_hook(
+            &storage.hooks_path(&vm),
+            HookEvent::PreStart,
+            &format!("echo $EMU_EVENT $EMU_SNAPSHOT >> {}", out.display()),
+        )?;
+        hooks.run(&vm, HookEvent::PreStart, &[("EMU_SNAPSHOT", "snap")])?;
+        assert_eq!(
+            std::fs::read_to_string(&out)?,
+            "global vm1\npre-start snap\n"
+        );
+
+        write_hook(&storage.hooks_path(&vm), HookEvent::PreStop, "exit 1")?;
+        assert!(hooks.run(&vm, HookEvent::PreStop, &[]).is_err());
+        Ok(())
+    }
+}
@@ -5,12 +5,13 @@ use kdam::{tqdm, BarExt};
 use std::{
     fs::remove_file,
     io::{Read, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::{Command, Stdio},
 };
 
 pub const QEMU_IMG_PATH: &str = "qemu-img";
 pub const QEMU_IMG_DEFAULT_FORMAT: &str = "qcow2";
+const QCOW2_MAGIC: &[u8] = b"QFI\xfb";
 
 pub fn qemu_img_name() -> String {
     format!(
@@ -23,6 +24,16 @@ pub fn qemu_img_name() -> String {
     )
 }
 
+/// Whether the image at `path` is qcow2, as opposed to raw, going by its header.
+pub fn is_qcow2(path: &Path) -> Result<bool> {
+    let mut magic = [0; 4];
+    match std::fs::File::open(path)?.read_exact(&mut magic) {
+        Ok(_) => Ok(magic == QCOW2_MAGIC),
+        // too short to be anything in particular.
+        Err(_) => Ok(false),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct QEmuImageHandler {
     format: String,
@@ -119,6 +130,33 @@ impl ImageHandler for QEmuImageHandler {
         newf.flush()?;
         Ok(())
     }
+
+    fn create_overlay(&self, base: PathBuf, overlay: PathBuf) -> Result<()> {
+        let base_format = if is_qcow2(&base)? { "qcow2" } else { "raw" };
+        let out = Command::new(QEMU_IMG_PATH)
+            .args(vec![
+                "create",
+                "-f",
+                &self.format,
+                "-b",
+                base.to_str().unwrap(),
+                "-F",
+                base_format,
+                overlay.to_str().unwrap(),
+            ])
+            .stdout(Stdio::null())
+            .output()?;
+
+        if !out.status.success() {
+            return Err(anyhow!(
+                "could not create an overlay of {}: {}",
+                base.display(),
+                String::from_utf8_lossy(&out.stderr).trim()
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 #[cfg(test)]
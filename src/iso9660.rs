@@ -0,0 +1,213 @@
+// a tiny ISO9660 writer. It only knows how to write a flat root directory of small files, which
+// is all a cloud-init seed needs. There are no Joliet or Rock Ridge extensions; Linux maps the
+// plain identifiers to lower case and strips the version suffix when mounting, which gives
+// cloud-init the filenames it expects.
+
+use anyhow::{anyhow, Result};
+use std::{io::Write, path::Path};
+
+const SECTOR_SIZE: usize = 2048;
+// sectors 0-15 are the system area, which is left blank.
+const PVD_SECTOR: usize = 16;
+const TERMINATOR_SECTOR: usize = 17;
+const L_PATH_TABLE_SECTOR: usize = 18;
+const M_PATH_TABLE_SECTOR: usize = 19;
+const ROOT_DIR_SECTOR: usize = 20;
+const FIRST_FILE_SECTOR: usize = 21;
+
+#[derive(Debug, Clone, Default)]
+pub struct Iso9660 {
+    volume_id: String,
+    files: Vec<(String, Vec<u8>)>,
+}
+
+fn both_u16(buf: &mut [u8], val: u16) {
+    buf[..2].copy_from_slice(&val.to_le_bytes());
+    buf[2..4].copy_from_slice(&val.to_be_bytes());
+}
+
+fn both_u32(buf: &mut [u8], val: u32) {
+    buf[..4].copy_from_slice(&val.to_le_bytes());
+    buf[4..8].copy_from_slice(&val.to_be_bytes());
+}
+
+fn padded(buf: &mut [u8], s: &str) {
+    buf.fill(b' ');
+    buf[..s.len()].copy_from_slice(s.as_bytes());
+}
+
+fn sectors(len: usize) -> usize {
+    len.div_ceil(SECTOR_SIZE).max(1)
+}
+
+fn dir_record(identifier: &[u8], extent: usize, len: usize, directory: bool) -> Vec<u8> {
+    let mut record_len = 33 + identifier.len();
+    if record_len % 2 == 1 {
+        record_len += 1;
+    }
+
+    let mut rec = vec![0_u8; record_len];
+    rec[0] = record_len as u8;
+    both_u32(&mut rec[2..10], extent as u32);
+    both_u32(&mut rec[10..18], len as u32);
+    // recording date is left zeroed (unspecified)
+    rec[25] = if directory { 2 } else { 0 };
+    both_u16(&mut rec[28..32], 1);
+    rec[32] = identifier.len() as u8;
+    rec[33..33 + identifier.len()].copy_from_slice(identifier);
+    rec
+}
+
+impl Iso9660 {
+    pub fn new(volume_id: &str) -> Self {
+        Self {
+            volume_id: volume_id.to_string(),
+            files: Vec::new(),
+        }
+    }
+
+    pub fn add_file(&mut self, name: &str, contents: Vec<u8>) -> Result<()> {
+        if name.is_empty() || name.len() > 30 || !name.is_ascii() || name.contains('/') {
+            return Err(anyhow!("invalid ISO9660 filename: {}", name));
+        }
+
+        self.files.push((name.to_string(), contents));
+        Ok(())
+    }
+
+    fn identifier(name: &str) -> Vec<u8> {
+        format!("{};1", name.to_uppercase()).into_bytes()
+    }
+
+    pub fn build(&self) -> Result<Vec<u8>> {
+        if self.volume_id.len() > 32 {
+            return Err(anyhow!("volume id is too long: {}", self.volume_id));
+        }
+
+        let mut files = self.files.clone();
+        files.sort_by(|a, b| Self::identifier(&a.0).cmp(&Self::identifier(&b.0)));
+
+        let mut extents = Vec::new();
+        let mut next = FIRST_FILE_SECTOR;
+        for (_, contents) in &files {
+            extents.push(next);
+            next += sectors(contents.len());
+        }
+        let total_sectors = next;
+
+        let mut root = Vec::new();
+        root.append(&mut dir_record(&[0], ROOT_DIR_SECTOR, SECTOR_SIZE, true));
+        root.append(&mut dir_record(&[1], ROOT_DIR_SECTOR, SECTOR_SIZE, true));
+        for (x, (name, contents)) in files.iter().enumerate() {
+            root.append(&mut dir_record(
+                &Self::identifier(name),
+                extents[x],
+                contents.len(),
+                false,
+            ));
+        }
+
+        if root.len() > SECTOR_SIZE {
+            return Err(anyhow!("too many files for the root directory"));
+        }
+
+        let mut image = vec![0_u8; total_sectors * SECTOR_SIZE];
+
+        let pvd = &mut image[PVD_SECTOR * SECTOR_SIZE..(PVD_SECTOR + 1) * SECTOR_SIZE];
+        pvd[0] = 1;
+        pvd[1..6].copy_from_slice(b"CD001");
+        pvd[6] = 1;
+        padded(&mut pvd[8..40], "");
+        padded(&mut pvd[40..72], &self.volume_id);
+        both_u32(&mut pvd[80..88], total_sectors as u32);
+        both_u16(&mut pvd[120..124], 1);
+        both_u16(&mut pvd[124..128], 1);
+        both_u16(&mut pvd[128..132], SECTOR_SIZE as u16);
+        // a path table with only the root directory in it is 10 bytes long
+        both_u32(&mut pvd[132..140], 10);
+        pvd[140..144].copy_from_slice(&(L_PATH_TABLE_SECTOR as u32).to_le_bytes());
+        pvd[148..152].copy_from_slice(&(M_PATH_TABLE_SECTOR as u32).to_be_bytes());
+        pvd[156..190].copy_from_slice(&dir_record(&[0], ROOT_DIR_SECTOR, SECTOR_SIZE, true));
+        padded(&mut pvd[190..813], "");
+        for date in [813, 830, 847, 864] {
+            pvd[date..date + 16].fill(b'0');
+        }
+        pvd[881] = 1;
+
+        let term =
+            &mut image[TERMINATOR_SECTOR * SECTOR_SIZE..(TERMINATOR_SECTOR + 1) * SECTOR_SIZE];
+        term[0] = 255;
+        term[1..6].copy_from_slice(b"CD001");
+        term[6] = 1;
+
+        for (sector, extent) in [
+            (L_PATH_TABLE_SECTOR, (ROOT_DIR_SECTOR as u32).to_le_bytes()),
+            (M_PATH_TABLE_SECTOR, (ROOT_DIR_SECTOR as u32).to_be_bytes()),
+        ] {
+            let table = &mut image[sector * SECTOR_SIZE..sector * SECTOR_SIZE + 10];
+            table[0] = 1;
+            table[2..6].copy_from_slice(&extent);
+            table[6..8].copy_from_slice(&if sector == L_PATH_TABLE_SECTOR {
+                1_u16.to_le_bytes()
+            } else {
+                1_u16.to_be_bytes()
+            });
+        }
+
+        let start = ROOT_DIR_SECTOR * SECTOR_SIZE;
+        image[start..start + root.len()].copy_from_slice(&root);
+
+        for (x, (_, contents)) in files.iter().enumerate() {
+            let start = extents[x] * SECTOR_SIZE;
+            image[start..start + contents.len()].copy_from_slice(contents);
+        }
+
+        Ok(image)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut f = std::fs::File::create(path)?;
+        f.write_all(&self.build()?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_build() -> Result<()> {
+        let mut iso = Iso9660::new("cidata");
+        iso.add_file("user-data", b"#cloud-config\n".to_vec())?;
+        iso.add_file("meta-data", b"instance-id: vm1\n".to_vec())?;
+        assert!(iso.add_file("a/b", Vec::new()).is_err());
+
+        let image = iso.build()?;
+        assert_eq!(image.len(), 23 * SECTOR_SIZE);
+
+        let pvd = &image[PVD_SECTOR * SECTOR_SIZE..];
+        assert_eq!(&pvd[1..6], b"CD001");
+        assert_eq!(&pvd[40..46], b"cidata");
+        assert_eq!(u32::from_le_bytes(pvd[80..84].try_into()?), 23);
+
+        // files are sorted by identifier; meta-data comes first
+        let root = &image[ROOT_DIR_SECTOR * SECTOR_SIZE..];
+        let mut offset = root[0] as usize + root[root[0] as usize] as usize;
+        let rec = &root[offset..];
+        assert_eq!(&rec[33..33 + rec[32] as usize], b"META-DATA;1");
+        let extent = u32::from_le_bytes(rec[2..6].try_into()?) as usize;
+        let len = u32::from_le_bytes(rec[10..14].try_into()?) as usize;
+        assert_eq!(
+            &image[extent * SECTOR_SIZE..extent * SECTOR_SIZE + len],
+            b"instance-id: vm1\n"
+        );
+
+        offset += rec[0] as usize;
+        let rec = &root[offset..];
+        assert_eq!(&rec[33..33 + rec[32] as usize], b"USER-DATA;1");
+
+        Ok(())
+    }
+}
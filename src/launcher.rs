@@ -1,24 +1,79 @@
 use super::{
-    config_storage::XDGConfigStorage,
+    boot::{self, BootIndexes, BootTarget},
+    cloud_init::CloudInit,
+    config::{DiskInterface, DisplayBackend, Firmware, VNC_BASE_PORT},
+    config_storage::{disk_id, XDGConfigStorage},
+    firmware::OVMF,
+    hooks::{HookEvent, Hooks},
     image::QEMU_IMG_DEFAULT_FORMAT,
+    logs::{self, OutputLogger, SerialLogger},
     qmp::messages::GenericReturn,
     traits::{ConfigStorageHandler, Launcher},
     vm::VM,
 };
-use crate::{qmp::client::Client, util::pid_running};
+use crate::{
+    accel::{self, Accelerator},
+    capabilities::Capabilities,
+    devices, numa,
+    qmp::client::Client,
+    shares::{self, Share, Virtiofsd},
+    template::{self, ExtraArgsData},
+    tpm::{self, Swtpm},
+    util::{path_exists, pid_running},
+};
 use anyhow::{anyhow, Result};
-use fork::{daemon, Fork};
+use fork::{daemon, fork, waitpid, Fork};
+use serde_json::json;
 use std::{
     fs::{read_to_string, remove_file},
     path::PathBuf,
     process::Command,
     process::ExitStatus,
+    process::Stdio,
     sync::Arc,
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-const QEMU_BIN_NAME: &str = "qemu-system-x86_64";
+pub const QEMU_BIN_NAME: &str = "qemu-system-x86_64";
+const MONITOR_TIMEOUT: Duration = Duration::from_secs(10);
+// how long a detached qemu must stay up for its launch to count as successful.
+const STARTUP_GRACE: Duration = Duration::from_secs(1);
+
+// options qemu only takes once (or where the last one silently wins), and the option emu
+// generates that each one collides with.
+const SINGLE_OPTIONS: &[(&str, &str)] = &[
+    ("-machine", "-machine"),
+    ("-M", "-machine"),
+    ("-accel", "-machine"),
+    ("-enable-kvm", "-machine"),
+    ("-m", "-m"),
+    ("-cpu", "-cpu"),
+    ("-smp", "-smp"),
+    ("-vga", "-vga"),
+    ("-bios", "-bios"),
+    ("-kernel", "-kernel"),
+    ("-initrd", "-initrd"),
+    ("-append", "-append"),
+    ("-display", "-display"),
+    ("-vnc", "-vnc"),
+    ("-spice", "-spice"),
+];
+
+/// The generated option an extra argument collides with, if any.
+fn conflicting_option(generated: &[String], arg: &str) -> Option<&'static str> {
+    // qemu accepts --option as well as -option.
+    let arg = arg
+        .strip_prefix('-')
+        .filter(|a| a.starts_with('-'))
+        .unwrap_or(arg);
+    SINGLE_OPTIONS
+        .iter()
+        .find(|(option, generated_option)| {
+            *option == arg && generated.iter().any(|g| g == generated_option)
+        })
+        .map(|(_, generated_option)| *generated_option)
+}
 
 macro_rules! append_vec {
     ( $v:expr, $( $x:expr ),* ) => {
@@ -44,6 +99,115 @@ macro_rules! into_vec {
     };
 }
 
+fn wait_started(config: &Arc<Box<dyn ConfigStorageHandler>>, vm: &VM, name: &str) -> Result<()> {
+    let pidfile = config.pidfile(vm);
+    let start = Instant::now();
+
+    loop {
+        if let Ok(Ok(pid)) = read_to_string(&pidfile).map(|s| s.trim().parse::<u32>()) {
+            sleep(STARTUP_GRACE);
+            if pid_running(pid) {
+                return Ok(());
+            }
+
+            return Err(anyhow!(
+                "{} exited right after starting{}",
+                name,
+                logs::exit_report(&config.qemu_log_path(vm))
+            ));
+        }
+
+        if start.elapsed() > MONITOR_TIMEOUT {
+            return Err(anyhow!(
+                "{} did not start{}",
+                name,
+                logs::exit_report(&config.qemu_log_path(vm))
+            ));
+        }
+
+        sleep(Duration::from_millis(50));
+    }
+}
+
+// daemon() closes stdin, stdout and stderr without reopening them, and the files the daemon
+// opens next would take their place: output meant for the terminal, of the daemon and of the
+// processes it starts, would end up in the serial and qemu logs.
+fn null_stdio() -> Result<()> {
+    // not with std, which opens files close-on-exec: /dev/null is likely to land on fd 0 itself.
+    let fd = unsafe { libc::open(c"/dev/null".as_ptr(), libc::O_RDWR) };
+    if fd < 0 {
+        return Err(anyhow!(
+            "could not open /dev/null: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    for target in 0..3 {
+        if fd != target && unsafe { libc::dup2(fd, target) } < 0 {
+            return Err(anyhow!(
+                "could not redirect fd {}: {}",
+                target,
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+
+    if fd > 2 {
+        unsafe { libc::close(fd) };
+    }
+
+    Ok(())
+}
+
+/// Run `f` in a daemon, which launches the hypervisor `name` and writes its pid to the pidfile.
+/// This returns once the hypervisor has stayed up for a moment, or with the last of its output
+/// if it did not. The overlays of an ephemeral run are removed once `f` returns.
+pub(crate) fn daemonize(
+    config: &Arc<Box<dyn ConfigStorageHandler>>,
+    vm: &VM,
+    name: &str,
+    f: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    // a stale pidfile would pass for the new process in wait_started.
+    let pidfile = config.pidfile(vm);
+    if path_exists(pidfile.clone()) {
+        remove_file(&pidfile)?;
+    }
+
+    match fork() {
+        Ok(Fork::Parent(child)) => {
+            // the child only daemonizes and exits; the hypervisor is started by its child.
+            waitpid(child).unwrap_or_default();
+            wait_started(config, vm, name)
+        }
+        Ok(Fork::Child) => {
+            // the runtime's threads do not survive a fork, and returning to main would wait on
+            // them forever while shutting it down; so these processes exit here instead.
+            match daemon(false, false) {
+                Ok(Fork::Child) => {
+                    if null_stdio().is_err() {
+                        std::process::exit(1)
+                    }
+                    let res = f();
+                    // an ephemeral run's overlays go with the hypervisor.
+                    if let Some(overlays) = vm.overlays() {
+                        std::fs::remove_dir_all(overlays).unwrap_or_default();
+                    }
+                    std::process::exit(if res.is_ok() { 0 } else { 1 })
+                }
+                Ok(Fork::Parent(_)) => std::process::exit(0),
+                Err(_) => std::process::exit(1),
+            }
+        }
+        Err(_) => Err(anyhow!("could not fork")),
+    }
+}
+
+// the `--cdrom` image, then the `--extra` one.
+fn cdroms(vm: &VM) -> Vec<PathBuf> {
+    vm.cdrom().into_iter().chain(vm.extra_disk()).collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct QEmuLauncher {
     config: Arc<Box<dyn ConfigStorageHandler>>,
@@ -58,6 +222,10 @@ impl Default for QEmuLauncher {
 }
 
 impl QEmuLauncher {
+    pub fn new(config: Arc<Box<dyn ConfigStorageHandler>>) -> Self {
+        Self { config }
+    }
+
     fn hostfwd_rules(&self, vm: &VM) -> Result<String> {
         let config = vm.config();
         let mut res = String::new();
@@ -68,47 +236,557 @@ impl QEmuLauncher {
         Ok(res)
     }
 
-    fn cdrom_rules(&self, v: &mut Vec<String>, disk: Option<PathBuf>, index: u8) -> Result<()> {
-        if let Some(cd) = disk {
-            match std::fs::metadata(&cd) {
-                Ok(_) => {
-                    append_vec!(
-                        v,
-                        "-drive",
-                        format!("file={},media=cdrom,index={}", cd.display(), index)
-                    );
+    // CD-ROMs get a device of their own, which can carry a boot index, and qemu finds them a
+    // free IDE unit.
+    fn cdrom_rules(&self, v: &mut Vec<String>, vm: &VM, boot: &BootIndexes) -> Result<()> {
+        for (x, cd) in cdroms(vm).into_iter().enumerate() {
+            if let Err(e) = std::fs::metadata(&cd) {
+                return Err(anyhow!("error locating cdrom file: {}", e));
+            }
+
+            let target = BootTarget::Cdrom(x);
+            let mut device = format!("ide-cd,drive=cdrom{},id={}", x, target.device_id());
+            if let Some(index) = boot.get(&target) {
+                device += &format!(",bootindex={}", index);
+            }
+
+            append_vec!(
+                v,
+                "-drive",
+                format!(
+                    "file={},media=cdrom,if=none,id=cdrom{},readonly=on",
+                    cd.display(),
+                    x
+                ),
+                "-device",
+                device
+            );
+        }
+
+        Ok(())
+    }
+
+    fn nic_rules(&self, v: &mut Vec<String>, vm: &VM, boot: &BootIndexes) -> Result<()> {
+        let hostfwd = self.hostfwd_rules(vm)?;
+        match boot.get(&BootTarget::Network) {
+            // the same card -nic adds, but as a device that can carry a boot index.
+            Some(index) => append_vec!(
+                v,
+                "-netdev",
+                format!("user,id=net0{}", hostfwd),
+                "-device",
+                format!(
+                    "e1000,netdev=net0,id={},bootindex={}",
+                    BootTarget::Network.device_id(),
+                    index
+                )
+            ),
+            None => append_vec!(v, "-nic", format!("user{}", hostfwd)),
+        }
+
+        Ok(())
+    }
+
+    /// The boot indexes of the VM's devices: for the first boot of this run, which
+    /// `--boot-once` may change, or for the boots after it.
+    fn boot_indexes(&self, vm: &VM, first_boot: bool) -> Result<BootIndexes> {
+        let config = vm.config();
+        let order = match vm.boot_once() {
+            Some(device) if first_boot => config.machine.boot_order_from(device),
+            _ => config.machine.boot_order.clone(),
+        };
+
+        let disks: Vec<(String, Option<u32>)> = self
+            .config
+            .disk_list(vm)?
+            .iter()
+            .map(|disk| {
+                let id = disk_id(disk);
+                let index = config.disks.get(&id).and_then(|d| d.bootindex);
+                (id, index)
+            })
+            .collect();
+
+        Ok(boot::indexes(&order, &disks, cdroms(vm).len()))
+    }
+
+    // firmware reads the boot order at reset, so the order for later boots can be set as soon
+    // as qemu is up.
+    fn restore_boot_order(&self, vm: &VM) -> Result<()> {
+        if vm.boot_once().is_none() {
+            return Ok(());
+        }
+
+        let first = self.boot_indexes(vm, true)?;
+        let later = self.boot_indexes(vm, false)?;
+        self.wait_for_monitor(vm)?;
+        self.qmp_command(vm, |mut c| {
+            // boot indexes must stay unique, so all of them are cleared before any is set.
+            for target in first.keys() {
+                c.set_boot_index(&target.device_id(), -1)?;
+            }
+            for (target, index) in &later {
+                c.set_boot_index(&target.device_id(), *index as i32)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn firmware_rules(&self, v: &mut Vec<String>, vm: &VM) -> Result<()> {
+        let config = vm.config();
+        if config.machine.firmware == Firmware::Uefi {
+            let ovmf = OVMF::locate(&config.machine)?;
+            append_vec!(
+                v,
+                "-drive",
+                format!(
+                    "if=pflash,format=raw,unit=0,readonly=on,file={}",
+                    ovmf.code.display()
+                ),
+                "-drive",
+                format!(
+                    "if=pflash,format=raw,unit=1,file={}",
+                    self.config.nvram_path(vm).display()
+                )
+            );
+        }
+
+        Ok(())
+    }
+
+    // the OVMF vars image is a template; each VM gets a writable copy on first boot so that boot
+    // entries and other EFI variables persist.
+    fn prepare_nvram(&self, vm: &VM) -> Result<()> {
+        let config = vm.config();
+        let nvram = self.config.nvram_path(vm);
+        if config.machine.firmware == Firmware::Uefi && !path_exists(nvram.clone()) {
+            let ovmf = OVMF::locate(&config.machine)?;
+            std::fs::copy(&ovmf.vars, &nvram).map_err(|e| {
+                anyhow!(
+                    "could not copy OVMF vars from {}: {}",
+                    ovmf.vars.display(),
+                    e
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // the seed image is rebuilt on every launch so that changes made with `emu cloud-init` are
+    // always picked up.
+    fn prepare_cloud_init(&self, vm: &VM) -> Result<()> {
+        let path = self.config.cloud_init_path(vm);
+        if path_exists(path.clone()) {
+            CloudInit::from_file(&path)?.write_seed(&vm.name(), &self.config.seed_path(vm))?;
+        }
+
+        Ok(())
+    }
+
+    // the seed may not have been generated yet; prepare_cloud_init takes care of that before
+    // launch.
+    fn cloud_init_rules(&self, v: &mut Vec<String>, vm: &VM) {
+        if path_exists(self.config.cloud_init_path(vm)) {
+            // no index: it would clash with whatever the disks or CD-ROMs were given.
+            append_vec!(
+                v,
+                "-drive",
+                format!(
+                    "if=none,id=seed,format=raw,readonly=on,file={}",
+                    self.config.seed_path(vm).display()
+                ),
+                "-device",
+                "ide-cd,drive=seed"
+            );
+        }
+    }
+
+    fn tpm_rules(&self, v: &mut Vec<String>, vm: &VM) {
+        let config = vm.config();
+        if config.machine.tpm {
+            v.append(&mut tpm::qemu_args(
+                &self.config.tpm_path(vm),
+                config.machine.firmware,
+            ));
+        }
+    }
+
+    fn start_tpm(&self, vm: &VM) -> Result<Option<Swtpm>> {
+        if vm.config().machine.tpm {
+            Ok(Some(Swtpm::start(self.config.tpm_path(vm))?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    // qemu writes everything that crosses the serial port into a fifo, regardless of whether
+    // anyone is attached to the console; the logger timestamps it into the VM's serial log.
+    fn start_serial_logger(&self, vm: &VM) -> Result<SerialLogger> {
+        SerialLogger::start(
+            self.config.serial_fifo_path(vm),
+            self.config.serial_log_path(vm),
+        )
+    }
+
+    // stops everything that runs alongside qemu, even when stopping one part fails; the first
+    // error is returned.
+    fn stop_helpers(&self, virtiofsd: Vec<Virtiofsd>, tpm: Option<Swtpm>) -> Result<()> {
+        let shares = shares::stop(virtiofsd);
+        let tpm = self.stop_tpm(tpm);
+        shares.and(tpm)
+    }
+
+    fn stop_tpm(&self, tpm: Option<Swtpm>) -> Result<()> {
+        if let Some(mut tpm) = tpm {
+            tpm.stop()?;
+        }
+
+        Ok(())
+    }
+
+    // qemu is probed at most once per upgrade; without it, launching fails soon enough anyway.
+    fn prepare_capabilities(&self, vm: &VM) -> Result<()> {
+        if let Ok(caps) = Capabilities::load() {
+            caps.valid(&vm.config().machine)?;
+        }
+
+        Ok(())
+    }
+
+    fn prepare_accel(&self, vm: &VM) -> Result<()> {
+        if let Err(e) = accel::kvm_usable() {
+            if vm.config().machine.require_kvm {
+                return Err(e);
+            }
+
+            println!("{}", e);
+            println!("Falling back to TCG emulation; {} will run slowly", vm);
+        }
+
+        Ok(())
+    }
+
+    fn memory_rules(&self, v: &mut Vec<String>, vm: &VM) -> Result<()> {
+        let config = vm.config();
+        let virtiofs = !config.shares.is_empty() && shares::locate_virtiofsd().is_some();
+        v.append(&mut numa::memory_args(&config.machine, virtiofs)?);
+        Ok(())
+    }
+
+    // vCPU threads only exist once qemu is up; their ids come from the monitor.
+    fn pin_vcpus(&self, vm: &VM) -> Result<()> {
+        let pinning = vm.config().machine.cpu_pinning;
+        if pinning.is_empty() {
+            return Ok(());
+        }
+
+        self.wait_for_monitor(vm)?;
+        self.qmp_command(vm, |mut c| {
+            for cpu in c.cpus()? {
+                if let Some(host) = pinning.get(cpu.cpu_index as usize) {
+                    numa::pin_thread(cpu.thread_id, *host)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn balloon_rules(&self, v: &mut Vec<String>, vm: &VM) {
+        let config = vm.config();
+        if config.machine.balloon {
+            let mut device = "virtio-balloon-pci,id=balloon0".to_string();
+            if config.machine.free_page_reporting {
+                device += ",free-page-reporting=on";
+            }
+            append_vec!(v, "-device", device);
+        }
+    }
+
+    fn device_rules(&self, v: &mut Vec<String>, vm: &VM) {
+        v.append(&mut devices::qemu_args(
+            &vm.config().devices,
+            &self.config.vm_root(vm),
+        ));
+    }
+
+    fn extra_args_rules(&self, v: &mut Vec<String>, vm: &VM) -> Result<()> {
+        let config = vm.config();
+        if config.extra_args.is_empty() {
+            return Ok(());
+        }
+
+        let data = ExtraArgsData::new(
+            vm,
+            &self.config.vm_root(vm),
+            &self.config.monitor_path(vm),
+            &self.config.serial_path(vm),
+        );
+        let mut extra = template::extra_args(&config.extra_args, &data)?;
+
+        for arg in &extra {
+            if let Some(option) = conflicting_option(v, arg) {
+                return Err(anyhow!(
+                    "extra argument {} conflicts with the {} emu already passes to qemu; use the matching setting instead",
+                    arg,
+                    option
+                ));
+            }
+        }
+
+        v.append(&mut extra);
+        Ok(())
+    }
+
+    fn share_rules(&self, v: &mut Vec<String>, vm: &VM) -> Result<()> {
+        let config = vm.config();
+        let list = Share::list(&config.shares);
+        for share in &list {
+            if !share.host.is_dir() {
+                return Err(anyhow!(
+                    "shared directory {} does not exist",
+                    share.host.display()
+                ));
+            }
+        }
+
+        v.append(&mut shares::qemu_args(
+            &list,
+            &self.config.shares_path(vm),
+            shares::locate_virtiofsd().is_some(),
+        ));
+
+        Ok(())
+    }
+
+    fn prepare_shares(&self, vm: &VM) -> Result<()> {
+        if !vm.config().shares.is_empty() {
+            std::fs::create_dir_all(self.config.shares_path(vm))?;
+            if shares::locate_virtiofsd().is_none() {
+                println!(
+                    "{} not found; sharing directories over 9p instead",
+                    shares::VIRTIOFSD_BIN_NAME
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn kernel_rules(&self, v: &mut Vec<String>, vm: &VM) -> Result<()> {
+        let config = vm.config();
+        if let Some(kernel) = config.machine.kernel {
+            for path in [Some(&kernel), config.machine.initrd.as_ref()]
+                .into_iter()
+                .flatten()
+            {
+                if let Err(e) = std::fs::metadata(path) {
+                    return Err(anyhow!("error locating {}: {}", path.display(), e));
                 }
-                Err(e) => return Err(anyhow!("error locating cdrom file: {}", e)),
+            }
+
+            append_vec!(v, "-kernel", kernel.display().to_string());
+
+            if let Some(initrd) = config.machine.initrd {
+                append_vec!(v, "-initrd", initrd.display().to_string());
+            }
+
+            if let Some(append) = config.machine.append {
+                append_vec!(v, "-append", append);
             }
         }
+
         Ok(())
     }
 
-    fn display_rule(&self, v: &mut Vec<String>, headless: bool) {
+    fn display_rule(&self, v: &mut Vec<String>, vm: &VM) {
+        let config = vm.config();
+        let display = config.machine.display;
+
         append_vec!(v, "-display");
-        if !headless {
+        if display == DisplayBackend::Gtk && !vm.headless() {
             append_vec!(v, "gtk");
         } else {
             append_vec!(v, "none");
         }
+
+        // with a password configured, the display refuses all connections until the password is
+        // set over QMP after launch.
+        let password = config.machine.display_password.is_some();
+        let path = self.config.display_path(vm);
+
+        match display {
+            DisplayBackend::Vnc => {
+                let mut opts = match config.machine.display_port {
+                    Some(port) => format!("127.0.0.1:{}", port - VNC_BASE_PORT),
+                    None => format!("unix:{}", path.display()),
+                };
+
+                if password {
+                    opts += ",password=on";
+                }
+
+                append_vec!(v, "-vnc", opts);
+            }
+            DisplayBackend::Spice => {
+                let mut opts = match config.machine.display_port {
+                    Some(port) => format!("port={},addr=127.0.0.1", port),
+                    None => format!("unix=on,addr={}", path.display()),
+                };
+
+                if !password {
+                    opts += ",disable-ticketing=on";
+                }
+
+                append_vec!(v, "-spice", opts);
+            }
+            _ => {}
+        }
     }
 
-    fn args(&self, vm: &VM) -> Result<Vec<String>> {
+    fn wait_for_monitor(&self, vm: &VM) -> Result<()> {
+        let start = Instant::now();
+        while Client::new(self.config.monitor_path(vm)).is_err() {
+            if start.elapsed() > MONITOR_TIMEOUT {
+                return Err(anyhow!("timed out waiting for {} to start", vm));
+            }
+
+            sleep(Duration::from_millis(100));
+        }
+
+        Ok(())
+    }
+
+    fn set_display_password(&self, vm: &VM) -> Result<()> {
         let config = vm.config();
-        let disk_list = self.config.disk_list(vm)?;
-        let mut disks = Vec::new();
-        for (x, disk) in disk_list.iter().enumerate() {
-            disks.push("-drive".to_string());
-            disks.push(format!(
-                "driver={},if={},file={},cache=none,media=disk,index={}",
-                QEMU_IMG_DEFAULT_FORMAT,
-                config.machine.image_interface,
-                disk.display(),
-                x
-            ));
+        if let Some(password) = config.machine.display_password {
+            self.wait_for_monitor(vm)?;
+            self.qmp_command(vm, |mut c| {
+                c.send_command::<GenericReturn>(
+                    "set_password",
+                    Some(json!({
+                        "protocol": config.machine.display.to_string(),
+                        "password": password,
+                    })),
+                )?;
+                Ok(())
+            })?;
+        }
+
+        Ok(())
+    }
+
+    fn disk_rules(&self, vm: &VM, boot: &BootIndexes) -> Result<Vec<String>> {
+        let config = vm.config();
+        let mut v = Vec::new();
+        let mut scsi = false;
+
+        for (x, disk) in self.config.disk_list(vm)?.iter().enumerate() {
+            let id = disk_id(disk);
+            let settings = config.disks.get(&id).cloned().unwrap_or_default();
+            let bootindex = boot.get(&BootTarget::Disk(id.clone()));
+
+            let mut opts = String::new();
+            if let Some(aio) = settings.aio {
+                opts += &format!(",aio={}", aio);
+            }
+            if settings.discard {
+                opts += ",discard=unmap";
+            }
+            if settings.detect_zeroes {
+                // zeroes can only be turned into holes when discards reach the image.
+                opts += if settings.discard {
+                    ",detect-zeroes=unmap"
+                } else {
+                    ",detect-zeroes=on"
+                };
+            }
+            if settings.read_only {
+                opts += ",readonly=on";
+            }
+
+            if settings.interface.is_none() && bootindex.is_none() {
+                append_vec!(
+                    v,
+                    "-drive",
+                    format!(
+                        "driver={},if={},file={},cache={}{},media=disk,index={}",
+                        QEMU_IMG_DEFAULT_FORMAT,
+                        config.machine.image_interface,
+                        disk.display(),
+                        settings.cache,
+                        opts,
+                        x
+                    )
+                );
+                continue;
+            }
+
+            // a disk with its own device; this is the only way to give it a boot index.
+            let interface = match settings.interface {
+                Some(interface) => interface,
+                None => DiskInterface::from_image_interface(&config.machine.image_interface)
+                    .ok_or_else(|| anyhow!("disk {} needs an interface to set a boot index", id))?,
+            };
+
+            let drive = format!("disk{}", id);
+            append_vec!(
+                v,
+                "-drive",
+                format!(
+                    "driver={},if=none,id={},file={},cache={}{}",
+                    QEMU_IMG_DEFAULT_FORMAT,
+                    drive,
+                    disk.display(),
+                    settings.cache,
+                    opts
+                )
+            );
+
+            let mut device = match interface {
+                DiskInterface::VirtioBlk => format!("virtio-blk-pci,drive={}", drive),
+                DiskInterface::VirtioScsi => {
+                    if !scsi {
+                        append_vec!(v, "-device", "virtio-scsi-pci,id=scsi0");
+                        scsi = true;
+                    }
+                    format!("scsi-hd,drive={},bus=scsi0.0", drive)
+                }
+                DiskInterface::Nvme => format!("nvme,drive={},serial={}", drive, drive),
+                DiskInterface::Ide => format!("ide-hd,drive={}", drive),
+            };
+
+            device += &format!(",id={}", BootTarget::Disk(id.clone()).device_id());
+            if let Some(bootindex) = bootindex {
+                device += &format!(",bootindex={}", bootindex);
+            }
+
+            append_vec!(v, "-device", device);
         }
 
+        Ok(v)
+    }
+
+    fn args(&self, vm: &VM) -> Result<Vec<String>> {
+        let config = vm.config();
+        let boot = self.boot_indexes(vm, true)?;
+        let mut disks = self.disk_rules(vm, &boot)?;
+
         let mon = self.config.monitor_path(vm);
+        let serial = self.config.serial_path(vm);
+        let accel = accel::select(config.machine.require_kvm)?;
+
+        // the host CPU model is only available under KVM; "max" is TCG's closest equivalent.
+        let mut cpu = vec![
+            if accel == Accelerator::Tcg && config.machine.cpu_type == "host" {
+                "max".to_string()
+            } else {
+                config.machine.cpu_type.clone()
+            },
+        ];
+        cpu.append(&mut config.machine.cpu_properties());
+        let (sockets, cores, threads) = config.machine.topology();
+        let maxcpus = config.machine.maxcpus();
 
         let mut v: Vec<String> = into_vec![
             "-nodefaults",
@@ -116,28 +794,45 @@ impl QEmuLauncher {
             format!("socket,server=on,wait=off,id=char0,path={}", mon.display()),
             "-mon",
             "chardev=char0,mode=control,pretty=on",
+            "-chardev",
+            format!(
+                "socket,server=on,wait=off,id=serial0,path={},logfile={},logappend=on",
+                serial.display(),
+                self.config.serial_fifo_path(vm).display()
+            ),
+            "-serial",
+            "chardev:serial0",
             "-machine",
-            "accel=kvm",
+            format!("accel={}", accel),
             "-vga",
             config.machine.vga,
             "-m",
             format!("{}M", config.machine.memory),
             "-cpu",
-            config.machine.cpu_type,
+            cpu.join(","),
             "-smp",
             format!(
-                "cpus={},cores={},maxcpus={}",
-                config.machine.cpus, config.machine.cpus, config.machine.cpus
-            ),
-            "-nic",
-            format!("user{}", self.hostfwd_rules(vm)?)
+                "cpus={},sockets={},cores={},threads={},maxcpus={}",
+                config.machine.cpus, sockets, cores, threads, maxcpus
+            )
         ];
 
+        self.nic_rules(&mut v, vm, &boot)?;
+
+        self.firmware_rules(&mut v, vm)?;
+        self.tpm_rules(&mut v, vm);
+        self.kernel_rules(&mut v, vm)?;
+        self.memory_rules(&mut v, vm)?;
+        self.balloon_rules(&mut v, vm);
+        self.device_rules(&mut v, vm);
+        self.share_rules(&mut v, vm)?;
         v.append(&mut disks);
 
-        self.display_rule(&mut v, vm.headless());
-        self.cdrom_rules(&mut v, vm.cdrom(), (disks.len() + 2) as u8)?;
-        self.cdrom_rules(&mut v, vm.extra_disk(), (disks.len() + 3) as u8)?;
+        self.display_rule(&mut v, vm);
+        self.cdrom_rules(&mut v, vm, &boot)?;
+        v.append(&mut boot::menu_args(&vm.config().machine));
+        self.cloud_init_rules(&mut v, vm);
+        self.extra_args_rules(&mut v, vm)?;
 
         Ok(v)
     }
@@ -157,6 +852,12 @@ impl QEmuLauncher {
 }
 
 impl Launcher for QEmuLauncher {
+    fn command_line(&self, vm: &VM) -> Result<Vec<String>> {
+        let mut v = vec![QEMU_BIN_NAME.to_string()];
+        v.append(&mut self.args(vm)?);
+        Ok(v)
+    }
+
     fn delete_snapshot(&self, vm: &VM, name: String) -> Result<()> {
         self.qmp_command(vm, |mut c| c.snapshot_delete(&name))?;
         println!("Deleted snapshot '{}'", name);
@@ -175,6 +876,19 @@ impl Launcher for QEmuLauncher {
         Ok(())
     }
 
+    fn balloon(&self, vm: &VM, megabytes: u32) -> Result<()> {
+        self.qmp_command(vm, |mut c| c.balloon(megabytes as u64 * 1024 * 1024))
+    }
+
+    fn balloon_size(&self, vm: &VM) -> Result<u32> {
+        let mut size = 0;
+        self.qmp_command(vm, |mut c| {
+            size = (c.balloon_info()?.actual / 1024 / 1024) as u32;
+            Ok(())
+        })?;
+        Ok(size)
+    }
+
     fn reset(&self, vm: &VM) -> Result<()> {
         self.qmp_command(vm, |mut c| {
             c.send_command::<GenericReturn>("system_reset", None)?;
@@ -210,28 +924,382 @@ impl Launcher for QEmuLauncher {
     }
 
     fn launch_attached(&self, vm: &VM) -> Result<ExitStatus> {
+        self.prepare_capabilities(vm)?;
+        self.prepare_accel(vm)?;
+        self.prepare_nvram(vm)?;
+        self.prepare_cloud_init(vm)?;
+        self.prepare_shares(vm)?;
         let args = self.args(vm)?;
+        // the loggers go first: nothing has to be stopped if they cannot start.
+        let logger = self.start_serial_logger(vm)?;
+        let mut output = OutputLogger::start(self.config.qemu_log_path(vm), "qemu")?;
+        let tpm = match self.start_tpm(vm) {
+            Ok(tpm) => tpm,
+            Err(e) => {
+                output.finish(&format!("could not start: {}", e));
+                return Err(e);
+            }
+        };
+        let virtiofsd = match shares::start(&vm.config().shares, &self.config.shares_path(vm)) {
+            Ok(v) => v,
+            Err(e) => {
+                output.finish(&format!("could not start: {}", e));
+                self.stop_tpm(tpm).unwrap_or_default();
+                return Err(e);
+            }
+        };
+        let hooks = Hooks::new(self.config.clone());
         let mut cmd = Command::new(QEMU_BIN_NAME);
-        Ok(cmd.args(args).spawn()?.wait()?)
+        let status = cmd
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .and_then(|mut child| {
+                output.capture(&mut child);
+                if let Err(e) = self.set_display_password(vm) {
+                    println!("could not set display password: {}", e);
+                }
+                if let Err(e) = self.pin_vcpus(vm) {
+                    println!("could not pin vCPUs: {}", e);
+                }
+                if let Err(e) = self.restore_boot_order(vm) {
+                    println!("could not restore the boot order: {}", e);
+                }
+                hooks.notify(vm, HookEvent::PostStart, &[]);
+                child.wait()
+            });
+        match &status {
+            Ok(status) => output.finish(&format!("exited with {}", status)),
+            Err(e) => output.finish(&format!("could not start: {}", e)),
+        }
+        logger.finish();
+        let stopped = self.stop_helpers(virtiofsd, tpm);
+        if status.is_ok() {
+            hooks.notify(vm, HookEvent::PostStop, &[]);
+        }
+        let status = status?;
+        stopped?;
+        Ok(status)
     }
 
     fn launch_detached(&self, vm: &VM) -> Result<()> {
+        self.prepare_capabilities(vm)?;
+        self.prepare_accel(vm)?;
+        self.prepare_nvram(vm)?;
+        self.prepare_cloud_init(vm)?;
+        self.prepare_shares(vm)?;
         let args = self.args(vm)?;
-        let mut cmd = Command::new(QEMU_BIN_NAME);
-        if let Ok(Fork::Child) = daemon(false, false) {
-            match cmd.args(args).spawn() {
+        if vm.config().machine.tpm {
+            Swtpm::check()?;
+        }
+
+        daemonize(&self.config, vm, "qemu", || {
+            let logger = self.start_serial_logger(vm)?;
+            let mut output = OutputLogger::start(self.config.qemu_log_path(vm), "qemu")?;
+            let tpm = match self.start_tpm(vm) {
+                Ok(tpm) => tpm,
+                Err(e) => {
+                    output.finish(&format!("could not start: {}", e));
+                    return Err(e);
+                }
+            };
+            let virtiofsd = match shares::start(&vm.config().shares, &self.config.shares_path(vm)) {
+                Ok(v) => v,
+                Err(e) => {
+                    output.finish(&format!("could not start: {}", e));
+                    self.stop_tpm(tpm).unwrap_or_default();
+                    return Err(e);
+                }
+            };
+            let hooks = Hooks::new(self.config.clone());
+            match Command::new(QEMU_BIN_NAME)
+                .args(args)
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+            {
                 Ok(mut child) => {
+                    output.capture(&mut child);
                     std::fs::write(
-                        &self.config.pidfile(vm),
+                        self.config.pidfile(vm),
                         format!("{}", child.id()).as_bytes(),
                     )?;
-                    child.wait()?;
-                    Ok(())
+                    if let Err(e) = self.set_display_password(vm) {
+                        println!("could not set display password: {}", e);
+                    }
+                    if let Err(e) = self.pin_vcpus(vm) {
+                        println!("could not pin vCPUs: {}", e);
+                    }
+                    if let Err(e) = self.restore_boot_order(vm) {
+                        println!("could not restore the boot order: {}", e);
+                    }
+                    hooks.notify(vm, HookEvent::PostStart, &[]);
+                    let status = child.wait();
+                    match &status {
+                        Ok(status) => output.finish(&format!("exited with {}", status)),
+                        Err(e) => output.finish(&format!("could not wait for qemu: {}", e)),
+                    }
+                    logger.finish();
+                    let stopped = self.stop_helpers(virtiofsd, tpm);
+                    if status.is_ok() {
+                        hooks.notify(vm, HookEvent::PostStop, &[]);
+                    }
+                    status?;
+                    stopped
+                }
+                Err(e) => {
+                    output.finish(&format!("could not start: {}", e));
+                    self.stop_helpers(virtiofsd, tpm).unwrap_or_default();
+                    Err(anyhow!(e))
                 }
-                Err(e) => Err(anyhow!(e)),
             }
-        } else {
-            return Err(anyhow!("could not fork"));
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{BootDevice, Configuration, DiskConfiguration},
+        util::shell_quote,
+    };
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    // `{root}` is the VM's directory and `{accel}` whatever this host supports.
+    const GOLDEN_ARGS: &str = "qemu-system-x86_64 -nodefaults \
+        -chardev socket,server=on,wait=off,id=char0,path={root}/mon \
+        -mon chardev=char0,mode=control,pretty=on \
+        -chardev socket,server=on,wait=off,id=serial0,path={root}/serial,logfile={root}/serial.fifo,logappend=on \
+        -serial chardev:serial0 -machine accel={accel} -vga virtio -m 2048M -cpu qemu64,vmx=on \
+        -smp cpus=2,sockets=1,cores=2,threads=2,maxcpus=4 -nic user,hostfwd=tcp:127.0.0.1:2222-:22 \
+        -kernel /boot/vmlinuz -append 'console=ttyS0 quiet' \
+        -drive driver=qcow2,if=virtio,file={root}/qemu-0.qcow2,cache=none,media=disk,index=0 \
+        -display none -vnc unix:{root}/display";
+
+    #[test]
+    fn test_command_line() -> Result<()> {
+        let dir = tempdir()?;
+        let storage: Arc<Box<dyn ConfigStorageHandler>> =
+            Arc::new(Box::new(XDGConfigStorage::new(dir.path().to_path_buf())));
+        let launcher = QEmuLauncher::new(storage.clone());
+
+        let mut vm = VM::new("vm1".to_string(), storage.clone());
+        storage.create(&vm)?;
+        std::fs::write(storage.vm_root(&vm).join("qemu-0.qcow2"), "")?;
+        std::fs::write(dir.path().join("vmlinuz"), "")?;
+
+        let mut config = Configuration::default();
+        config.machine.memory = 2048;
+        config.machine.cpus = 2;
+        config.machine.cpu_type = "qemu64".to_string();
+        config.machine.threads = Some(2);
+        config.machine.maxcpus = Some(4);
+        config.machine.cpu_flags = vec!["+vmx".to_string()];
+        config.machine.display = DisplayBackend::Vnc;
+        config.machine.kernel = Some(dir.path().join("vmlinuz"));
+        config.machine.append = Some("console=ttyS0 quiet".to_string());
+        config.map_port(2222, 22);
+        vm.set_config(config);
+        vm.set_headless(true);
+
+        let expected = GOLDEN_ARGS
+            .replace(
+                "/boot/vmlinuz",
+                &dir.path().join("vmlinuz").display().to_string(),
+            )
+            .replace("{root}", &storage.vm_root(&vm).display().to_string())
+            .replace("{accel}", &accel::select(false)?.to_string());
+
+        let argv = launcher.command_line(&vm)?;
+        assert_eq!(
+            argv.iter()
+                .map(|arg| shell_quote(arg))
+                .collect::<Vec<String>>()
+                .join(" "),
+            expected
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extra_args_rules() -> Result<()> {
+        let dir = tempdir()?;
+        let storage: Arc<Box<dyn ConfigStorageHandler>> =
+            Arc::new(Box::new(XDGConfigStorage::new(dir.path().to_path_buf())));
+        let launcher = QEmuLauncher::new(storage.clone());
+
+        let mut vm = VM::new("vm1".to_string(), storage.clone());
+        storage.create(&vm)?;
+
+        let mut config = Configuration::default();
+        config.set_machine_value("extra-args", "-device pvpanic -name {vm_name}")?;
+        vm.set_config(config.clone());
+        let mut v = vec!["-m".to_string(), "1024M".to_string()];
+        launcher.extra_args_rules(&mut v, &vm)?;
+        assert_eq!(v, vec!["-m", "1024M", "-device", "pvpanic", "-name", "vm1"]);
+
+        config.set_machine_value("extra-args", "--m 2048M")?;
+        vm.set_config(config.clone());
+        assert!(launcher.extra_args_rules(&mut v, &vm).is_err());
+        config.set_machine_value("extra-args", "-M q35")?;
+        vm.set_config(config);
+        assert!(launcher
+            .args(&vm)
+            .unwrap_err()
+            .to_string()
+            .contains("conflicts with the -machine"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_cloud_init_rules() -> Result<()> {
+        let dir = tempdir()?;
+        let storage: Arc<Box<dyn ConfigStorageHandler>> =
+            Arc::new(Box::new(XDGConfigStorage::new(dir.path().to_path_buf())));
+        let launcher = QEmuLauncher::new(storage.clone());
+
+        let vm = VM::new("vm1".to_string(), storage.clone());
+        storage.create(&vm)?;
+
+        let mut v = Vec::new();
+        launcher.cloud_init_rules(&mut v, &vm);
+        assert!(v.is_empty());
+
+        std::fs::write(storage.cloud_init_path(&vm), "")?;
+        launcher.cloud_init_rules(&mut v, &vm);
+        assert_eq!(
+            v,
+            vec![
+                "-drive".to_string(),
+                format!(
+                    "if=none,id=seed,format=raw,readonly=on,file={}",
+                    storage.seed_path(&vm).display()
+                ),
+                "-device".to_string(),
+                "ide-cd,drive=seed".to_string(),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_disk_rules() -> Result<()> {
+        let dir = tempdir()?;
+        let storage: Arc<Box<dyn ConfigStorageHandler>> =
+            Arc::new(Box::new(XDGConfigStorage::new(dir.path().to_path_buf())));
+        let launcher = QEmuLauncher::new(storage.clone());
+
+        let mut vm = VM::new("vm1".to_string(), storage.clone());
+        storage.create(&vm)?;
+        for x in 0..3 {
+            std::fs::write(storage.vm_root(&vm).join(format!("qemu-{}.qcow2", x)), "")?;
         }
+
+        let mut config = Configuration::default();
+        config.set_disk_value(0, "discard", "true")?;
+        config.set_disk_value(0, "detect-zeroes", "true")?;
+        config.set_disk_value(1, "interface", "virtio-scsi")?;
+        config.set_disk_value(1, "aio", "native")?;
+        config.set_disk_value(1, "bootindex", "0")?;
+        config.disks.insert(
+            "2".to_string(),
+            DiskConfiguration {
+                read_only: true,
+                bootindex: Some(1),
+                ..Default::default()
+            },
+        );
+        vm.set_config(config);
+
+        let root = storage.vm_root(&vm);
+        assert_eq!(
+            launcher.disk_rules(&vm, &launcher.boot_indexes(&vm, true)?)?,
+            vec![
+                "-drive".to_string(),
+                format!(
+                    "driver=qcow2,if=virtio,file={}/qemu-0.qcow2,cache=none,discard=unmap,detect-zeroes=unmap,media=disk,index=0",
+                    root.display()
+                ),
+                "-drive".to_string(),
+                format!(
+                    "driver=qcow2,if=none,id=disk1,file={}/qemu-1.qcow2,cache=none,aio=native",
+                    root.display()
+                ),
+                "-device".to_string(),
+                "virtio-scsi-pci,id=scsi0".to_string(),
+                "-device".to_string(),
+                "scsi-hd,drive=disk1,bus=scsi0.0,id=disk1-dev,bootindex=0".to_string(),
+                "-drive".to_string(),
+                format!(
+                    "driver=qcow2,if=none,id=disk2,file={}/qemu-2.qcow2,cache=none,readonly=on",
+                    root.display()
+                ),
+                "-device".to_string(),
+                "virtio-blk-pci,drive=disk2,id=disk2-dev,bootindex=1".to_string(),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_boot_rules() -> Result<()> {
+        let dir = tempdir()?;
+        let storage: Arc<Box<dyn ConfigStorageHandler>> =
+            Arc::new(Box::new(XDGConfigStorage::new(dir.path().to_path_buf())));
+        let launcher = QEmuLauncher::new(storage.clone());
+
+        let mut vm = VM::new("vm1".to_string(), storage.clone());
+        storage.create(&vm)?;
+        std::fs::write(storage.vm_root(&vm).join("qemu-0.qcow2"), "")?;
+        let iso = dir.path().join("install.iso");
+        std::fs::write(&iso, "")?;
+        vm.set_cdrom(iso.clone());
+
+        let mut config = Configuration::default();
+        config.machine.boot_order = vec![BootDevice::Disk, BootDevice::Network];
+        vm.set_config(config);
+        vm.set_boot_once(BootDevice::Cdrom);
+
+        let first = launcher.boot_indexes(&vm, true)?;
+        let mut v = Vec::new();
+        launcher.cdrom_rules(&mut v, &vm, &first)?;
+        launcher.nic_rules(&mut v, &vm, &first)?;
+        assert_eq!(
+            v,
+            vec![
+                "-drive".to_string(),
+                format!(
+                    "file={},media=cdrom,if=none,id=cdrom0,readonly=on",
+                    iso.display()
+                ),
+                "-device".to_string(),
+                "ide-cd,drive=cdrom0,id=cdrom0-dev,bootindex=0".to_string(),
+                "-netdev".to_string(),
+                "user,id=net0".to_string(),
+                "-device".to_string(),
+                "e1000,netdev=net0,id=nic0,bootindex=2".to_string(),
+            ]
+        );
+        assert_eq!(first[&BootTarget::Disk("0".to_string())], 1);
+
+        // after the first boot, the CD-ROM is out of the order.
+        let later = launcher.boot_indexes(&vm, false)?;
+        assert_eq!(
+            later,
+            [
+                (BootTarget::Disk("0".to_string()), 0),
+                (BootTarget::Network, 1)
+            ]
+            .into_iter()
+            .collect()
+        );
+
+        Ok(())
     }
 }
@@ -1,24 +1,51 @@
+pub mod accel;
+pub mod boot;
+pub mod capabilities;
+pub mod cloud_hypervisor;
+pub mod cloud_init;
 pub mod command;
 pub mod command_handler;
 pub mod config;
 pub mod config_storage;
+pub mod console;
+pub mod devices;
+pub mod firecracker;
+pub mod firmware;
+pub mod hooks;
 pub mod image;
+pub mod iso9660;
 pub mod launcher;
+pub mod logs;
 #[allow(dead_code)]
 pub mod network;
+pub mod numa;
 pub mod qmp;
+pub mod rest;
+pub mod shares;
+pub mod snapshot;
 pub mod supervisor;
+pub mod tap;
 pub mod template;
+pub mod tpm;
 pub mod traits;
 pub mod util;
 pub mod vm;
+pub mod wait;
+pub mod web_console;
 
 use self::{
-    command::{CommandType, Commands, ConfigPortSubcommand, ConfigSubcommand, SnapshotSubcommand},
+    cloud_init::CloudInit,
+    command::{
+        CommandType, Commands, ConfigDeviceSubcommand, ConfigDiskSubcommand, ConfigNumaSubcommand,
+        ConfigPortSubcommand, ConfigShareSubcommand, ConfigSubcommand, SnapshotSubcommand,
+    },
     command_handler::CommandHandler,
+    util::parse_duration,
+    wait::Readiness,
 };
 use anyhow::Result;
 use clap::Parser;
+use regex::Regex;
 
 pub async fn evaluate() -> Result<()> {
     let handler = CommandHandler::default();
@@ -60,9 +87,105 @@ pub async fn evaluate() -> Result<()> {
                     handler.port_unmap(&name.into(), hostport)
                 }
             },
+            ConfigSubcommand::Disk(sub) => match sub {
+                ConfigDiskSubcommand::Set {
+                    name,
+                    disk,
+                    key,
+                    value,
+                } => handler.disk_set(&name.into(), disk, key, value),
+                ConfigDiskSubcommand::Reset { name, disk } => {
+                    handler.disk_reset(&name.into(), disk)
+                }
+            },
+            ConfigSubcommand::Device(sub) => match sub {
+                ConfigDeviceSubcommand::Add { name, device } => {
+                    handler.device_add(&name.into(), device)
+                }
+                ConfigDeviceSubcommand::Remove { name, device } => {
+                    handler.device_remove(&name.into(), device)
+                }
+            },
+            ConfigSubcommand::Numa(sub) => match sub {
+                ConfigNumaSubcommand::Add {
+                    name,
+                    cpus,
+                    memory,
+                    host_node,
+                } => handler.numa_add(&name.into(), cpus, memory, host_node),
+                ConfigNumaSubcommand::Clear { name } => handler.numa_clear(&name.into()),
+            },
+            ConfigSubcommand::Share(sub) => match sub {
+                ConfigShareSubcommand::Add {
+                    name,
+                    host_path,
+                    tag,
+                } => handler.share_add(&name.into(), host_path, tag),
+                ConfigShareSubcommand::Remove { name, host_path } => {
+                    handler.share_remove(&name.into(), host_path)
+                }
+            },
         },
+        CommandType::CloudInit {
+            hostname,
+            ssh_keys,
+            user_data,
+            meta_data,
+            network_config,
+            clear,
+            name,
+        } => {
+            if clear {
+                handler.cloud_init_clear(&name.into())
+            } else if hostname.is_none()
+                && ssh_keys.is_empty()
+                && user_data.is_none()
+                && meta_data.is_none()
+                && network_config.is_none()
+            {
+                handler.cloud_init_show(&name.into())
+            } else {
+                let mut keys = Vec::new();
+                for key in ssh_keys {
+                    // keys may be given literally or as a path to a public key file
+                    if std::path::Path::new(&key).is_file() {
+                        keys.push(std::fs::read_to_string(key)?.trim().to_string());
+                    } else {
+                        keys.push(key);
+                    }
+                }
+
+                let read = |path: Option<std::path::PathBuf>| -> Result<Option<String>> {
+                    Ok(match path {
+                        Some(path) => Some(std::fs::read_to_string(path)?),
+                        None => None,
+                    })
+                };
+
+                handler.cloud_init_set(
+                    &name.into(),
+                    CloudInit {
+                        hostname,
+                        ssh_authorized_keys: keys,
+                        user_data: read(user_data)?,
+                        meta_data: read(meta_data)?,
+                        network_config: read(network_config)?,
+                    },
+                )
+            }
+        }
         CommandType::ListDisks { name } => handler.list_disks(&name.into()),
         CommandType::NC { name, port } => handler.nc(&name.into(), port).await,
+        CommandType::Console { name } => handler.console(&name.into()).await,
+        CommandType::Balloon { name, size } => handler.balloon(&name.into(), size),
+        CommandType::Args { json, name } => handler.args(&name.into(), json),
+        CommandType::Display { name } => handler.display(&name.into()),
+        CommandType::WebConsole { port, name } => handler.web_console(&name.into(), port).await,
+        CommandType::Logs {
+            follow,
+            since,
+            name,
+        } => handler.logs(&name.into(), follow, since),
         CommandType::SSH { name, args } => handler.ssh(&name.into(), args),
         CommandType::Create { append, name, size } => handler.create(&name.into(), size, append),
         CommandType::Rename { old, new } => handler.rename(&old.into(), &new.into()),
@@ -80,6 +203,12 @@ pub async fn evaluate() -> Result<()> {
             detach,
             cdrom,
             extra_disk,
+            kernel,
+            initrd,
+            append,
+            dry_run,
+            ephemeral,
+            boot_once,
             name,
         } => {
             let mut vm: vm::VM = name.into();
@@ -90,11 +219,54 @@ pub async fn evaluate() -> Result<()> {
             if let Some(extra_disk) = extra_disk {
                 vm.set_extra_disk(extra_disk)
             }
+            if let Some(device) = boot_once {
+                vm.set_boot_once(device)
+            }
 
-            handler.run(&vm, detach)
+            // kernel flags override the configuration for this run only
+            let mut config = vm.config();
+            if kernel.is_some() {
+                config.machine.kernel = kernel;
+            }
+            if initrd.is_some() {
+                config.machine.initrd = initrd;
+            }
+            if append.is_some() {
+                config.machine.append = append;
+            }
+            vm.set_config(config);
+
+            if dry_run {
+                if ephemeral {
+                    vm.set_overlays(handler.overlays_path(&vm));
+                }
+                handler.args(&vm, false)
+            } else {
+                handler.run(&vm, detach, ephemeral)
+            }
         }
         CommandType::List { running } => handler.list(running),
         CommandType::Shutdown { name, nowait } => handler.shutdown(&name.into(), nowait),
+        CommandType::Wait {
+            ssh: _,
+            port,
+            agent,
+            serial_regex,
+            timeout,
+            name,
+        } => {
+            let readiness = if let Some(port) = port {
+                Readiness::Port(port)
+            } else if agent {
+                Readiness::Agent
+            } else if let Some(re) = serial_regex {
+                Readiness::Serial(Regex::new(&re)?)
+            } else {
+                Readiness::Ssh
+            };
+
+            handler.wait(&name.into(), readiness, parse_duration(&timeout)?)
+        }
         CommandType::QMP {
             name,
             command,
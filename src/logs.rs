@@ -0,0 +1,450 @@
+use crate::util::{format_timestamp, parse_timestamp, path_exists};
+use anyhow::{anyhow, Result};
+use std::{
+    ffi::CString,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    process::Child,
+    sync::{
+        mpsc::{channel, Receiver},
+        Arc, Mutex,
+    },
+    thread::sleep,
+    time::{Duration, SystemTime},
+};
+
+const MAX_LOG_SIZE: u64 = 10 * 1024 * 1024;
+const MAX_LOG_FILES: usize = 5;
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+const LOGGER_FINISH_TIMEOUT: Duration = Duration::from_secs(1);
+const STARTED: &str = "started";
+const EXIT_REPORT_LINES: usize = 10;
+
+/// A log file that is rotated to `<name>.1` through `<name>.N` once it grows too large. Each
+/// line is prefixed with the time it was written.
+#[derive(Debug)]
+pub struct RotatingLog {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut s = path.as_os_str().to_owned();
+    s.push(format!(".{}", index));
+    PathBuf::from(s)
+}
+
+/// All existing files for the log at `path`, oldest first.
+pub fn log_files(path: &Path) -> Vec<PathBuf> {
+    let mut v = Vec::new();
+    for x in (1..MAX_LOG_FILES).rev() {
+        let rotated = rotated_path(path, x);
+        if path_exists(rotated.clone()) {
+            v.push(rotated);
+        }
+    }
+
+    if path_exists(path.to_path_buf()) {
+        v.push(path.to_path_buf());
+    }
+
+    v
+}
+
+impl RotatingLog {
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self { path, file, size })
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        for x in (1..MAX_LOG_FILES - 1).rev() {
+            let from = rotated_path(&self.path, x);
+            if path_exists(from.clone()) {
+                std::fs::rename(from, rotated_path(&self.path, x + 1))?;
+            }
+        }
+
+        std::fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        *self = Self::open(self.path.clone())?;
+        Ok(())
+    }
+
+    pub fn write_line(&mut self, time: SystemTime, line: &str) -> Result<()> {
+        if self.size >= MAX_LOG_SIZE {
+            self.rotate()?;
+        }
+
+        let line = format!("{} {}\n", format_timestamp(time), line);
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn mkfifo(path: &Path) -> Result<()> {
+    if path_exists(path.to_path_buf()) {
+        std::fs::remove_file(path)?;
+    }
+
+    let cpath = CString::new(path.as_os_str().as_bytes())?;
+    if unsafe { libc::mkfifo(cpath.as_ptr(), 0o600) } != 0 {
+        return Err(anyhow!(
+            "could not create fifo {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    Ok(())
+}
+
+// calls `write` with each line read from `r`, until it is closed.
+fn copy_lines(mut r: impl Read, mut write: impl FnMut(SystemTime, &str)) {
+    let mut buf = [0_u8; 4096];
+    let mut line = Vec::new();
+    while let Ok(size) = r.read(&mut buf) {
+        if size == 0 {
+            break;
+        }
+
+        let now = SystemTime::now();
+        for b in &buf[..size] {
+            if *b == b'\n' {
+                write(now, String::from_utf8_lossy(&line).trim_end_matches('\r'));
+                line.clear();
+            } else {
+                line.push(*b);
+            }
+        }
+    }
+
+    if !line.is_empty() {
+        write(
+            SystemTime::now(),
+            String::from_utf8_lossy(&line).trim_end_matches('\r'),
+        );
+    }
+}
+
+/// Reads serial output from a fifo qemu writes into, and writes it to a rotating log with
+/// timestamps. The logger runs until qemu closes the fifo.
+#[derive(Debug)]
+pub struct SerialLogger {
+    done: Receiver<()>,
+}
+
+impl SerialLogger {
+    pub fn start(fifo: PathBuf, log: PathBuf) -> Result<Self> {
+        mkfifo(&fifo)?;
+        let mut log = RotatingLog::open(log)?;
+        let (s, r) = channel();
+
+        std::thread::spawn(move || {
+            // opening the fifo blocks until qemu opens the other end.
+            if let Ok(f) = File::open(&fifo) {
+                copy_lines(f, |time, line| {
+                    log.write_line(time, line).unwrap_or_default();
+                });
+            }
+
+            s.send(()).unwrap_or_default();
+        });
+
+        Ok(Self { done: r })
+    }
+
+    /// Give the logger a moment to drain the fifo after qemu has exited. If qemu never opened
+    /// the fifo, the logger thread is stuck and is abandoned.
+    pub fn finish(self) {
+        self.done
+            .recv_timeout(LOGGER_FINISH_TIMEOUT)
+            .unwrap_or_default();
+    }
+}
+
+/// Logs what the hypervisor prints on stdout and stderr, with each line marked with its name
+/// (`[qemu]`) so that it stands out next to serial output in `emu logs`.
+#[derive(Debug)]
+pub struct OutputLogger {
+    log: Arc<Mutex<RotatingLog>>,
+    prefix: String,
+    done: Vec<Receiver<()>>,
+}
+
+impl OutputLogger {
+    pub fn start(path: PathBuf, name: &str) -> Result<Self> {
+        let prefix = format!("[{}]", name);
+        let mut log = RotatingLog::open(path)?;
+        log.write_line(SystemTime::now(), &format!("{} {}", prefix, STARTED))?;
+        Ok(Self {
+            log: Arc::new(Mutex::new(log)),
+            prefix,
+            done: Vec::new(),
+        })
+    }
+
+    /// Log what `child` writes to its stdout and stderr, which must be piped.
+    pub fn capture(&mut self, child: &mut Child) {
+        if let Some(stdout) = child.stdout.take() {
+            self.attach(stdout);
+        }
+
+        if let Some(stderr) = child.stderr.take() {
+            self.attach(stderr);
+        }
+    }
+
+    fn attach(&mut self, output: impl Read + Send + 'static) {
+        let log = self.log.clone();
+        let prefix = self.prefix.clone();
+        let (s, r) = channel();
+
+        std::thread::spawn(move || {
+            copy_lines(output, |time, line| {
+                if let Ok(mut log) = log.lock() {
+                    log.write_line(time, &format!("{} {}", prefix, line))
+                        .unwrap_or_default();
+                }
+            });
+
+            s.send(()).unwrap_or_default();
+        });
+
+        self.done.push(r);
+    }
+
+    /// Drain what qemu wrote before it exited, then record how it exited.
+    pub fn finish(self, status: &str) {
+        for done in &self.done {
+            done.recv_timeout(LOGGER_FINISH_TIMEOUT).unwrap_or_default();
+        }
+
+        if let Ok(mut log) = self.log.lock() {
+            log.write_line(SystemTime::now(), &format!("{} {}", self.prefix, status))
+                .unwrap_or_default();
+        }
+    }
+}
+
+fn line_time(line: &str) -> Option<SystemTime> {
+    parse_timestamp(line.split_once(' ')?.0)
+}
+
+/// Yields the lines of the log at `path` (and its rotated files) written at or after `since`.
+pub fn read_lines(path: &Path, since: Option<SystemTime>) -> Result<Vec<String>> {
+    let mut v = Vec::new();
+    for file in log_files(path) {
+        for line in BufReader::new(File::open(file)?).lines() {
+            let line = line?;
+            match (since, line_time(&line)) {
+                (Some(since), Some(time)) if time < since => {}
+                _ => v.push(line),
+            }
+        }
+    }
+
+    Ok(v)
+}
+
+/// Merges the lines of two logs in the order they were written.
+pub fn merge_lines(a: Vec<String>, b: Vec<String>) -> Vec<String> {
+    let mut v = Vec::with_capacity(a.len() + b.len());
+    let mut a = a.into_iter().peekable();
+    let mut b = b.into_iter().peekable();
+
+    loop {
+        let take_b = match (a.peek(), b.peek()) {
+            (None, None) => break,
+            (None, Some(_)) => true,
+            (Some(_), None) => false,
+            (Some(x), Some(y)) => {
+                matches!((line_time(x), line_time(y)), (Some(x), Some(y)) if y < x)
+            }
+        };
+
+        v.push(if take_b { b.next() } else { a.next() }.unwrap());
+    }
+
+    v
+}
+
+/// The last `count` lines of the log at `path`, for error messages.
+pub fn tail(path: &Path, count: usize) -> Vec<String> {
+    let lines = read_lines(path, None).unwrap_or_default();
+    lines[lines.len().saturating_sub(count)..].to_vec()
+}
+
+/// The end of the log at `path`, to explain why qemu exited.
+pub fn exit_report(path: &Path) -> String {
+    let mut lines = tail(path, EXIT_REPORT_LINES);
+
+    // earlier runs are no help.
+    let started = format!("] {}", STARTED);
+    if let Some(pos) = lines.iter().rposition(|line| line.ends_with(&started)) {
+        lines.drain(..pos);
+    }
+
+    if lines.is_empty() {
+        return String::new();
+    }
+
+    format!("\nlast lines of {}:\n{}", path.display(), lines.join("\n"))
+}
+
+/// When the hypervisor logging to `path` was last started, if it ever was.
+pub fn last_started(path: &Path) -> Option<SystemTime> {
+    let started = format!("] {}", STARTED);
+    read_lines(path, None)
+        .ok()?
+        .iter()
+        .rev()
+        .find(|line| line.ends_with(&started))
+        .and_then(|line| line_time(line))
+}
+
+/// Prints new lines written to the logs at `paths` as they arrive. This never returns unless
+/// there is an error; it survives rotation of the logs.
+pub fn follow(paths: &[PathBuf]) -> Result<()> {
+    let mut files = Vec::new();
+    for path in paths {
+        let mut f = File::open(path)?;
+        let ino = f.metadata()?.ino();
+        f.seek(SeekFrom::End(0))?;
+        files.push((path, f, ino));
+    }
+
+    let mut buf = Vec::new();
+
+    loop {
+        for (path, f, ino) in files.iter_mut() {
+            buf.clear();
+            f.read_to_end(&mut buf)?;
+            if !buf.is_empty() {
+                let mut stdout = std::io::stdout();
+                stdout.write_all(&buf)?;
+                stdout.flush()?;
+            }
+
+            if let Ok(meta) = std::fs::metadata(&path) {
+                if meta.ino() != *ino {
+                    // rotated; drain what's left of the old file, then pick up the new one.
+                    buf.clear();
+                    f.read_to_end(&mut buf)?;
+                    std::io::stdout().write_all(&buf)?;
+                    *f = File::open(&path)?;
+                    *ino = f.metadata()?.ino();
+                }
+            }
+        }
+
+        sleep(FOLLOW_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_rotating_log() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("serial.log");
+        let start = SystemTime::now();
+        let mut log = RotatingLog::open(path.clone())?;
+        log.write_line(start - Duration::from_secs(3600), "old")?;
+        log.write_line(start, "new")?;
+
+        let lines = read_lines(&path, None)?;
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" old"));
+        let lines = read_lines(&path, Some(start - Duration::from_secs(60)))?;
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].ends_with(" new"));
+
+        log.size = MAX_LOG_SIZE;
+        log.write_line(start, "rotated")?;
+        assert_eq!(log_files(&path), vec![rotated_path(&path, 1), path.clone()]);
+        let lines = read_lines(&path, None)?;
+        assert_eq!(lines.len(), 3);
+        assert!(lines[2].ends_with(" rotated"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_serial_logger() -> Result<()> {
+        let dir = tempdir()?;
+        let fifo = dir.path().join("serial.fifo");
+        let path = dir.path().join("serial.log");
+        let logger = SerialLogger::start(fifo.clone(), path.clone())?;
+
+        let mut f = OpenOptions::new().write(true).open(&fifo)?;
+        f.write_all(b"booting\r\nlogin: ")?;
+        drop(f);
+        logger.finish();
+
+        let lines = read_lines(&path, None)?;
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" booting"));
+        assert!(lines[1].ends_with(" login: "));
+        Ok(())
+    }
+
+    #[test]
+    fn test_output_logger() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("qemu.log");
+        let mut logger = OutputLogger::start(path.clone(), "qemu")?;
+        logger.attach(&b"qemu-system-x86_64: -m 0: bad size\n"[..]);
+        logger.finish("exited with exit status: 1");
+
+        let lines = read_lines(&path, None)?;
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with(" [qemu] started"));
+        assert!(lines[1].ends_with(" [qemu] qemu-system-x86_64: -m 0: bad size"));
+        assert_eq!(tail(&path, 1), lines[2..]);
+        assert_eq!(tail(&path, 10), lines);
+
+        OutputLogger::start(path.clone(), "qemu")?.finish("exited with signal: 9 (SIGKILL)");
+        let report = exit_report(&path);
+        assert!(!report.contains("bad size"));
+        assert!(report.ends_with(" [qemu] exited with signal: 9 (SIGKILL)"));
+
+        let lines = read_lines(&path, None)?;
+        assert_eq!(last_started(&path), line_time(&lines[3]));
+        assert!(last_started(&dir.path().join("missing.log")).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_lines() -> Result<()> {
+        let start = SystemTime::now();
+        let line = |secs, s| {
+            format!(
+                "{} {}",
+                format_timestamp(start + Duration::from_secs(secs)),
+                s
+            )
+        };
+        let serial = vec![line(0, "booting"), line(2, "login:")];
+        let qemu = vec![line(1, "[qemu] warning"), line(3, "[qemu] exited")];
+        assert_eq!(
+            merge_lines(serial.clone(), qemu.clone()),
+            vec![
+                serial[0].clone(),
+                qemu[0].clone(),
+                serial[1].clone(),
+                qemu[1].clone()
+            ]
+        );
+        assert_eq!(merge_lines(serial.clone(), Vec::new()), serial);
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use crate::config::{parse_cpu_list, MachineConfiguration, MemoryBackend};
+use anyhow::{anyhow, Result};
+
+fn backend(
+    machine: &MachineConfiguration,
+    shared: bool,
+    id: &str,
+    size: u32,
+    host_node: Option<u32>,
+) -> String {
+    // plain ram can't be shared with other processes, memfd can.
+    let mut opts = match (machine.memory_backend, shared) {
+        (MemoryBackend::Ram, false) => format!("memory-backend-ram,id={},size={}M", id, size),
+        _ => format!("memory-backend-memfd,id={},size={}M", id, size),
+    };
+
+    if machine.memory_backend == MemoryBackend::Hugepages {
+        opts += ",hugetlb=on";
+    }
+
+    if shared {
+        opts += ",share=on";
+    }
+
+    if machine.memory_prealloc {
+        opts += ",prealloc=on";
+    }
+
+    if let Some(host_node) = host_node {
+        opts += &format!(",host-nodes={},policy=bind", host_node);
+    }
+
+    opts
+}
+
+/// qemu arguments for the guest's memory. Without any memory settings, qemu allocates memory on
+/// its own and nothing is returned. `shared` forces memory to be shared, which vhost-user
+/// devices like virtiofs need.
+pub fn memory_args(machine: &MachineConfiguration, shared: bool) -> Result<Vec<String>> {
+    let shared = shared || machine.memory_shared;
+    let mut v = Vec::new();
+
+    if machine.numa.is_empty() {
+        if machine.memory_backend != MemoryBackend::Ram || shared || machine.memory_prealloc {
+            v.push("-object".to_string());
+            v.push(backend(machine, shared, "mem", machine.memory, None));
+            v.push("-numa".to_string());
+            v.push("node,memdev=mem".to_string());
+        }
+
+        return Ok(v);
+    }
+
+    for (x, node) in machine.numa.iter().enumerate() {
+        let id = format!("mem{}", x);
+        v.push("-object".to_string());
+        v.push(backend(machine, shared, &id, node.memory, node.host_node));
+
+        let mut opts = format!("node,nodeid={}", x);
+        for (start, end) in parse_cpu_list(&node.cpus)? {
+            if start == end {
+                opts += &format!(",cpus={}", start);
+            } else {
+                opts += &format!(",cpus={}-{}", start, end);
+            }
+        }
+        opts += &format!(",memdev={}", id);
+
+        v.push("-numa".to_string());
+        v.push(opts);
+    }
+
+    Ok(v)
+}
+
+/// Restrict a thread (a qemu vCPU thread, usually) to a single host CPU.
+pub fn pin_thread(tid: i32, cpu: u32) -> Result<()> {
+    if cpu as usize >= libc::CPU_SETSIZE as usize {
+        return Err(anyhow!("host cpu {} is out of range", cpu));
+    }
+
+    let res = unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu as usize, &mut set);
+        libc::sched_setaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &set)
+    };
+
+    if res != 0 {
+        return Err(anyhow!(
+            "could not pin thread {} to cpu {}: {}",
+            tid,
+            cpu,
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::NumaNode;
+    use anyhow::Result;
+
+    #[test]
+    fn test_memory_args() -> Result<()> {
+        let mut machine = MachineConfiguration {
+            memory: 4096,
+            ..Default::default()
+        };
+        assert!(memory_args(&machine, false)?.is_empty());
+        assert_eq!(
+            memory_args(&machine, true)?,
+            vec![
+                "-object",
+                "memory-backend-memfd,id=mem,size=4096M,share=on",
+                "-numa",
+                "node,memdev=mem"
+            ]
+        );
+
+        machine.memory_backend = MemoryBackend::Hugepages;
+        machine.memory_prealloc = true;
+        machine.numa = vec![
+            NumaNode {
+                cpus: "0-3".to_string(),
+                memory: 2048,
+                host_node: Some(0),
+            },
+            NumaNode {
+                cpus: "4-6,7".to_string(),
+                memory: 2048,
+                host_node: None,
+            },
+        ];
+        assert_eq!(
+            memory_args(&machine, false)?,
+            vec![
+                "-object",
+                "memory-backend-memfd,id=mem0,size=2048M,hugetlb=on,prealloc=on,host-nodes=0,policy=bind",
+                "-numa",
+                "node,nodeid=0,cpus=0-3,memdev=mem0",
+                "-object",
+                "memory-backend-memfd,id=mem1,size=2048M,hugetlb=on,prealloc=on",
+                "-numa",
+                "node,nodeid=1,cpus=4-6,cpus=7,memdev=mem1",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_thread() -> Result<()> {
+        // pin a thread to a cpu it is already allowed on, so this works on any machine.
+        std::thread::spawn(|| -> Result<()> {
+            let cpu = unsafe {
+                let mut set: libc::cpu_set_t = std::mem::zeroed();
+                libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set);
+                (0..libc::CPU_SETSIZE as usize)
+                    .find(|cpu| libc::CPU_ISSET(*cpu, &set))
+                    .unwrap()
+            };
+            pin_thread(0, cpu as u32)?;
+            assert!(pin_thread(0, libc::CPU_SETSIZE as u32).is_err());
+            Ok(())
+        })
+        .join()
+        .unwrap()?;
+        Ok(())
+    }
+}
@@ -1,4 +1,7 @@
-use super::messages::{ErrorReturn, Event, GenericReturn, JobInfo, QueryBlock, QueryJobs};
+use super::messages::{
+    BalloonInfo, CpuInfoFast, ErrorReturn, Event, GenericReturn, JobInfo, QueryBalloon, QueryBlock,
+    QueryCpusFast, QueryJobs,
+};
 use anyhow::{anyhow, Result};
 use serde_json::{json, Value};
 use std::{
@@ -92,6 +95,36 @@ impl Client {
         self.send_command("query-block", None)
     }
 
+    pub fn balloon_info(&mut self) -> Result<BalloonInfo> {
+        Ok(self
+            .send_command::<QueryBalloon>("query-balloon", None)?
+            .result)
+    }
+
+    pub fn balloon(&mut self, bytes: u64) -> Result<()> {
+        self.send_command::<GenericReturn>("balloon", Some(json!({ "value": bytes })))?;
+        Ok(())
+    }
+
+    /// Takes effect at the next reset; -1 takes the device out of the boot order.
+    pub fn set_boot_index(&mut self, device: &str, index: i32) -> Result<()> {
+        self.send_command::<GenericReturn>(
+            "qom-set",
+            Some(json!({
+                "path": format!("/machine/peripheral/{}", device),
+                "property": "bootindex",
+                "value": index,
+            })),
+        )?;
+        Ok(())
+    }
+
+    pub fn cpus(&mut self) -> Result<Vec<CpuInfoFast>> {
+        Ok(self
+            .send_command::<QueryCpusFast>("query-cpus-fast", None)?
+            .result)
+    }
+
     pub fn jobs(&mut self) -> Result<QueryJobs> {
         self.send_command("query-jobs", None)
     }
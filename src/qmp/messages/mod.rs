@@ -39,6 +39,33 @@ pub struct QueryJobs {
     pub result: Vec<JobInfo>,
 }
 
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct QueryBalloon {
+    #[serde(rename = "return")]
+    pub result: BalloonInfo,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BalloonInfo {
+    pub actual: u64, // bytes
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct QueryCpusFast {
+    #[serde(rename = "return")]
+    pub result: Vec<CpuInfoFast>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct CpuInfoFast {
+    pub cpu_index: u32,
+    pub thread_id: i32,
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct JobInfo {
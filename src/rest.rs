@@ -0,0 +1,159 @@
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::{
+    io::{Read, Write},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+};
+
+/// A minimal HTTP/1.1 client for the REST APIs hypervisors like cloud-hypervisor and
+/// firecracker serve on a unix socket. One request per connection.
+#[derive(Debug, Clone)]
+pub struct RestClient {
+    path: PathBuf,
+}
+
+impl RestClient {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Send a request, returning the response body (if any) as JSON. Responses outside of 2xx
+    /// are errors, with whatever the server said about them.
+    pub fn request(
+        &self,
+        method: &str,
+        endpoint: &str,
+        body: Option<Value>,
+    ) -> Result<Option<Value>> {
+        let mut stream = UnixStream::connect(&self.path)
+            .map_err(|e| anyhow!("could not connect to {}: {}", self.path.display(), e))?;
+
+        let body = body.map(|b| b.to_string()).unwrap_or_default();
+        let mut req = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nAccept: application/json\r\nConnection: close\r\n",
+            method, endpoint
+        );
+        if !body.is_empty() {
+            req += &format!(
+                "Content-Type: application/json\r\nContent-Length: {}\r\n",
+                body.len()
+            );
+        }
+        req += "\r\n";
+        req += &body;
+        stream.write_all(req.as_bytes())?;
+
+        let mut buf = Vec::new();
+        stream.read_to_end(&mut buf)?;
+        let response = String::from_utf8_lossy(&buf);
+
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or_else(|| anyhow!("malformed response from {}", self.path.display()))?;
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|s| s.parse::<u16>().ok())
+            .ok_or_else(|| anyhow!("malformed response from {}", self.path.display()))?;
+
+        if !(200..300).contains(&status) {
+            return Err(anyhow!(
+                "{} {} failed with status {}: {}",
+                method,
+                endpoint,
+                status,
+                body.trim()
+            ));
+        }
+
+        if body.trim().is_empty() {
+            return Ok(None);
+        }
+
+        Ok(Some(serde_json::from_str(body)?))
+    }
+
+    pub fn get(&self, endpoint: &str) -> Result<Option<Value>> {
+        self.request("GET", endpoint, None)
+    }
+
+    pub fn put(&self, endpoint: &str, body: Option<Value>) -> Result<Option<Value>> {
+        self.request("PUT", endpoint, body)
+    }
+
+    pub fn patch(&self, endpoint: &str, body: Option<Value>) -> Result<Option<Value>> {
+        self.request("PATCH", endpoint, body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use serde_json::json;
+    use std::{io::BufRead, io::BufReader, os::unix::net::UnixListener};
+    use tempfile::tempdir;
+
+    // answers each connection with the next response, and hands back the requests it got.
+    fn serve(
+        listener: UnixListener,
+        responses: Vec<&'static str>,
+    ) -> std::thread::JoinHandle<Vec<String>> {
+        std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(l) = line.strip_prefix("Content-Length: ") {
+                        length = l.trim().parse().unwrap();
+                    }
+                    request += &line;
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).unwrap();
+                request += &String::from_utf8(body).unwrap();
+                requests.push(request);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        })
+    }
+
+    #[test]
+    fn test_request() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("api.sock");
+        let server = serve(
+            UnixListener::bind(&path)?,
+            vec![
+                "HTTP/1.1 204 No Content\r\n\r\n",
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{\"state\":\"Running\"}",
+                "HTTP/1.1 500 Internal Server Error\r\n\r\nVM is not running",
+            ],
+        );
+
+        let client = RestClient::new(path);
+        assert_eq!(client.put("/api/v1/vm.pause", Some(json!({"a": 1})))?, None);
+        assert_eq!(
+            client.get("/api/v1/vm.info")?,
+            Some(json!({"state": "Running"}))
+        );
+        let err = client.put("/api/v1/vm.resume", None).unwrap_err();
+        assert!(err.to_string().contains("VM is not running"));
+
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("PUT /api/v1/vm.pause HTTP/1.1\r\n"));
+        assert!(requests[0].ends_with("\r\n\r\n{\"a\":1}"));
+        assert!(requests[1].starts_with("GET /api/v1/vm.info HTTP/1.1\r\n"));
+        Ok(())
+    }
+}
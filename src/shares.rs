@@ -0,0 +1,254 @@
+use crate::{config::ShareMap, util::path_exists};
+use anyhow::{anyhow, Result};
+use std::{
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+pub const VIRTIOFSD_BIN_NAME: &str = "virtiofsd";
+const VIRTIOFSD_STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// virtiofsd is rarely on $PATH; distributions install it next to qemu's other helpers. $PATH is
+// searched after these.
+const VIRTIOFSD_SEARCH_PATHS: &[&str] = &[
+    // fedora, rhel, arch, opensuse
+    "/usr/libexec/virtiofsd",
+    "/usr/lib/virtiofsd",
+    // debian, ubuntu
+    "/usr/lib/qemu/virtiofsd",
+];
+
+/// The longest mount tag both virtiofs and 9p accept.
+pub const MAX_TAG_LEN: usize = 31;
+
+/// A host directory exposed to the guest under a mount tag, e.g. `mount -t virtiofs <tag> /mnt`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Share {
+    pub host: PathBuf,
+    pub tag: String,
+}
+
+impl Share {
+    /// The shares of a VM, ordered by tag so that device ids are stable between runs.
+    pub fn list(shares: &ShareMap) -> Vec<Self> {
+        let mut v: Vec<Self> = shares
+            .iter()
+            .map(|(host, tag)| Self {
+                host: PathBuf::from(host),
+                tag: tag.clone(),
+            })
+            .collect();
+        v.sort_by(|a, b| a.tag.cmp(&b.tag));
+        v
+    }
+
+    pub fn socket_path(&self, dir: &Path) -> PathBuf {
+        dir.join(format!("{}.sock", self.tag))
+    }
+}
+
+pub fn valid_tag(tag: &str) -> Result<()> {
+    if tag.is_empty() || tag.len() > MAX_TAG_LEN {
+        return Err(anyhow!(
+            "share tag '{}' must be between 1 and {} characters",
+            tag,
+            MAX_TAG_LEN
+        ));
+    }
+
+    if !tag
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        return Err(anyhow!(
+            "share tag '{}' may only contain letters, numbers, '-', '_' and '.'",
+            tag
+        ));
+    }
+
+    Ok(())
+}
+
+/// Find virtiofsd. Without it, shares fall back to 9p.
+pub fn locate_virtiofsd() -> Option<PathBuf> {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    VIRTIOFSD_SEARCH_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .chain(std::env::split_paths(&path).map(|p| p.join(VIRTIOFSD_BIN_NAME)))
+        .find(|p| path_exists(p.clone()))
+}
+
+/// A virtiofsd serving one share to one VM. It exits on its own once qemu disconnects.
+#[derive(Debug)]
+pub struct Virtiofsd {
+    child: Child,
+}
+
+impl Virtiofsd {
+    pub fn start(bin: &Path, share: &Share, dir: &Path) -> Result<Self> {
+        let socket = share.socket_path(dir);
+        if path_exists(socket.clone()) {
+            std::fs::remove_file(&socket)?;
+        }
+
+        // emu runs unprivileged, which rules out virtiofsd's namespace sandbox.
+        let child = Command::new(bin)
+            .args(vec![
+                format!("--socket-path={}", socket.display()),
+                format!("--shared-dir={}", share.host.display()),
+                "--sandbox=none".to_string(),
+                "--cache=auto".to_string(),
+            ])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| anyhow!("could not start {}: {}", bin.display(), e))?;
+
+        let mut virtiofsd = Self { child };
+
+        let start = Instant::now();
+        while !path_exists(socket.clone()) {
+            if let Some(status) = virtiofsd.child.try_wait()? {
+                return Err(anyhow!(
+                    "{} exited early sharing {}: {}",
+                    VIRTIOFSD_BIN_NAME,
+                    share.host.display(),
+                    status
+                ));
+            }
+
+            if start.elapsed() > VIRTIOFSD_STARTUP_TIMEOUT {
+                virtiofsd.stop()?;
+                return Err(anyhow!(
+                    "timed out waiting for {} to start",
+                    VIRTIOFSD_BIN_NAME
+                ));
+            }
+
+            sleep(Duration::from_millis(50));
+        }
+
+        Ok(virtiofsd)
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        if self.child.try_wait()?.is_none() {
+            self.child.kill()?;
+            self.child.wait()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Start a virtiofsd for each share, with sockets in `dir`. Nothing is started without
+/// virtiofsd installed.
+pub fn start(shares: &ShareMap, dir: &Path) -> Result<Vec<Virtiofsd>> {
+    let mut v = Vec::new();
+    if let Some(bin) = locate_virtiofsd() {
+        for share in Share::list(shares) {
+            match Virtiofsd::start(&bin, &share, dir) {
+                Ok(virtiofsd) => v.push(virtiofsd),
+                Err(e) => {
+                    stop(v).unwrap_or_default();
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    Ok(v)
+}
+
+/// Stop every virtiofsd, even when stopping one of them fails; the first error is returned.
+pub fn stop(v: Vec<Virtiofsd>) -> Result<()> {
+    let mut res = Ok(());
+    for mut virtiofsd in v {
+        let stopped = virtiofsd.stop();
+        if res.is_ok() {
+            res = stopped;
+        }
+    }
+
+    res
+}
+
+/// qemu arguments for the shares. virtiofs also requires guest memory to be shared with
+/// virtiofsd; see `numa::memory_args`.
+pub fn qemu_args(shares: &[Share], dir: &Path, virtiofs: bool) -> Vec<String> {
+    let mut v = Vec::new();
+    for (x, share) in shares.iter().enumerate() {
+        if virtiofs {
+            v.push("-chardev".to_string());
+            v.push(format!(
+                "socket,id=fs{},path={}",
+                x,
+                share.socket_path(dir).display()
+            ));
+            v.push("-device".to_string());
+            v.push(format!(
+                "vhost-user-fs-pci,chardev=fs{},tag={}",
+                x, share.tag
+            ));
+        } else {
+            v.push("-fsdev".to_string());
+            v.push(format!(
+                "local,id=fs{},path={},security_model=none",
+                x,
+                share.host.display()
+            ));
+            v.push("-device".to_string());
+            v.push(format!(
+                "virtio-9p-pci,fsdev=fs{},mount_tag={}",
+                x, share.tag
+            ));
+        }
+    }
+
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_qemu_args() -> Result<()> {
+        let mut map = ShareMap::default();
+        map.insert("/home/user/src".to_string(), "src".to_string());
+        map.insert("/srv/data".to_string(), "data".to_string());
+        let shares = Share::list(&map);
+        assert_eq!(shares[0].tag, "data");
+
+        let dir = Path::new("/vm/shares");
+        let args = qemu_args(&shares, dir, true);
+        assert_eq!(args.len(), 8);
+        assert_eq!(args[1], "socket,id=fs0,path=/vm/shares/data.sock");
+        assert_eq!(args[7], "vhost-user-fs-pci,chardev=fs1,tag=src");
+
+        let args = qemu_args(&shares, dir, false);
+        assert_eq!(args.len(), 8);
+        assert_eq!(
+            args[5],
+            "local,id=fs1,path=/home/user/src,security_model=none"
+        );
+        assert_eq!(args[7], "virtio-9p-pci,fsdev=fs1,mount_tag=src");
+
+        assert!(qemu_args(&[], dir, true).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_valid_tag() -> Result<()> {
+        assert!(valid_tag("src").is_ok());
+        assert!(valid_tag("my-src_1.0").is_ok());
+        assert!(valid_tag("").is_err());
+        assert!(valid_tag("a/b").is_err());
+        assert!(valid_tag(&"a".repeat(MAX_TAG_LEN + 1)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use crate::{config_storage::disk_id, traits::ConfigStorageHandler, util::path_exists, vm::VM};
+use anyhow::{anyhow, Result};
+use std::{
+    fs::File,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+const DISKS_DIR: &str = "disks";
+
+// a reflink where the filesystem can share extents, a plain copy where it cannot.
+fn copy_disk(from: &Path, to: &Path) -> Result<()> {
+    let src = File::open(from)?;
+    let dst = File::create(to)?;
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) } == 0 {
+        return Ok(());
+    }
+
+    drop(dst);
+    std::fs::copy(from, to)?;
+    Ok(())
+}
+
+fn writable_disks(config: &dyn ConfigStorageHandler, vm: &VM) -> Result<Vec<PathBuf>> {
+    let settings = vm.config().disks;
+    Ok(config
+        .disk_list(vm)?
+        .into_iter()
+        .filter(|disk| {
+            !settings
+                .get(&disk_id(disk))
+                .is_some_and(|disk| disk.read_only)
+        })
+        .collect())
+}
+
+/// Save the disks the guest can write to in the snapshot at `path`. cloud-hypervisor and
+/// Firecracker snapshots only hold the guest's memory and devices, and restoring them against
+/// disks that have moved on would corrupt the guest's filesystems.
+pub fn save_disks(config: &dyn ConfigStorageHandler, vm: &VM, path: &Path) -> Result<()> {
+    let dir = path.join(DISKS_DIR);
+    std::fs::create_dir(&dir)?;
+    for disk in writable_disks(config, vm)? {
+        if let Some(name) = disk.file_name() {
+            copy_disk(&disk, &dir.join(name))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Put back the disks saved in snapshot `name` at `path`. Nothing is touched if any of them is
+/// missing.
+pub fn restore_disks(
+    config: &dyn ConfigStorageHandler,
+    vm: &VM,
+    name: &str,
+    path: &Path,
+) -> Result<()> {
+    let mut disks = Vec::new();
+    for disk in writable_disks(config, vm)? {
+        let saved = path
+            .join(DISKS_DIR)
+            .join(disk.file_name().unwrap_or_default());
+        if !path_exists(saved.clone()) {
+            return Err(anyhow!(
+                "snapshot '{}' does not hold {}, which {} may have written to since",
+                name,
+                disk.display(),
+                vm
+            ));
+        }
+        disks.push((saved, disk));
+    }
+
+    for (saved, disk) in disks {
+        copy_disk(&saved, &disk)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Configuration, config_storage::XDGConfigStorage};
+    use anyhow::Result;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_disks() -> Result<()> {
+        let dir = tempdir()?;
+        let storage: Arc<Box<dyn ConfigStorageHandler>> =
+            Arc::new(Box::new(XDGConfigStorage::new(dir.path().to_path_buf())));
+
+        let mut vm = VM::new("vm1".to_string(), storage.clone());
+        storage.create(&vm)?;
+        let root = storage.vm_root(&vm);
+        std::fs::write(root.join("qemu-0.qcow2"), "before")?;
+        std::fs::write(root.join("qemu-1.qcow2"), "read-only")?;
+
+        let mut config = Configuration::default();
+        config.set_disk_value(1, "read-only", "true")?;
+        vm.set_config(config);
+
+        let snapshot = dir.path().join("snapshot");
+        std::fs::create_dir(&snapshot)?;
+        save_disks(&**storage, &vm, &snapshot)?;
+        assert!(path_exists(snapshot.join(DISKS_DIR).join("qemu-0.qcow2")));
+        assert!(!path_exists(snapshot.join(DISKS_DIR).join("qemu-1.qcow2")));
+
+        std::fs::write(root.join("qemu-0.qcow2"), "after")?;
+        restore_disks(&**storage, &vm, "snapshot", &snapshot)?;
+        assert_eq!(
+            std::fs::read_to_string(root.join("qemu-0.qcow2"))?,
+            "before"
+        );
+
+        std::fs::write(root.join("qemu-2.qcow2"), "new")?;
+        assert!(restore_disks(&**storage, &vm, "snapshot", &snapshot).is_err());
+        Ok(())
+    }
+}
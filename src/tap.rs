@@ -0,0 +1,259 @@
+use crate::{config::PortMap, traits::ConfigStorageHandler, vm::VM};
+use anyhow::{anyhow, Result};
+use std::{
+    collections::HashSet,
+    fs::read_to_string,
+    io::copy,
+    net::{Ipv4Addr, Shutdown, TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+pub const IP_BIN_NAME: &str = "ip";
+const TAP_PREFIX: &str = "emutap";
+const NETMASK: &str = "255.255.255.252";
+// 172.30.0.0/16, carved into /30s: one for each VM, holding the host and guest addresses.
+const SUBNETS: u32 = 1 << 14;
+
+/// A TAP device connecting one VM to the host, for hypervisors without user-mode networking.
+/// Its index, which its name and addresses are derived from, is allocated on first use and kept
+/// in the VM's directory, so they stay the same between runs and across snapshots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tap {
+    index: u32,
+}
+
+// FNV-1a, which unlike std's hasher is guaranteed not to change.
+fn hash(s: &str) -> u32 {
+    s.bytes()
+        .fold(0x811c9dc5, |h, b| (h ^ b as u32).wrapping_mul(0x01000193))
+}
+
+fn read_index(path: &Path) -> Option<u32> {
+    read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn ip(args: &[&str]) -> Result<()> {
+    let out = Command::new(IP_BIN_NAME)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|e| anyhow!("could not run {}: {}", IP_BIN_NAME, e))?;
+
+    if !out.status.success() {
+        return Err(anyhow!(
+            "`{} {}` failed (creating TAP devices needs CAP_NET_ADMIN): {}",
+            IP_BIN_NAME,
+            args.join(" "),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+
+    Ok(())
+}
+
+// relays one forwarded connection in both directions until either side closes.
+fn relay(client: TcpStream, guest: (Ipv4Addr, u16)) {
+    if let Ok(server) = TcpStream::connect(guest) {
+        if let (Ok(mut client_r), Ok(mut server_w)) = (client.try_clone(), server.try_clone()) {
+            std::thread::spawn(move || {
+                copy(&mut client_r, &mut server_w).unwrap_or_default();
+                server_w.shutdown(Shutdown::Write).unwrap_or_default();
+            });
+        }
+
+        let (mut server_r, mut client_w) = (server, client);
+        copy(&mut server_r, &mut client_w).unwrap_or_default();
+        client_w.shutdown(Shutdown::Write).unwrap_or_default();
+    }
+}
+
+impl Tap {
+    /// The TAP device of `vm`. A new index starts from a hash of the VM's name and skips those
+    /// of the other VMs and of devices already on the host.
+    pub fn new(config: &dyn ConfigStorageHandler, vm: &VM) -> Result<Self> {
+        let path = config.tap_path(vm);
+        if let Some(index) = read_index(&path) {
+            return Ok(Self { index });
+        }
+
+        let taken = config
+            .vm_list()?
+            .iter()
+            .filter(|other| other.name() != vm.name())
+            .filter_map(|other| read_index(&config.tap_path(other)))
+            .collect::<HashSet<u32>>();
+
+        let start = hash(&vm.name()) % SUBNETS;
+        let tap = (0..SUBNETS)
+            .map(|x| Self {
+                index: (start + x) % SUBNETS,
+            })
+            .find(|tap| !taken.contains(&tap.index) && !tap.exists())
+            .ok_or_else(|| anyhow!("no TAP device addresses are left"))?;
+
+        std::fs::write(path, tap.index.to_string())?;
+        Ok(tap)
+    }
+
+    fn exists(&self) -> bool {
+        PathBuf::from("/sys/class/net").join(self.name()).exists()
+    }
+
+    pub fn name(&self) -> String {
+        format!("{}{}", TAP_PREFIX, self.index)
+    }
+
+    fn address(&self, host: u8) -> Ipv4Addr {
+        let base = self.index * 4;
+        Ipv4Addr::new(172, 30, (base >> 8) as u8, (base & 0xff) as u8 + host)
+    }
+
+    pub fn host_address(&self) -> Ipv4Addr {
+        self.address(1)
+    }
+
+    pub fn guest_address(&self) -> Ipv4Addr {
+        self.address(2)
+    }
+
+    pub fn guest_mac(&self) -> String {
+        format!(
+            "06:00:ac:1e:{:02x}:{:02x}",
+            (self.index >> 8) as u8,
+            self.index as u8
+        )
+    }
+
+    /// The kernel's `ip=` boot argument, which configures the guest's side without help from
+    /// the guest's userspace.
+    pub fn kernel_arg(&self) -> String {
+        format!(
+            "ip={}::{}:{}::eth0:off",
+            self.guest_address(),
+            self.host_address(),
+            NETMASK
+        )
+    }
+
+    /// Create the device and bring it up with the host's address. The index belongs to one VM
+    /// only, so a device by its name was left behind by a crashed run of that VM, and is
+    /// replaced; the caller makes sure the VM is not running.
+    pub fn create(&self) -> Result<()> {
+        let name = self.name();
+        if self.exists() {
+            self.destroy()?;
+        }
+        ip(&["tuntap", "add", "dev", &name, "mode", "tap"])?;
+        ip(&[
+            "addr",
+            "add",
+            &format!("{}/30", self.host_address()),
+            "dev",
+            &name,
+        ])?;
+        ip(&["link", "set", &name, "up"])
+    }
+
+    pub fn destroy(&self) -> Result<()> {
+        ip(&["link", "del", &self.name()])
+    }
+
+    /// Forward each host port on `localhost` to its guest port, the way qemu's user-mode
+    /// networking does. The forwarders live as long as the process.
+    pub fn forward_ports(&self, ports: &PortMap) -> Result<()> {
+        let guest = self.guest_address();
+        for (host, guest_port) in ports {
+            let listener = TcpListener::bind(format!("127.0.0.1:{}", host))
+                .map_err(|e| anyhow!("could not forward port {}: {}", host, e))?;
+            let guest_port = *guest_port;
+
+            std::thread::spawn(move || {
+                for client in listener.incoming().flatten() {
+                    std::thread::spawn(move || relay(client, (guest, guest_port)));
+                }
+            });
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config_storage::XDGConfigStorage;
+    use anyhow::Result;
+    use std::{
+        io::{Read, Write},
+        sync::Arc,
+    };
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_addresses() -> Result<()> {
+        let tap = Tap { index: 0x123 };
+        assert_eq!(tap.name(), "emutap291");
+        assert_eq!(tap.host_address(), Ipv4Addr::new(172, 30, 4, 141));
+        assert_eq!(tap.guest_address(), Ipv4Addr::new(172, 30, 4, 142));
+        assert_eq!(tap.guest_mac(), "06:00:ac:1e:01:23");
+        assert_eq!(
+            tap.kernel_arg(),
+            "ip=172.30.4.142::172.30.4.141:255.255.255.252::eth0:off"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_allocation() -> Result<()> {
+        let dir = tempdir()?;
+        let storage: Arc<Box<dyn ConfigStorageHandler>> =
+            Arc::new(Box::new(XDGConfigStorage::new(dir.path().to_path_buf())));
+
+        let vm1 = VM::new("vm1".to_string(), storage.clone());
+        let vm2 = VM::new("vm2".to_string(), storage.clone());
+        storage.create(&vm1)?;
+        storage.create(&vm2)?;
+
+        // vm2 already holds the index vm1 would start from.
+        let start = hash("vm1") % SUBNETS;
+        std::fs::write(storage.tap_path(&vm2), start.to_string())?;
+
+        let tap = Tap::new(&**storage, &vm1)?;
+        assert_ne!(tap.index, start);
+        assert!(tap.name().len() < 16);
+        assert_eq!(Tap::new(&**storage, &vm1)?, tap);
+        assert_eq!(
+            std::fs::read_to_string(storage.tap_path(&vm1))?,
+            tap.index.to_string()
+        );
+        assert_eq!(Tap::new(&**storage, &vm2)?.index, start);
+        Ok(())
+    }
+
+    #[test]
+    fn test_relay() -> Result<()> {
+        let server = TcpListener::bind("127.0.0.1:0")?;
+        let port = server.local_addr()?.port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = server.accept().unwrap();
+            let mut buf = [0; 4];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&buf).unwrap();
+        });
+
+        let front = TcpListener::bind("127.0.0.1:0")?;
+        let addr = front.local_addr()?;
+        std::thread::spawn(move || {
+            let (client, _) = front.accept().unwrap();
+            relay(client, (Ipv4Addr::LOCALHOST, port));
+        });
+
+        let mut client = TcpStream::connect(addr)?;
+        client.write_all(b"ping")?;
+        let mut buf = String::new();
+        client.read_to_string(&mut buf)?;
+        assert_eq!(buf, "ping");
+        Ok(())
+    }
+}
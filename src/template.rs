@@ -1,7 +1,8 @@
 use super::vm::VM;
 use anyhow::{anyhow, Result};
 use serde::Serialize;
-use tinytemplate::TinyTemplate;
+use std::path::Path;
+use tinytemplate::{format_unescaped, TinyTemplate};
 
 const EMU_DEFAULT_PATH: &str = "/bin/emu";
 
@@ -39,6 +40,53 @@ impl Data {
     }
 }
 
+/// Variables available to `extra_args`, e.g. `{vm_root}/extra.qcow2`.
+#[derive(Serialize)]
+pub struct ExtraArgsData {
+    vm_name: String,
+    vm_root: String,
+    monitor_path: String,
+    serial_path: String,
+    ssh_port: u16,
+}
+
+impl ExtraArgsData {
+    pub fn new(vm: &VM, vm_root: &Path, monitor_path: &Path, serial_path: &Path) -> Self {
+        Self {
+            vm_name: vm.name(),
+            vm_root: vm_root.display().to_string(),
+            monitor_path: monitor_path.display().to_string(),
+            serial_path: serial_path.display().to_string(),
+            ssh_port: vm.config().machine.ssh_port,
+        }
+    }
+}
+
+/// Check that an extra argument is a valid template, without rendering it.
+pub fn valid_extra_arg(arg: &str) -> Result<()> {
+    let mut t = TinyTemplate::new();
+    t.add_template("arg", arg)
+        .map_err(|e| anyhow!("invalid extra argument '{}': {}", arg, e))?;
+    Ok(())
+}
+
+/// Render `extra_args`. Values are inserted as they are; qemu is not a browser.
+pub fn extra_args(args: &[String], data: &ExtraArgsData) -> Result<Vec<String>> {
+    let mut v = Vec::new();
+    for arg in args {
+        let mut t = TinyTemplate::new();
+        t.set_default_formatter(&format_unescaped);
+        t.add_template("arg", arg)
+            .map_err(|e| anyhow!("invalid extra argument '{}': {}", arg, e))?;
+        v.push(
+            t.render("arg", data)
+                .map_err(|e| anyhow!("could not render extra argument '{}': {}", arg, e))?,
+        );
+    }
+
+    Ok(v)
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct Systemd;
 
@@ -66,4 +114,34 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_extra_args() -> Result<()> {
+        let vm: VM = "vm1".to_string().into();
+        let data = ExtraArgsData::new(
+            &vm,
+            Path::new("/vms/vm1"),
+            Path::new("/vms/vm1/mon"),
+            Path::new("/vms/vm1/serial"),
+        );
+        let args = vec![
+            "-drive".to_string(),
+            "file={vm_root}/scratch.raw,if=virtio,format=raw".to_string(),
+            "-name".to_string(),
+            "{vm_name}&co".to_string(),
+        ];
+        assert_eq!(
+            extra_args(&args, &data)?,
+            vec![
+                "-drive",
+                "file=/vms/vm1/scratch.raw,if=virtio,format=raw",
+                "-name",
+                "vm1&co"
+            ]
+        );
+        assert!(extra_args(&["{nothing}".to_string()], &data).is_err());
+        assert!(valid_extra_arg("{vm_root").is_err());
+        assert!(valid_extra_arg("-device").is_ok());
+        Ok(())
+    }
 }
@@ -0,0 +1,128 @@
+use crate::{config::Firmware, util::path_exists};
+use anyhow::{anyhow, Result};
+use std::{
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+pub const SWTPM_BIN_NAME: &str = "swtpm";
+const SWTPM_SOCKET_NAME: &str = "swtpm.sock";
+const SWTPM_STARTUP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A software TPM emulator backing a single VM. The state directory persists between runs; the
+/// process itself lives only as long as the qemu it is attached to.
+#[derive(Debug)]
+pub struct Swtpm {
+    child: Child,
+}
+
+impl Swtpm {
+    pub fn socket_path(state_dir: &Path) -> PathBuf {
+        state_dir.join(SWTPM_SOCKET_NAME)
+    }
+
+    /// Make sure swtpm can be started at all. This is useful before forking, where errors are
+    /// otherwise lost.
+    pub fn check() -> Result<()> {
+        match Command::new(SWTPM_BIN_NAME)
+            .arg("--version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+        {
+            Ok(_) => Ok(()),
+            Err(e) => Err(anyhow!("could not run {}: {}", SWTPM_BIN_NAME, e)),
+        }
+    }
+
+    pub fn start(state_dir: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&state_dir)?;
+        let socket = Self::socket_path(&state_dir);
+        if path_exists(socket.clone()) {
+            std::fs::remove_file(&socket)?;
+        }
+
+        let child = Command::new(SWTPM_BIN_NAME)
+            .args(vec![
+                "socket".to_string(),
+                "--tpm2".to_string(),
+                "--terminate".to_string(),
+                "--tpmstate".to_string(),
+                format!("dir={}", state_dir.display()),
+                "--ctrl".to_string(),
+                format!("type=unixio,path={}", socket.display()),
+            ])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .map_err(|e| anyhow!("could not start {}: {}", SWTPM_BIN_NAME, e))?;
+
+        let mut swtpm = Self { child };
+
+        let start = Instant::now();
+        while !path_exists(socket.clone()) {
+            if let Some(status) = swtpm.child.try_wait()? {
+                return Err(anyhow!("{} exited early: {}", SWTPM_BIN_NAME, status));
+            }
+
+            if start.elapsed() > SWTPM_STARTUP_TIMEOUT {
+                swtpm.stop()?;
+                return Err(anyhow!("timed out waiting for {} to start", SWTPM_BIN_NAME));
+            }
+
+            sleep(Duration::from_millis(50));
+        }
+
+        Ok(swtpm)
+    }
+
+    pub fn stop(&mut self) -> Result<()> {
+        // with --terminate, swtpm usually exits on its own once qemu disconnects.
+        if self.child.try_wait()?.is_none() {
+            self.child.kill()?;
+            self.child.wait()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// qemu arguments for attaching a TPM device to the swtpm socket. UEFI guests get the CRB
+/// interface, legacy BIOS guests get TIS.
+pub fn qemu_args(state_dir: &Path, firmware: Firmware) -> Vec<String> {
+    let model = match firmware {
+        Firmware::Uefi => "tpm-crb",
+        Firmware::Bios => "tpm-tis",
+    };
+
+    vec![
+        "-chardev".to_string(),
+        format!(
+            "socket,id=chrtpm,path={}",
+            Swtpm::socket_path(state_dir).display()
+        ),
+        "-tpmdev".to_string(),
+        "emulator,id=tpm0,chardev=chrtpm".to_string(),
+        "-device".to_string(),
+        format!("{},tpmdev=tpm0", model),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+
+    #[test]
+    fn test_qemu_args() -> Result<()> {
+        let dir = Path::new("/vm/tpm");
+        let args = qemu_args(dir, Firmware::Uefi);
+        assert_eq!(args[1], "socket,id=chrtpm,path=/vm/tpm/swtpm.sock");
+        assert_eq!(args[5], "tpm-crb,tpmdev=tpm0");
+        let args = qemu_args(dir, Firmware::Bios);
+        assert_eq!(args[5], "tpm-tis,tpmdev=tpm0");
+        Ok(())
+    }
+}
@@ -2,7 +2,8 @@ use super::vm::VM;
 use anyhow::Result;
 use std::{fmt::Debug, path::PathBuf, process::ExitStatus, sync::Arc};
 
-const DEFAULT_SNAPSHOT_TAG: &str = "[EMU-Suspend]";
+/// The snapshot `emu save`, `emu load` and `emu clear-state` work on.
+pub const DEFAULT_SNAPSHOT_TAG: &str = "[EMU-Suspend]";
 
 #[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Supervisors {
@@ -16,6 +17,7 @@ pub trait ImageHandler: Debug {
     fn create(&self, target: PathBuf, gbs: usize) -> Result<PathBuf>;
     fn remove(&self, disk: PathBuf) -> Result<()>;
     fn clone_image(&self, description: String, old: PathBuf, new: PathBuf) -> Result<()>;
+    fn create_overlay(&self, base: PathBuf, overlay: PathBuf) -> Result<()>;
 }
 
 pub trait SupervisorHandler: Debug {
@@ -44,6 +46,18 @@ pub trait ConfigStorageHandler: Debug {
     fn config_path(&self, vm: &VM) -> PathBuf;
     fn vm_root(&self, vm: &VM) -> PathBuf;
     fn monitor_path(&self, vm: &VM) -> PathBuf;
+    fn serial_path(&self, vm: &VM) -> PathBuf;
+    fn display_path(&self, vm: &VM) -> PathBuf;
+    fn serial_fifo_path(&self, vm: &VM) -> PathBuf;
+    fn serial_log_path(&self, vm: &VM) -> PathBuf;
+    fn qemu_log_path(&self, vm: &VM) -> PathBuf;
+    fn nvram_path(&self, vm: &VM) -> PathBuf;
+    fn tpm_path(&self, vm: &VM) -> PathBuf;
+    fn cloud_init_path(&self, vm: &VM) -> PathBuf;
+    fn seed_path(&self, vm: &VM) -> PathBuf;
+    fn shares_path(&self, vm: &VM) -> PathBuf;
+    fn tap_path(&self, vm: &VM) -> PathBuf;
+    fn hooks_path(&self, vm: &VM) -> PathBuf;
     fn write_config(&self, vm: VM) -> Result<()>;
     fn vm_exists(&self, vm: &VM) -> bool;
     fn vm_list(&self) -> Result<Vec<VM>>;
@@ -56,6 +70,7 @@ pub trait ConfigStorageHandler: Debug {
 }
 
 pub trait Launcher: Debug {
+    fn command_line(&self, vm: &VM) -> Result<Vec<String>>;
     fn launch_attached(&self, vm: &VM) -> Result<ExitStatus>;
     fn launch_detached(&self, vm: &VM) -> Result<()>;
     fn shutdown_wait(&self, vm: &VM) -> Result<ExitStatus>;
@@ -64,6 +79,8 @@ pub trait Launcher: Debug {
     fn snapshot(&self, vm: &VM, name: String) -> Result<()>;
     fn restore(&self, vm: &VM, name: String) -> Result<()>;
     fn delete_snapshot(&self, vm: &VM, name: String) -> Result<()>;
+    fn balloon(&self, vm: &VM, megabytes: u32) -> Result<()>;
+    fn balloon_size(&self, vm: &VM) -> Result<u32>;
 
     fn save_state(&self, vm: &VM) -> Result<()> {
         self.snapshot(vm, DEFAULT_SNAPSHOT_TAG.to_string())
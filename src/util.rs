@@ -1,4 +1,8 @@
-use std::path::PathBuf;
+use anyhow::{anyhow, Result};
+use std::{
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 pub fn pid_running(pid: u32) -> bool {
     path_exists(PathBuf::from(format!("/proc/{}", pid)))
@@ -12,11 +16,242 @@ pub fn valid_filename(name: &str) -> bool {
     !(name.contains("..") || name.contains(std::path::MAIN_SEPARATOR) || name.contains("\x00"))
 }
 
+/// Quotes a string for a POSIX shell. Strings that are safe as they are are left alone, which
+/// keeps long command lines readable.
+pub fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c))
+    {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// Splits a string into words the way a POSIX shell does, with single and double quotes, and is
+/// the inverse of `shell_quote`. Unlike a shell, a backslash outside quotes is kept unless it
+/// escapes whitespace, a quote or another backslash, so that `\{` reaches the template as is.
+pub fn shell_split(s: &str) -> Result<Vec<String>> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => {
+                if let Some(word) = word.take() {
+                    words.push(word);
+                }
+            }
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(anyhow!("unterminated single quote in {}", s)),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(anyhow!("unterminated double quote in {}", s)),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(anyhow!("unterminated double quote in {}", s)),
+                    }
+                }
+            }
+            '\\' => {
+                let word = word.get_or_insert_with(String::new);
+                match chars.next() {
+                    Some(c) if c.is_whitespace() || "'\"\\".contains(c) => word.push(c),
+                    Some(c) => {
+                        word.push('\\');
+                        word.push(c);
+                    }
+                    None => word.push('\\'),
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+
+    if let Some(word) = word {
+        words.push(word);
+    }
+
+    Ok(words)
+}
+
+// date conversions below are Howard Hinnant's civil calendar algorithms; they save us a
+// dependency on a full date library for the sake of printing log timestamps.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(m <= 2), m, d)
+}
+
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Formats a time as a UTC RFC 3339 timestamp with millisecond precision.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() as i64;
+    let (y, m, d) = civil_from_days(secs.div_euclid(86400));
+    let rem = secs.rem_euclid(86400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        y,
+        m,
+        d,
+        rem / 3600,
+        (rem % 3600) / 60,
+        rem % 60,
+        since.subsec_millis()
+    )
+}
+
+/// Parses timestamps produced by `format_timestamp`; the fractional part is optional.
+pub fn parse_timestamp(s: &str) -> Option<SystemTime> {
+    let s = s.strip_suffix('Z')?;
+    let (date, time) = s.split_once('T')?;
+    let (time, millis) = match time.split_once('.') {
+        Some((time, fraction)) => {
+            if fraction.is_empty() || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+
+            // `.5` is 500 milliseconds; digits past the third are dropped.
+            let millis = format!("{:0<3}", &fraction[..fraction.len().min(3)]);
+            (time, millis.parse::<u64>().ok()?)
+        }
+        None => (time, 0),
+    };
+
+    let date = date
+        .split('-')
+        .map(|x| x.parse::<i64>().ok())
+        .collect::<Option<Vec<i64>>>()?;
+    let time = time
+        .split(':')
+        .map(|x| x.parse::<i64>().ok())
+        .collect::<Option<Vec<i64>>>()?;
+
+    if date.len() != 3 || time.len() != 3 {
+        return None;
+    }
+
+    let secs = days_from_civil(date[0], date[1], date[2]) * 86400
+        + time[0] * 3600
+        + time[1] * 60
+        + time[2];
+
+    Some(
+        UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?) + Duration::from_millis(millis),
+    )
+}
+
+/// Parses short durations like `30s`, `10m`, `2h` or `1d`. Bare numbers are seconds.
+pub fn parse_duration(s: &str) -> Result<Duration> {
+    let (num, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => s.split_at(pos),
+        None => (s, "s"),
+    };
+
+    let num = num
+        .parse::<u64>()
+        .map_err(|_| anyhow!("invalid duration: {}", s))?;
+
+    let mult = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return Err(anyhow!("invalid duration unit in {}; use s, m, h or d", s)),
+    };
+
+    num.checked_mul(mult)
+        .map(Duration::from_secs)
+        .ok_or_else(|| anyhow!("duration is too long: {}", s))
+}
+
+/// Parses either a timestamp or a duration into the past, relative to now.
+pub fn parse_since(s: &str) -> Result<SystemTime> {
+    if let Some(time) = parse_timestamp(s) {
+        return Ok(time);
+    }
+
+    SystemTime::now()
+        .checked_sub(parse_duration(s)?)
+        .ok_or_else(|| anyhow!("{} reaches too far into the past", s))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use anyhow::Result;
 
+    #[test]
+    fn test_shell_quote() -> Result<()> {
+        assert_eq!(shell_quote("-drive"), "-drive");
+        assert_eq!(
+            shell_quote("file=/vm/qemu-0.qcow2,index=0"),
+            "file=/vm/qemu-0.qcow2,index=0"
+        );
+        assert_eq!(shell_quote("console=ttyS0 quiet"), "'console=ttyS0 quiet'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote(""), "''");
+        Ok(())
+    }
+
+    #[test]
+    fn test_shell_split() -> Result<()> {
+        assert_eq!(
+            shell_split("  -device  pvpanic ")?,
+            vec!["-device", "pvpanic"]
+        );
+        assert_eq!(
+            shell_split("-fw_cfg 'name=opt/x,string=a b'")?,
+            vec!["-fw_cfg", "name=opt/x,string=a b"]
+        );
+        assert_eq!(
+            shell_split(r#"-append "console=ttyS0 \"quiet\"" a\ b"#)?,
+            vec!["-append", "console=ttyS0 \"quiet\"", "a b"]
+        );
+        assert_eq!(shell_split(r"\{literal} ''")?, vec![r"\{literal}", ""]);
+        assert!(shell_split("'open").is_err());
+        assert!(shell_split("\"open").is_err());
+
+        for s in ["plain", "console=ttyS0 quiet", "it's", "", "a\\b \"c\""] {
+            assert_eq!(shell_split(&shell_quote(s))?, vec![s]);
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_pid_running() -> Result<()> {
         assert!(pid_running(1));
@@ -31,6 +266,45 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_timestamps() -> Result<()> {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        assert_eq!(format_timestamp(time), "2023-11-14T22:13:20.123Z");
+        assert_eq!(parse_timestamp("2023-11-14T22:13:20.123Z"), Some(time));
+        assert_eq!(
+            parse_timestamp("2023-11-14T22:13:20Z"),
+            Some(time - Duration::from_millis(123))
+        );
+        assert_eq!(format_timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            format_timestamp(UNIX_EPOCH + Duration::from_secs(951782400)),
+            "2000-02-29T00:00:00.000Z"
+        );
+        assert_eq!(
+            parse_timestamp("2023-11-14T22:13:20.1Z"),
+            Some(time - Duration::from_millis(23))
+        );
+        assert_eq!(parse_timestamp("2023-11-14T22:13:20.123456Z"), Some(time));
+        assert_eq!(parse_timestamp("2023-11-14T22:13:20.Z"), None);
+        assert_eq!(parse_timestamp("2023-11-14T22:13:20.+1Z"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_duration() -> Result<()> {
+        assert_eq!(parse_duration("30")?, Duration::from_secs(30));
+        assert_eq!(parse_duration("10m")?, Duration::from_secs(600));
+        assert_eq!(parse_duration("2h")?, Duration::from_secs(7200));
+        assert_eq!(parse_duration("1d")?, Duration::from_secs(86400));
+        assert!(parse_duration("1w").is_err());
+        assert!(parse_duration("m").is_err());
+        assert!(parse_duration("18446744073709551615d").is_err());
+        assert!(parse_since("10m").is_ok());
+        assert!(parse_since("18446744073709551615s").is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_valid_filename() -> Result<()> {
         for item in vec!["../one", "/vmlinuz", "im\x00smrt", "one/../two"] {
@@ -3,7 +3,7 @@ use super::{
     supervisor::{PidSupervisor, SystemdSupervisor},
     traits::{ConfigStorageHandler, SupervisorHandler, Supervisors},
 };
-use crate::config::Configuration;
+use crate::config::{BootDevice, Configuration};
 use anyhow::Result;
 use serde::{de::Visitor, Deserialize, Serialize};
 use std::{fmt::Display, path::PathBuf, sync::Arc};
@@ -13,8 +13,10 @@ pub struct VM {
     name: String,
     cdrom: Option<PathBuf>,
     extra_disk: Option<PathBuf>,
+    boot_once: Option<BootDevice>,
     config: Configuration,
     headless: bool,
+    overlays: Option<PathBuf>,
     supervisor: Supervisors,
 }
 
@@ -72,13 +74,31 @@ impl VM {
     }
 
     pub fn extra_disk(&self) -> Option<PathBuf> {
-        self.cdrom.clone()
+        self.extra_disk.clone()
     }
 
     pub fn set_extra_disk(&mut self, extra_disk: PathBuf) {
         self.extra_disk = Some(extra_disk)
     }
 
+    /// The device to boot from first in this run only; later boots follow `boot_order`.
+    pub fn boot_once(&self) -> Option<BootDevice> {
+        self.boot_once
+    }
+
+    pub fn set_boot_once(&mut self, device: BootDevice) {
+        self.boot_once = Some(device)
+    }
+
+    /// The directory holding the throwaway overlays an ephemeral run boots from.
+    pub fn overlays(&self) -> Option<PathBuf> {
+        self.overlays.clone()
+    }
+
+    pub fn set_overlays(&mut self, overlays: PathBuf) {
+        self.overlays = Some(overlays)
+    }
+
     pub fn config(&self) -> Configuration {
         self.config.clone()
     }
@@ -180,8 +200,8 @@ mod tests {
 
         vm.set_cdrom(PathBuf::from("/cdrom"));
         assert_eq!(vm.cdrom(), Some(PathBuf::from("/cdrom")));
-        vm.set_extra_disk(PathBuf::from("/cdrom"));
-        assert_eq!(vm.extra_disk(), Some(PathBuf::from("/cdrom")));
+        vm.set_extra_disk(PathBuf::from("/extra"));
+        assert_eq!(vm.extra_disk(), Some(PathBuf::from("/extra")));
         vm.set_headless(true);
         assert!(vm.headless());
 
@@ -0,0 +1,214 @@
+use crate::{devices::Device, logs, traits::ConfigStorageHandler, vm::VM};
+use anyhow::{anyhow, Result};
+use regex::Regex;
+use serde_json::{json, Value};
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpStream},
+    os::unix::net::UnixStream,
+    path::Path,
+    sync::Arc,
+    thread::sleep,
+    time::{Duration, Instant, SystemTime},
+};
+
+pub const GUEST_AGENT_PORT: &str = "org.qemu.guest_agent.0";
+const PROBE_INTERVAL: Duration = Duration::from_millis(500);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What has to happen before a guest counts as ready to use.
+#[derive(Debug, Clone)]
+pub enum Readiness {
+    /// The guest's SSH server sends its banner on the VM's `ssh_port`.
+    Ssh,
+    /// Something in the guest keeps connections to this forwarded host port open.
+    Port(u16),
+    /// The qemu guest agent answers.
+    Agent,
+    /// A line of serial output since the VM last started matches.
+    Serial(Regex),
+}
+
+impl std::fmt::Display for Readiness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Ssh => write!(f, "SSH"),
+            Self::Port(port) => write!(f, "port {}", port),
+            Self::Agent => write!(f, "the guest agent"),
+            Self::Serial(re) => write!(f, "serial output matching /{}/", re),
+        }
+    }
+}
+
+fn connect(port: u16) -> Option<TcpStream> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let stream = TcpStream::connect_timeout(&addr, PROBE_TIMEOUT).ok()?;
+    stream.set_read_timeout(Some(PROBE_TIMEOUT)).ok()?;
+    Some(stream)
+}
+
+// qemu accepts connections to forwarded ports whether the guest listens or not, so a connection
+// proves nothing until the guest has said something.
+fn probe_ssh(port: u16) -> bool {
+    let mut banner = [0; 4];
+    connect(port).is_some_and(|mut s| s.read_exact(&mut banner).is_ok() && &banner == b"SSH-")
+}
+
+// servers that wait for the client to speak first never send anything; but when nothing in the
+// guest is listening, qemu closes the connection right away.
+fn probe_port(port: u16) -> bool {
+    let mut buf = [0; 1];
+    match connect(port).map(|mut s| s.read(&mut buf)) {
+        Some(Ok(n)) => n > 0,
+        Some(Err(e)) => matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+        None => false,
+    }
+}
+
+// guest-sync answers with the id it was sent, which tells its reply apart from anything left
+// over from an earlier client.
+fn probe_agent(socket: &Path) -> bool {
+    let id = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64 % 1_000_000_000)
+        .unwrap_or_default();
+
+    let res = (|| -> Result<bool> {
+        let mut stream = UnixStream::connect(socket)?;
+        stream.set_read_timeout(Some(PROBE_TIMEOUT))?;
+        stream.write_all(
+            format!(
+                "{}\n",
+                json!({"execute": "guest-sync", "arguments": {"id": id}})
+            )
+            .as_bytes(),
+        )?;
+
+        for line in BufReader::new(stream).lines() {
+            if let Ok(reply) = serde_json::from_str::<Value>(&line?) {
+                if reply["return"].as_u64() == Some(id) {
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    })();
+
+    res.unwrap_or_default()
+}
+
+fn probe_serial(re: &Regex, log: &Path, since: Option<SystemTime>) -> bool {
+    logs::read_lines(log, since)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|line| line.split_once(' '))
+        .any(|(_, line)| re.is_match(line))
+}
+
+/// Block until the guest of the running `vm` is ready, or `timeout` has passed.
+pub fn wait(
+    config: &Arc<Box<dyn ConfigStorageHandler>>,
+    vm: &VM,
+    readiness: &Readiness,
+    timeout: Duration,
+) -> Result<()> {
+    let agent = Device::socket_path(&config.vm_root(vm), GUEST_AGENT_PORT);
+    if let Readiness::Agent = readiness {
+        let has_agent = vm
+            .config()
+            .devices
+            .iter()
+            .any(|d| matches!(d, Device::VirtioSerial { name } if name == GUEST_AGENT_PORT));
+        if !has_agent {
+            return Err(anyhow!(
+                "{} has no guest agent port; add one with `emu config device add {} virtio-serial,name={}`",
+                vm,
+                vm,
+                GUEST_AGENT_PORT
+            ));
+        }
+    }
+
+    // serial output from earlier boots must not count.
+    let since = logs::last_started(&config.qemu_log_path(vm));
+    let start = Instant::now();
+
+    loop {
+        if !vm.supervisor().is_active(vm)? {
+            return Err(anyhow!("{} is not running", vm));
+        }
+
+        let ready = match readiness {
+            Readiness::Ssh => probe_ssh(vm.config().machine.ssh_port),
+            Readiness::Port(port) => probe_port(*port),
+            Readiness::Agent => probe_agent(&agent),
+            Readiness::Serial(re) => probe_serial(re, &config.serial_log_path(vm), since),
+        };
+
+        if ready {
+            return Ok(());
+        }
+
+        if start.elapsed() > timeout {
+            return Err(anyhow!(
+                "timed out waiting for {} on {} after {}s",
+                readiness,
+                vm,
+                timeout.as_secs()
+            ));
+        }
+
+        sleep(PROBE_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use std::{net::TcpListener, os::unix::net::UnixListener};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_probes() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        std::thread::spawn(move || {
+            // the first connection is closed unanswered, as qemu does when nothing listens.
+            drop(listener.accept());
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(b"SSH-2.0-OpenSSH_9.6\r\n").unwrap();
+        });
+        assert!(!probe_ssh(port));
+        assert!(probe_ssh(port));
+
+        let dir = tempdir()?;
+        let socket = dir.path().join("agent.sock");
+        let listener = UnixListener::bind(&socket)?;
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let req: Value = serde_json::from_str(&line).unwrap();
+            let mut stream = stream;
+            stream.write_all(b"{\"return\": 12}\n").unwrap();
+            stream
+                .write_all(format!("{}\n", json!({"return": req["arguments"]["id"]})).as_bytes())
+                .unwrap();
+        });
+        assert!(probe_agent(&socket));
+
+        let log = dir.path().join("serial.log");
+        std::fs::write(
+            &log,
+            "2024-01-01T00:00:00.000Z login: \n2024-01-02T00:00:00.000Z booting\n",
+        )?;
+        let since = crate::util::parse_timestamp("2024-01-02T00:00:00.000Z");
+        assert!(probe_serial(&Regex::new("^login:")?, &log, None));
+        assert!(!probe_serial(&Regex::new("^login:")?, &log, since));
+        assert!(probe_serial(&Regex::new("boot")?, &log, since));
+        Ok(())
+    }
+}
@@ -0,0 +1,659 @@
+use anyhow::{anyhow, Result};
+use des::{
+    cipher::{generic_array::GenericArray, BlockEncrypt, KeyInit},
+    Des,
+};
+use futures::{SinkExt, StreamExt};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UnixStream},
+};
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::derive_accept_key,
+        protocol::{frame::coding::CloseCode, CloseFrame, Role},
+        Message,
+    },
+    WebSocketStream,
+};
+
+const PAGE: &str = include_str!("web_console.html");
+const WEBSOCKET_PATH: &str = "/websocket";
+const MAX_REQUEST_SIZE: usize = 8192;
+const RFB_VERSION: &[u8] = b"RFB 003.008\n";
+const SECURITY_NONE: u8 = 1;
+const SECURITY_VNC_AUTH: u8 = 2;
+
+/// Where the VM's VNC server can be reached.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VncTarget {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+/// Serves the bundled VNC client page over HTTP, and bridges its websocket to the VM's VNC
+/// server. Every websocket gets its own VNC connection. With a display password, the page has to
+/// send it first; the bridge then logs in to the VNC server itself.
+#[derive(Debug, Clone)]
+pub struct WebConsole {
+    vm_name: String,
+    target: VncTarget,
+    password: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Request {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+}
+
+fn parse_request(head: &str) -> Result<Request> {
+    let mut lines = head.split("\r\n");
+    let mut parts = lines
+        .next()
+        .ok_or_else(|| anyhow!("empty request"))?
+        .split_whitespace();
+
+    let method = parts.next().ok_or_else(|| anyhow!("missing method"))?;
+    let path = parts.next().ok_or_else(|| anyhow!("missing path"))?;
+
+    let mut headers = HashMap::new();
+    for line in lines {
+        if let Some((key, value)) = line.split_once(':') {
+            headers.insert(key.trim().to_lowercase(), value.trim().to_string());
+        }
+    }
+
+    Ok(Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers,
+    })
+}
+
+// the console is only for this host's browser: the Host check keeps DNS rebinding out, and the
+// Origin check keeps other sites' pages from opening the websocket. The port is not checked, as
+// an SSH tunnel may forward any local port to the console. Browsers always send Origin with a
+// websocket handshake; other clients may not.
+const LOCAL_HOSTS: [&str; 3] = ["127.0.0.1", "localhost", "[::1]"];
+
+fn host_name(host: &str) -> &str {
+    match host.find(']') {
+        Some(end) if host.starts_with('[') => &host[..=end],
+        _ => host.split(':').next().unwrap_or(host),
+    }
+}
+
+fn valid_host(req: &Request) -> bool {
+    req.headers
+        .get("host")
+        .is_some_and(|host| LOCAL_HOSTS.contains(&host_name(host)))
+}
+
+fn valid_origin(req: &Request) -> bool {
+    match (req.headers.get("origin"), req.headers.get("host")) {
+        (Some(origin), Some(host)) => *origin == format!("http://{}", host),
+        (Some(_), None) => false,
+        (None, _) => true,
+    }
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut buf = Vec::new();
+    let mut chunk = [0_u8; 1024];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        let size = stream.read(&mut chunk).await?;
+        if size == 0 {
+            return Err(anyhow!("connection closed during request"));
+        }
+
+        buf.extend_from_slice(&chunk[..size]);
+        if buf.len() > MAX_REQUEST_SIZE {
+            return Err(anyhow!("request too large"));
+        }
+    }
+
+    parse_request(&String::from_utf8_lossy(&buf))
+}
+
+async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    stream
+        .write_all(
+            format!(
+                "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+                status,
+                content_type,
+                body.len(),
+                body
+            )
+            .as_bytes(),
+        )
+        .await?;
+    Ok(())
+}
+
+// VNC authentication encrypts the server's challenge with DES, keyed with the first 8 bytes of
+// the password with the bits of each byte reversed.
+fn vnc_auth_response(password: &str, challenge: &[u8; 16]) -> [u8; 16] {
+    let mut key = [0_u8; 8];
+    for (k, b) in key.iter_mut().zip(password.bytes()) {
+        *k = b.reverse_bits();
+    }
+
+    let des = Des::new(GenericArray::from_slice(&key));
+    let mut response = *challenge;
+    for block in response.chunks_mut(8) {
+        des.encrypt_block(GenericArray::from_mut_slice(block));
+    }
+
+    response
+}
+
+async fn read_reason<S: AsyncRead + Unpin>(vnc: &mut S) -> Result<String> {
+    let len = vnc.read_u32().await? as usize;
+    if len > MAX_REQUEST_SIZE {
+        return Err(anyhow!("the VNC server sent an oversized message"));
+    }
+
+    let mut reason = vec![0_u8; len];
+    vnc.read_exact(&mut reason).await?;
+    Ok(String::from_utf8_lossy(&reason).to_string())
+}
+
+// logs in to a VNC server that asks for a password, up to where the client sends its ClientInit.
+async fn vnc_login<S>(vnc: &mut S, password: &str) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut version = [0_u8; 12];
+    vnc.read_exact(&mut version).await?;
+    vnc.write_all(RFB_VERSION).await?;
+
+    let count = vnc.read_u8().await?;
+    if count == 0 {
+        return Err(anyhow!(
+            "the VNC server refused the connection: {}",
+            read_reason(vnc).await?
+        ));
+    }
+
+    let mut types = vec![0_u8; count as usize];
+    vnc.read_exact(&mut types).await?;
+    if !types.contains(&SECURITY_VNC_AUTH) {
+        return Err(anyhow!("the VNC server does not ask for a password"));
+    }
+
+    vnc.write_u8(SECURITY_VNC_AUTH).await?;
+    let mut challenge = [0_u8; 16];
+    vnc.read_exact(&mut challenge).await?;
+    vnc.write_all(&vnc_auth_response(password, &challenge))
+        .await?;
+
+    if vnc.read_u32().await? != 0 {
+        return Err(anyhow!(
+            "the VNC server rejected the display password: {}",
+            read_reason(vnc).await?
+        ));
+    }
+
+    Ok(())
+}
+
+// reads `n` bytes the browser sent, keeping anything past them in `pending`.
+async fn ws_read(
+    ws: &mut WebSocketStream<TcpStream>,
+    pending: &mut Vec<u8>,
+    n: usize,
+) -> Result<Vec<u8>> {
+    while pending.len() < n {
+        match ws.next().await {
+            Some(Ok(Message::Binary(data))) => pending.extend_from_slice(&data),
+            Some(Ok(Message::Close(_))) | None => {
+                return Err(anyhow!("the browser closed the connection"))
+            }
+            Some(Ok(_)) => {}
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+
+    Ok(pending.drain(..n).collect())
+}
+
+// stands in for the VNC server's handshake once the bridge has logged in, offering the browser
+// no authentication. Returns whatever the browser sent past it.
+async fn handshake(ws: &mut WebSocketStream<TcpStream>) -> Result<Vec<u8>> {
+    let mut pending = Vec::new();
+    ws.send(Message::Binary(RFB_VERSION.to_vec().into()))
+        .await?;
+    ws_read(ws, &mut pending, RFB_VERSION.len()).await?;
+    ws.send(Message::Binary(vec![1, SECURITY_NONE].into()))
+        .await?;
+    ws_read(ws, &mut pending, 1).await?;
+    ws.send(Message::Binary(vec![0; 4].into())).await?;
+    Ok(pending)
+}
+
+async fn close(mut ws: WebSocketStream<TcpStream>, reason: &str) -> Result<()> {
+    ws.close(Some(CloseFrame {
+        code: CloseCode::Policy,
+        reason: reason.into(),
+    }))
+    .await?;
+    Ok(())
+}
+
+async fn bridge<S>(ws: WebSocketStream<TcpStream>, vnc: S, pending: Vec<u8>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let (mut vnc_rx, mut vnc_tx) = tokio::io::split(vnc);
+    let mut buf = vec![0_u8; 65536];
+    vnc_tx.write_all(&pending).await?;
+
+    loop {
+        tokio::select! {
+            msg = ws_rx.next() => match msg {
+                Some(Ok(Message::Binary(data))) => vnc_tx.write_all(&data).await?,
+                Some(Ok(Message::Close(_))) | None => break,
+                // pings are answered by tungstenite itself
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+            res = vnc_rx.read(&mut buf) => {
+                let size = res?;
+                if size == 0 {
+                    break;
+                }
+                ws_tx.send(Message::Binary(buf[..size].to_vec().into())).await?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+impl WebConsole {
+    pub fn new(vm_name: String, target: VncTarget, password: Option<String>) -> Self {
+        Self {
+            vm_name,
+            target,
+            password,
+        }
+    }
+
+    pub fn page(&self) -> String {
+        PAGE.replace("{vm_name}", &html_escape(&self.vm_name))
+            .replace(
+                "{password_required}",
+                if self.password.is_some() {
+                    "true"
+                } else {
+                    "false"
+                },
+            )
+    }
+
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let console = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = console.handle(stream).await {
+                    println!("web console: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let req = read_request(&mut stream).await?;
+
+        if !valid_host(&req) {
+            return respond(
+                &mut stream,
+                "403 Forbidden",
+                "text/plain",
+                "the web console is only served to this host\n",
+            )
+            .await;
+        }
+
+        if req.method != "GET" {
+            return respond(
+                &mut stream,
+                "405 Method Not Allowed",
+                "text/plain",
+                "method not allowed\n",
+            )
+            .await;
+        }
+
+        match req.path.as_str() {
+            "/" | "/index.html" => {
+                respond(
+                    &mut stream,
+                    "200 OK",
+                    "text/html; charset=utf-8",
+                    &self.page(),
+                )
+                .await
+            }
+            WEBSOCKET_PATH if !valid_origin(&req) => {
+                respond(
+                    &mut stream,
+                    "403 Forbidden",
+                    "text/plain",
+                    "websocket connections from other sites are not allowed\n",
+                )
+                .await
+            }
+            WEBSOCKET_PATH => self.upgrade(stream, &req).await,
+            _ => respond(&mut stream, "404 Not Found", "text/plain", "not found\n").await,
+        }
+    }
+
+    async fn upgrade(&self, mut stream: TcpStream, req: &Request) -> Result<()> {
+        let key = match req.headers.get("sec-websocket-key") {
+            Some(key)
+                if req
+                    .headers
+                    .get("upgrade")
+                    .is_some_and(|u| u.eq_ignore_ascii_case("websocket")) =>
+            {
+                key
+            }
+            _ => {
+                return respond(
+                    &mut stream,
+                    "400 Bad Request",
+                    "text/plain",
+                    "expected a websocket upgrade\n",
+                )
+                .await
+            }
+        };
+
+        // noVNC and friends ask for the "binary" subprotocol; our own page asks for nothing.
+        let protocol = match req.headers.get("sec-websocket-protocol") {
+            Some(protocols) if protocols.split(',').any(|p| p.trim() == "binary") => {
+                "Sec-WebSocket-Protocol: binary\r\n"
+            }
+            _ => "",
+        };
+
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n{}\r\n",
+                    derive_accept_key(key.as_bytes()),
+                    protocol
+                )
+                .as_bytes(),
+            )
+            .await?;
+
+        let mut ws = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+
+        if let Some(password) = &self.password {
+            match ws.next().await {
+                Some(Ok(Message::Text(given))) if given.as_str() == password => {}
+                _ => return close(ws, "wrong display password").await,
+            }
+        }
+
+        match &self.target {
+            VncTarget::Unix(path) => self.connect(ws, UnixStream::connect(path).await?).await,
+            VncTarget::Tcp(addr) => self.connect(ws, TcpStream::connect(addr).await?).await,
+        }
+    }
+
+    async fn connect<S>(&self, mut ws: WebSocketStream<TcpStream>, mut vnc: S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut pending = Vec::new();
+        if let Some(password) = &self.password {
+            if let Err(e) = vnc_login(&mut vnc, password).await {
+                close(ws, &e.to_string()).await?;
+                return Err(e);
+            }
+
+            pending = handshake(&mut ws).await?;
+        }
+
+        bridge(ws, vnc, pending).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use tempfile::tempdir;
+    use tokio::net::UnixListener;
+    use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+
+    async fn expect_binary(ws: &mut WebSocketStream<TcpStream>, want: &[u8]) {
+        match ws.next().await {
+            Some(Ok(Message::Binary(data))) => assert_eq!(&data[..], want),
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_request() -> Result<()> {
+        let req = parse_request(
+            "GET /websocket HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nSec-WebSocket-Key: abc\r\n\r\n",
+        )?;
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.path, "/websocket");
+        assert_eq!(req.headers.get("upgrade"), Some(&"websocket".to_string()));
+        assert_eq!(
+            req.headers.get("sec-websocket-key"),
+            Some(&"abc".to_string())
+        );
+        assert!(parse_request("").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_valid_origin() -> Result<()> {
+        let req = |headers: &str| parse_request(&format!("GET / HTTP/1.1\r\n{}\r\n", headers));
+
+        assert!(valid_host(&req("Host: 127.0.0.1:6080")?));
+        assert!(valid_host(&req("Host: localhost:8080")?));
+        assert!(valid_host(&req("Host: [::1]:6080")?));
+        assert!(valid_host(&req("Host: localhost")?));
+        assert!(!valid_host(&req("Host: evil.example:6080")?));
+        assert!(!valid_host(&req("Host: localhost.evil.example")?));
+        assert!(!valid_host(&req("")?));
+
+        assert!(valid_origin(&req("Host: localhost:6080")?));
+        assert!(valid_origin(&req(
+            "Host: localhost:8080\r\nOrigin: http://localhost:8080"
+        )?));
+        assert!(valid_origin(&req(
+            "Host: [::1]:6080\r\nOrigin: http://[::1]:6080"
+        )?));
+        assert!(!valid_origin(&req(
+            "Host: localhost:6080\r\nOrigin: http://127.0.0.1:6080"
+        )?));
+        assert!(!valid_origin(&req(
+            "Host: localhost:6080\r\nOrigin: http://evil.example"
+        )?));
+        assert!(!valid_origin(&req("Host: localhost:6080\r\nOrigin: null")?));
+        assert!(!valid_origin(&req("Origin: http://localhost:6080")?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_page() -> Result<()> {
+        let console = WebConsole::new("<vm>".to_string(), VncTarget::Unix("/vnc".into()), None);
+        let page = console.page();
+        assert!(page.contains("emu: &lt;vm&gt;"));
+        assert!(!page.contains("{vm_name}"));
+        assert!(page.contains("const password = false ?"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_vnc_auth_response() -> Result<()> {
+        let mut challenge = [0_u8; 16];
+        for (x, b) in challenge.iter_mut().enumerate() {
+            *b = x as u8;
+        }
+
+        // from `openssl enc -des-ecb`, keyed with "hunter2" bit-reversed.
+        assert_eq!(
+            vnc_auth_response("hunter2", &challenge),
+            [
+                0xae, 0x2f, 0xfb, 0x6b, 0x2f, 0xdd, 0x58, 0xfb, 0x77, 0x56, 0x79, 0x77, 0xd8, 0xd5,
+                0xec, 0xe4
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bridge_password() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("display");
+        let vnc = UnixListener::bind(&path)?;
+
+        // the fake VNC server asks for "hunter2" and then echoes
+        tokio::spawn(async move {
+            let (mut s, _) = vnc.accept().await.unwrap();
+            s.write_all(b"RFB 003.008\n").await.unwrap();
+            let mut version = [0_u8; 12];
+            s.read_exact(&mut version).await.unwrap();
+            s.write_all(&[1, SECURITY_VNC_AUTH]).await.unwrap();
+            assert_eq!(s.read_u8().await.unwrap(), SECURITY_VNC_AUTH);
+            let challenge = [7_u8; 16];
+            s.write_all(&challenge).await.unwrap();
+            let mut response = [0_u8; 16];
+            s.read_exact(&mut response).await.unwrap();
+            assert_eq!(response, vnc_auth_response("hunter2", &challenge));
+            s.write_u32(0).await.unwrap();
+
+            let mut buf = [0_u8; 64];
+            let size = s.read(&mut buf).await.unwrap();
+            s.write_all(&buf[..size]).await.unwrap();
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(
+            WebConsole::new(
+                "vm1".to_string(),
+                VncTarget::Unix(path),
+                Some("hunter2".to_string()),
+            )
+            .serve(listener),
+        );
+
+        let connect = || async {
+            let stream = TcpStream::connect(addr).await?;
+            let req = format!("ws://{}{}", addr, WEBSOCKET_PATH).into_client_request()?;
+            Ok::<_, anyhow::Error>(tokio_tungstenite::client_async(req, stream).await?.0)
+        };
+
+        let mut ws = connect().await?;
+        ws.send(Message::Text("wrong".into())).await?;
+        match ws.next().await {
+            Some(Ok(Message::Close(Some(frame)))) => {
+                assert_eq!(frame.reason.as_str(), "wrong display password")
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        // the browser sees a server without authentication.
+        let mut ws = connect().await?;
+        ws.send(Message::Text("hunter2".into())).await?;
+        expect_binary(&mut ws, b"RFB 003.008\n").await;
+        ws.send(Message::Binary(b"RFB 003.008\n".to_vec().into()))
+            .await?;
+        expect_binary(&mut ws, &[1, SECURITY_NONE]).await;
+        ws.send(Message::Binary(vec![SECURITY_NONE].into())).await?;
+        expect_binary(&mut ws, &[0, 0, 0, 0]).await;
+
+        ws.send(Message::Binary(b"hello".to_vec().into())).await?;
+        expect_binary(&mut ws, b"hello").await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bridge() -> Result<()> {
+        let dir = tempdir()?;
+        let path = dir.path().join("display");
+        let vnc = UnixListener::bind(&path)?;
+
+        // the fake VNC server greets and then echoes
+        tokio::spawn(async move {
+            let (mut s, _) = vnc.accept().await.unwrap();
+            s.write_all(b"RFB 003.008\n").await.unwrap();
+            let mut buf = [0_u8; 64];
+            let size = s.read(&mut buf).await.unwrap();
+            s.write_all(&buf[..size]).await.unwrap();
+        });
+
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(
+            WebConsole::new("vm1".to_string(), VncTarget::Unix(path), None).serve(listener),
+        );
+
+        let stream = TcpStream::connect(addr).await?;
+        let req = format!("ws://{}{}", addr, WEBSOCKET_PATH).into_client_request()?;
+        let (mut ws, _) = tokio_tungstenite::client_async(req, stream).await?;
+
+        match ws.next().await {
+            Some(Ok(Message::Binary(data))) => assert_eq!(&data[..], b"RFB 003.008\n"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        ws.send(Message::Binary(b"hello".to_vec().into())).await?;
+        match ws.next().await {
+            Some(Ok(Message::Binary(data))) => assert_eq!(&data[..], b"hello"),
+            other => panic!("unexpected message: {:?}", other),
+        }
+
+        // through an SSH tunnel, the browser's port is not the console's.
+        let stream = TcpStream::connect(addr).await?;
+        let mut req = format!("ws://localhost:8080{}", WEBSOCKET_PATH).into_client_request()?;
+        req.headers_mut()
+            .insert("Origin", "http://localhost:8080".parse()?);
+        tokio_tungstenite::client_async(req, stream).await?;
+
+        // a page on another site must not reach the console.
+        let stream = TcpStream::connect(addr).await?;
+        let mut req = format!("ws://{}{}", addr, WEBSOCKET_PATH).into_client_request()?;
+        req.headers_mut()
+            .insert("Origin", "http://evil.example".parse()?);
+        match tokio_tungstenite::client_async(req, stream).await {
+            Err(tokio_tungstenite::tungstenite::Error::Http(res)) => {
+                assert_eq!(res.status(), 403)
+            }
+            other => panic!(
+                "unexpected handshake result: {:?}",
+                other.map(|(_, res)| res)
+            ),
+        }
+
+        Ok(())
+    }
+}